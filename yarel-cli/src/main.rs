@@ -16,9 +16,12 @@
 use std::env;
 use std::fs;
 use std::io::{self, Write};
+use std::path::Path;
 use std::process;
 
-use yarel::error::ErrorKind;
+use yarel::compiler;
+use yarel::error::{Error, ErrorKind};
+use yarel::serialize;
 use yarel::vm::{self, Vm};
 
 fn repl(vm: &mut Vm) {
@@ -46,21 +49,50 @@ fn repl(vm: &mut Vm) {
     }
 }
 
+fn report_error(error: Error) -> ! {
+    let exit_code = if error.kind() == ErrorKind::CompileError {
+        65
+    } else {
+        70
+    };
+    eprint!("{}", error);
+    process::exit(exit_code);
+}
+
 fn run_file(vm: &mut Vm, path: &str) {
-    let source = fs::read_to_string(path);
-    let result = match source {
-        Ok(contents) => vm::interpret(vm, contents, None),
-        _ => panic!("Unable to read from file."),
+    let result = if Path::new(path).extension().is_some_and(|e| e == "ylc") {
+        match fs::read(path) {
+            Ok(bytecode) => vm::interpret_bytecode(vm, &bytecode, None),
+            _ => panic!("Unable to read from file."),
+        }
+    } else {
+        match fs::read_to_string(path) {
+            Ok(contents) => vm::interpret(vm, contents, None),
+            _ => panic!("Unable to read from file."),
+        }
     };
 
     if let Err(error) = result {
-        let exit_code = if error.kind() == ErrorKind::CompileError {
-            65
-        } else {
-            70
-        };
-        eprint!("{}", error);
-        process::exit(exit_code);
+        report_error(error);
+    }
+}
+
+fn compile_file(vm: &mut Vm, path: &str) {
+    let source = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        _ => panic!("Unable to read from file."),
+    };
+    let bytecode = compiler::compile(vm, source, None)
+        .and_then(|function| serialize::serialize_function(&function));
+    match bytecode {
+        Ok(bytecode) => {
+            let output = Path::new(path).with_extension("ylc");
+            if fs::write(&output, bytecode).is_err() {
+                eprintln!("Unable to write to file '{}'.", output.display());
+                process::exit(74);
+            }
+        }
+        Err(error) => report_error(error),
     }
 }
 
@@ -73,8 +105,10 @@ fn main() {
         repl(&mut vm);
     } else if args.len() == 2 {
         run_file(&mut vm, &args[1]);
+    } else if args.len() == 3 && args[1] == "--compile" {
+        compile_file(&mut vm, &args[2]);
     } else {
-        eprintln!("Usage: ./yarel-cli [path]");
+        eprintln!("Usage: ./yarel-cli [--compile] [path]");
         process::exit(64);
     }
 }
@@ -15,6 +15,7 @@
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
+use yarel::optimizer::OptimizationLevel;
 use yarel::vm;
 
 const FIBONACCI_SOURCE: &str = "
@@ -36,6 +37,66 @@ while i > 0 {
 }
 ";
 
+const METHOD_CALL_SOURCE: &str = "
+class Counter {
+    #[constructor]
+    fn new(self) {
+        self.count = 0;
+    }
+    fn increment(self) {
+        self.count += 1;
+    }
+    fn get(self) {
+        return self.count;
+    }
+}
+var counter = Counter.new();
+while counter.get() < 1000000 {
+    counter.increment();
+}
+";
+
+const LOCAL_ARITHMETIC_SOURCE: &str = "
+fn sum(n) {
+    var total = 0;
+    var i = 0;
+    while i < n {
+        total = total + i;
+        i = i + 1;
+    }
+    return total;
+}
+sum(1000000);
+";
+
+// Keeps many values live on the stack at once, so most of the work is copying stack slots.
+const STACK_HEAVY_SOURCE: &str = "
+fn mix(a, b, c, d) {
+    var e = a + b;
+    var f = c - d;
+    var g = e * f;
+    return a + b + c + d + e + f + g;
+}
+var total = 0;
+for i in 0..250000 {
+    total = mix(i, total, 1, 2) % 1000;
+}
+";
+
+const COLLECTION_HEAVY_SOURCE: &str = "
+var values = [];
+for i in 0..100000 {
+    values.push(i);
+}
+var total = 0;
+for _ in 0..5 {
+    for i in 0..values.len() {
+        total = total + values[i];
+        values[i] = values[i] + 1;
+    }
+}
+";
+
 fn criterion_benchmark(c: &mut Criterion) {
     let mut vm = vm::Vm::with_built_ins();
 
@@ -50,6 +111,30 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("while loop 1m", |b| {
         b.iter(|| vm::interpret(&mut vm, WHILE_LOOP_SOURCE.to_string(), None))
     });
+
+    c.bench_function("method call 1m", |b| {
+        b.iter(|| vm::interpret(&mut vm, METHOD_CALL_SOURCE.to_string(), None))
+    });
+
+    // Superinstructions are only produced at the full optimization level.
+    c.bench_function("local arithmetic 1m", |b| {
+        b.iter(|| vm::interpret(&mut vm, LOCAL_ARITHMETIC_SOURCE.to_string(), None))
+    });
+
+    vm.set_optimization_level(OptimizationLevel::Basic);
+    c.bench_function("local arithmetic 1m without superinstructions", |b| {
+        b.iter(|| vm::interpret(&mut vm, LOCAL_ARITHMETIC_SOURCE.to_string(), None))
+    });
+    vm.set_optimization_level(OptimizationLevel::Full);
+
+    // Run with and without the nan_boxing feature to compare value representations.
+    c.bench_function("stack heavy 250k", |b| {
+        b.iter(|| vm::interpret(&mut vm, STACK_HEAVY_SOURCE.to_string(), None))
+    });
+
+    c.bench_function("collection heavy 600k", |b| {
+        b.iter(|| vm::interpret(&mut vm, COLLECTION_HEAVY_SOURCE.to_string(), None))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -53,6 +53,12 @@ struct ClassSpec {
     metaclass: String,
 }
 
+#[derive(Debug, Serialize)]
+struct NameSpec {
+    name: String,
+    repr: String,
+}
+
 fn main() {
     let man_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap();
     let out_dir = env::var_os("OUT_DIR").unwrap();
@@ -76,6 +82,7 @@ fn compile_core_classes(man_dir: &OsString, out_dir: &Path) {
     let core_source = fs::read_to_string(src_path)
         .unwrap()
         .as_str()
+        .replace('\\', "\\\\")
         .replace("\"", "\\\"");
 
     let dest_path = out_dir.join("core.yl.rs");
@@ -125,12 +132,22 @@ fn compile_class_store(man_dir: &OsString, out_dir: &Path) {
             }
         })
         .collect::<Vec<_>>();
+    let name_specs = yaml[1]
+        .as_hash()
+        .unwrap()
+        .iter()
+        .map(|(name, repr)| NameSpec {
+            name: name.as_str().unwrap().to_owned(),
+            repr: repr.as_str().unwrap().to_owned(),
+        })
+        .collect::<Vec<_>>();
 
     let dest_path = out_dir.join("class_store.yaml.rs");
 
     let template = fs::read_to_string(template_path).unwrap();
     let mut context = Context::new();
     context.insert("class_specs", &class_specs);
+    context.insert("name_specs", &name_specs);
     let result = Tera::one_off(&template, &context, false).unwrap();
     fs::write(dest_path, result).unwrap();
 
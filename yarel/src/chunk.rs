@@ -13,11 +13,33 @@
  * limitations under the License.
  */
 
-use crate::memory;
-use crate::value;
+use std::cell::Cell;
+use std::fmt;
+use std::mem;
 
-#[repr(u8)]
-pub enum OpCode {
+use crate::common;
+use crate::memory::{self, Gc};
+use crate::object::{ObjClass, ObjFunction};
+use crate::packed_value::PackedValue;
+use crate::value::{self, Value};
+
+// Defines OpCode along with a list of every opcode, so that the largest opcode doesn't have to be
+// kept up to date by hand.
+macro_rules! define_opcodes {
+    ($($name:ident,)*) => {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name,)*
+        }
+
+        impl OpCode {
+            const ALL: &'static [OpCode] = &[$(OpCode::$name,)*];
+        }
+    };
+}
+
+define_opcodes! {
     Constant,
     Nil,
     True,
@@ -57,6 +79,11 @@ pub enum OpCode {
     BuildString,
     BuildTuple,
     BuildVec,
+    Extend,
+    Unpack,
+    MatchSequence,
+    MatchRange,
+    MatchField,
     IterNext,
     Jump,
     JumpIfFalse,
@@ -67,13 +94,22 @@ pub enum OpCode {
     PushExcHandler,
     PopExcHandler,
     Throw,
+    Rethrow,
     Call,
+    CallKw,
+    CallSpread,
     Invoke,
+    InvokeKw,
+    InvokeSpread,
     Construct,
     SuperInvoke,
+    SuperInvokeKw,
+    SuperInvokeSpread,
     Closure,
     CloseUpvalue,
     Return,
+    Yield,
+    Await,
     DeclareClass,
     DefineClass,
     Inherit,
@@ -81,10 +117,27 @@ pub enum OpCode {
     StaticMethod,
     StartImport,
     FinishImport,
+    // Superinstructions, which are only produced by the optimiser.
+    GetLocals,
+    GetLocalConstant,
+    AddLocals,
 }
 
+// OpCode::from_byte relies on the opcodes running contiguously from zero to OpCode::LAST.
+const _: () = {
+    let mut i = 0;
+    while i < OpCode::ALL.len() {
+        assert!(OpCode::ALL[i] as usize == i);
+        i += 1;
+    }
+    assert!(OpCode::LAST as usize == OpCode::ALL.len() - 1);
+};
+
 impl OpCode {
-    pub(crate) fn arg_sizes(&self) -> &[usize] {
+    /// The opcode with the largest value.
+    const LAST: OpCode = OpCode::ALL[OpCode::ALL.len() - 1];
+
+    pub fn arg_sizes(&self) -> &[usize] {
         match self {
             OpCode::Constant => &[2],
             OpCode::Nil => &[],
@@ -99,7 +152,7 @@ impl OpCode {
             OpCode::SetGlobal => &[2],
             OpCode::GetUpvalue => &[1],
             OpCode::SetUpvalue => &[1],
-            OpCode::GetProperty => &[2],
+            OpCode::GetProperty => &[2, 2],
             OpCode::SetProperty => &[2],
             OpCode::GetClass => &[],
             OpCode::GetSuper => &[2],
@@ -125,6 +178,11 @@ impl OpCode {
             OpCode::BuildString => &[1],
             OpCode::BuildTuple => &[1],
             OpCode::BuildVec => &[1],
+            OpCode::Extend => &[],
+            OpCode::Unpack => &[1, 1],
+            OpCode::MatchSequence => &[1, 1, 1],
+            OpCode::MatchRange => &[],
+            OpCode::MatchField => &[2],
             OpCode::IterNext => &[],
             OpCode::Jump => &[2],
             OpCode::JumpIfFalse => &[2],
@@ -132,16 +190,25 @@ impl OpCode {
             OpCode::Loop => &[2],
             OpCode::JumpFinally => &[],
             OpCode::PushExcHandler => &[2, 2],
-            OpCode::PopExcHandler => &[2, 2],
+            OpCode::PopExcHandler => &[],
             OpCode::EndFinally => &[],
             OpCode::Throw => &[],
+            OpCode::Rethrow => &[2],
             OpCode::Call => &[1],
-            OpCode::Invoke => &[2, 1],
+            OpCode::CallKw => &[1, 1],
+            OpCode::CallSpread => &[1],
+            OpCode::Invoke => &[2, 2, 1],
+            OpCode::InvokeKw => &[2, 1, 1],
+            OpCode::InvokeSpread => &[2, 1],
             OpCode::Construct => &[1],
-            OpCode::SuperInvoke => &[2, 1],
+            OpCode::SuperInvoke => &[2, 2, 1],
+            OpCode::SuperInvokeKw => &[2, 1, 1],
+            OpCode::SuperInvokeSpread => &[2, 1],
             OpCode::Closure => &[2],
             OpCode::CloseUpvalue => &[],
             OpCode::Return => &[],
+            OpCode::Yield => &[],
+            OpCode::Await => &[],
             OpCode::DeclareClass => &[2],
             OpCode::DefineClass => &[],
             OpCode::Inherit => &[],
@@ -149,86 +216,70 @@ impl OpCode {
             OpCode::StaticMethod => &[2],
             OpCode::StartImport => &[2],
             OpCode::FinishImport => &[],
+            OpCode::GetLocals => &[1, 1],
+            OpCode::GetLocalConstant => &[1, 2],
+            OpCode::AddLocals => &[1, 1],
+        }
+    }
+
+    /// Decodes an opcode, returning None if the byte doesn't correspond to an instruction.
+    pub fn from_byte(value: u8) -> Option<Self> {
+        if value <= OpCode::LAST as u8 {
+            // Safety: OpCode is repr(u8) and its discriminants run contiguously from zero to
+            // OpCode::LAST, so every value in that range is a valid OpCode.
+            Some(unsafe { mem::transmute::<u8, OpCode>(value) })
+        } else {
+            None
         }
     }
 }
 
-impl From<u8> for OpCode {
+/// Determines how the elements left over by OpCode::Unpack are handled.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum UnpackRest {
+    None,
+    Tuple,
+    Vec,
+}
+
+impl From<u8> for UnpackRest {
+    fn from(value: u8) -> Self {
+        match value {
+            value if value == UnpackRest::None as u8 => UnpackRest::None,
+            value if value == UnpackRest::Tuple as u8 => UnpackRest::Tuple,
+            value if value == UnpackRest::Vec as u8 => UnpackRest::Vec,
+            _ => panic!("Unknown unpack rest kind {}", value),
+        }
+    }
+}
+
+/// Determines the kind of sequence accepted by OpCode::MatchSequence.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum SequenceKind {
+    Tuple,
+    Vec,
+}
+
+impl From<u8> for SequenceKind {
     fn from(value: u8) -> Self {
         match value {
-            value if value == OpCode::Constant as u8 => OpCode::Constant,
-            value if value == OpCode::Nil as u8 => OpCode::Nil,
-            value if value == OpCode::True as u8 => OpCode::True,
-            value if value == OpCode::False as u8 => OpCode::False,
-            value if value == OpCode::Pop as u8 => OpCode::Pop,
-            value if value == OpCode::CopyTop as u8 => OpCode::CopyTop,
-            value if value == OpCode::GetLocal as u8 => OpCode::GetLocal,
-            value if value == OpCode::SetLocal as u8 => OpCode::SetLocal,
-            value if value == OpCode::GetGlobal as u8 => OpCode::GetGlobal,
-            value if value == OpCode::DefineGlobal as u8 => OpCode::DefineGlobal,
-            value if value == OpCode::SetGlobal as u8 => OpCode::SetGlobal,
-            value if value == OpCode::GetUpvalue as u8 => OpCode::GetUpvalue,
-            value if value == OpCode::SetUpvalue as u8 => OpCode::SetUpvalue,
-            value if value == OpCode::GetProperty as u8 => OpCode::GetProperty,
-            value if value == OpCode::SetProperty as u8 => OpCode::SetProperty,
-            value if value == OpCode::GetClass as u8 => OpCode::GetClass,
-            value if value == OpCode::GetSuper as u8 => OpCode::GetSuper,
-            value if value == OpCode::Equal as u8 => OpCode::Equal,
-            value if value == OpCode::Greater as u8 => OpCode::Greater,
-            value if value == OpCode::Less as u8 => OpCode::Less,
-            value if value == OpCode::Add as u8 => OpCode::Add,
-            value if value == OpCode::Subtract as u8 => OpCode::Subtract,
-            value if value == OpCode::Multiply as u8 => OpCode::Multiply,
-            value if value == OpCode::Divide as u8 => OpCode::Divide,
-            value if value == OpCode::BitwiseAnd as u8 => OpCode::BitwiseAnd,
-            value if value == OpCode::BitwiseOr as u8 => OpCode::BitwiseOr,
-            value if value == OpCode::BitwiseXor as u8 => OpCode::BitwiseXor,
-            value if value == OpCode::Modulo as u8 => OpCode::Modulo,
-            value if value == OpCode::LogicalNot as u8 => OpCode::LogicalNot,
-            value if value == OpCode::BitwiseNot as u8 => OpCode::BitwiseNot,
-            value if value == OpCode::BitShiftLeft as u8 => OpCode::BitShiftLeft,
-            value if value == OpCode::BitShiftRight as u8 => OpCode::BitShiftRight,
-            value if value == OpCode::Negate as u8 => OpCode::Negate,
-            value if value == OpCode::FormatString as u8 => OpCode::FormatString,
-            value if value == OpCode::BuildHashMap as u8 => OpCode::BuildHashMap,
-            value if value == OpCode::BuildRange as u8 => OpCode::BuildRange,
-            value if value == OpCode::BuildString as u8 => OpCode::BuildString,
-            value if value == OpCode::BuildTuple as u8 => OpCode::BuildTuple,
-            value if value == OpCode::BuildVec as u8 => OpCode::BuildVec,
-            value if value == OpCode::IterNext as u8 => OpCode::IterNext,
-            value if value == OpCode::Jump as u8 => OpCode::Jump,
-            value if value == OpCode::JumpIfFalse as u8 => OpCode::JumpIfFalse,
-            value if value == OpCode::JumpIfStopIter as u8 => OpCode::JumpIfStopIter,
-            value if value == OpCode::Loop as u8 => OpCode::Loop,
-            value if value == OpCode::JumpFinally as u8 => OpCode::JumpFinally,
-            value if value == OpCode::EndFinally as u8 => OpCode::EndFinally,
-            value if value == OpCode::PushExcHandler as u8 => OpCode::PushExcHandler,
-            value if value == OpCode::PopExcHandler as u8 => OpCode::PopExcHandler,
-            value if value == OpCode::Throw as u8 => OpCode::Throw,
-            value if value == OpCode::Call as u8 => OpCode::Call,
-            value if value == OpCode::Invoke as u8 => OpCode::Invoke,
-            value if value == OpCode::Construct as u8 => OpCode::Construct,
-            value if value == OpCode::SuperInvoke as u8 => OpCode::SuperInvoke,
-            value if value == OpCode::Closure as u8 => OpCode::Closure,
-            value if value == OpCode::CloseUpvalue as u8 => OpCode::CloseUpvalue,
-            value if value == OpCode::Return as u8 => OpCode::Return,
-            value if value == OpCode::DeclareClass as u8 => OpCode::DeclareClass,
-            value if value == OpCode::DefineClass as u8 => OpCode::DefineClass,
-            value if value == OpCode::Inherit as u8 => OpCode::Inherit,
-            value if value == OpCode::Method as u8 => OpCode::Method,
-            value if value == OpCode::StaticMethod as u8 => OpCode::StaticMethod,
-            value if value == OpCode::StartImport as u8 => OpCode::StartImport,
-            value if value == OpCode::FinishImport as u8 => OpCode::FinishImport,
-            _ => panic!("Unknown opcode {}", value),
+            value if value == SequenceKind::Tuple as u8 => SequenceKind::Tuple,
+            value if value == SequenceKind::Vec as u8 => SequenceKind::Vec,
+            _ => panic!("Unknown sequence kind {}", value),
         }
     }
 }
 
 #[derive(Clone, Default)]
 pub struct Chunk {
+    /// Instructions and their operands. Two-byte operands are little-endian, so serialised chunks
+    /// can be loaded on any platform.
     pub code: Vec<u8>,
     pub lines: Vec<i32>,
-    pub constants: Vec<value::Value>,
+    pub constants: Vec<PackedValue>,
+    pub inline_caches: Vec<InlineCache>,
 }
 
 impl Chunk {
@@ -242,10 +293,15 @@ impl Chunk {
     }
 
     pub fn add_constant(&mut self, value: value::Value) -> usize {
-        self.constants.push(value);
+        self.constants.push(PackedValue::new(value));
         self.constants.len() - 1
     }
 
+    pub fn add_inline_cache(&mut self) -> usize {
+        self.inline_caches.push(InlineCache::default());
+        self.inline_caches.len() - 1
+    }
+
     pub(crate) fn code_offset(&self, ptr: *const u8) -> usize {
         ptr as usize - (&self.code[0] as *const u8) as usize
     }
@@ -254,9 +310,588 @@ impl Chunk {
 impl memory::GcManaged for Chunk {
     fn mark(&self) {
         self.constants.mark();
+        self.inline_caches.mark();
+    }
+
+    fn heap_size(&self) -> usize {
+        self.code.capacity()
+            + self.lines.capacity() * mem::size_of::<i32>()
+            + self.constants.capacity() * mem::size_of::<PackedValue>()
+            + self.inline_caches.capacity() * mem::size_of::<InlineCache>()
     }
+}
+
+/// Remembers the method found by the last `GetProperty`, `Invoke` or `SuperInvoke` instruction
+/// that uses it, along with the class it was found on. A class's methods can't change once the
+/// class has been defined, and the cache keeps the class alive, so an entry only needs replacing
+/// when the instruction sees a different class. Redefining a class creates a new class object,
+/// which misses the cache.
+#[derive(Clone, Default)]
+pub struct InlineCache {
+    entry: Cell<Option<(Gc<ObjClass>, Value)>>,
+}
+
+impl InlineCache {
+    pub(crate) fn get(&self, class: Gc<ObjClass>) -> Option<Value> {
+        match self.entry.get() {
+            Some((cached_class, method)) if cached_class == class => Some(method),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set(&self, class: Gc<ObjClass>, method: Value) {
+        memory::write_barrier(&class);
+        memory::write_barrier(&method);
+        self.entry.set(Some((class, method)));
+    }
+}
+
+impl memory::GcManaged for InlineCache {
+    fn mark(&self) {
+        if let Some((class, method)) = self.entry.get() {
+            class.mark();
+            method.mark();
+        }
+    }
+}
 
-    fn blacken(&self) {
-        self.constants.blacken();
+/// The reason a function's bytecode was rejected by `verify`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyErrorKind {
+    InvalidSignature,
+    MalformedChunk,
+    UnknownOpcode(u8),
+    TruncatedInstruction,
+    InvalidConstant(usize),
+    InvalidLocal(usize),
+    InvalidUpvalue(usize),
+    InvalidInlineCache(usize),
+    InvalidOperand,
+    InvalidJump,
+    MissingExcHandler,
+    MissingReturn,
+    StackUnderflow,
+    StackOverflow,
+    StackMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyErrorKind::InvalidSignature => write!(f, "invalid function signature"),
+            VerifyErrorKind::MalformedChunk => write!(f, "malformed chunk"),
+            VerifyErrorKind::UnknownOpcode(byte) => write!(f, "unknown opcode {}", byte),
+            VerifyErrorKind::TruncatedInstruction => write!(f, "truncated instruction"),
+            VerifyErrorKind::InvalidConstant(index) => {
+                write!(f, "invalid constant index {}", index)
+            }
+            VerifyErrorKind::InvalidLocal(slot) => write!(f, "invalid local slot {}", slot),
+            VerifyErrorKind::InvalidUpvalue(index) => {
+                write!(f, "invalid upvalue index {}", index)
+            }
+            VerifyErrorKind::InvalidInlineCache(index) => {
+                write!(f, "invalid inline cache index {}", index)
+            }
+            VerifyErrorKind::InvalidOperand => write!(f, "invalid operand"),
+            VerifyErrorKind::InvalidJump => write!(f, "invalid jump target"),
+            VerifyErrorKind::MissingExcHandler => write!(f, "missing exception handler"),
+            VerifyErrorKind::MissingReturn => write!(f, "missing return"),
+            VerifyErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VerifyErrorKind::StackOverflow => write!(f, "stack overflow"),
+            VerifyErrorKind::StackMismatch { expected, found } => write!(
+                f,
+                "inconsistent stack depth (expected {} but found {})",
+                expected, found
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "{} at offset {} in script", self.kind, self.offset)
+        } else {
+            write!(
+                f,
+                "{} at offset {} in function '{}'",
+                self.kind, self.offset, self.function
+            )
+        }
+    }
+}
+
+/// Checks that the bytecode of a function, and of any functions nested within it, can be run
+/// safely by the VM. Every instruction must be valid, every operand must refer to an existing
+/// constant, local, upvalue or inline cache, every jump must land on an instruction and the stack
+/// must have the same depth whichever path execution takes to reach an instruction. The depth
+/// may not exceed the number of slots the VM reserves for each call frame.
+pub fn verify(function: &ObjFunction) -> Result<(), VerifyError> {
+    // Nested functions are visited using a worklist rather than recursion, so deeply nested
+    // bytecode can't overflow the stack.
+    Verifier::new(function).verify()?;
+    let mut worklist = nested_functions(function);
+    while let Some(nested) = worklist.pop() {
+        Verifier::new(&nested).verify()?;
+        worklist.extend(nested_functions(&nested));
+    }
+    Ok(())
+}
+
+/// Returns the functions in a function's constants, in reverse order so that popping them from a
+/// worklist visits them in the order they appear.
+fn nested_functions(function: &ObjFunction) -> Vec<Gc<ObjFunction>> {
+    function
+        .chunk
+        .constants
+        .iter()
+        .rev()
+        .filter_map(|constant| constant.try_as_obj_function())
+        .collect()
+}
+
+struct ExcHandler {
+    push_offset: usize,
+    try_offset: usize,
+    catch_offset: usize,
+    finally_offset: usize,
+}
+
+impl ExcHandler {
+    fn guards(&self, offset: usize) -> bool {
+        self.try_offset <= offset && offset < self.catch_offset
+    }
+}
+
+struct Verifier<'a> {
+    function: &'a ObjFunction,
+    chunk: &'a Chunk,
+    // The size of the instruction starting at each offset, or zero if no instruction starts there.
+    sizes: Vec<usize>,
+    // The stack depth, relative to the frame's slot base, before each instruction is executed.
+    depths: Vec<Option<usize>>,
+    handlers: Vec<ExcHandler>,
+    worklist: Vec<usize>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(function: &'a ObjFunction) -> Self {
+        let len = function.chunk.code.len();
+        Verifier {
+            function,
+            chunk: &function.chunk,
+            sizes: vec![0; len],
+            depths: vec![None; len],
+            handlers: Vec::new(),
+            worklist: Vec::new(),
+        }
+    }
+
+    fn verify(&mut self) -> Result<(), VerifyError> {
+        let function = self.function;
+        let fixed_arity = function
+            .arity
+            .checked_sub(1 + function.is_variadic as usize);
+        // Keyword arguments are bound by name, so there must be a name for every fixed parameter.
+        let valid_signature = fixed_arity.is_some_and(|arity| {
+            function.default_count <= arity && function.parameters.len() == arity
+        }) && function.arity <= common::LOCALS_MAX
+            && function.upvalue_count <= common::UPVALUES_MAX;
+        if !valid_signature {
+            return Err(self.error(0, VerifyErrorKind::InvalidSignature));
+        }
+        if self.chunk.code.is_empty() || self.chunk.lines.len() != self.chunk.code.len() {
+            return Err(self.error(0, VerifyErrorKind::MalformedChunk));
+        }
+
+        self.decode()?;
+
+        // The callee and its arguments occupy the bottom of the stack when a function is called.
+        self.flow(0, 0, function.arity)?;
+        while let Some(offset) = self.worklist.pop() {
+            let depth = self.depths[offset].unwrap();
+            self.check_instruction(offset, depth)?;
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self) -> Result<(), VerifyError> {
+        let len = self.chunk.code.len();
+        let mut offset = 0;
+        while offset < len {
+            let opcode = self.opcode(offset)?;
+            let mut size = 1 + opcode.arg_sizes().iter().sum::<usize>();
+            if offset + size > len {
+                return Err(self.error(offset, VerifyErrorKind::TruncatedInstruction));
+            }
+            match opcode {
+                OpCode::Closure => {
+                    size += 2 * self.function_constant(offset)?.upvalue_count;
+                    if offset + size > len {
+                        return Err(self.error(offset, VerifyErrorKind::TruncatedInstruction));
+                    }
+                }
+                OpCode::PushExcHandler => {
+                    let try_offset = offset + size;
+                    let catch_offset = try_offset + self.read_short(offset + 1);
+                    let finally_offset = catch_offset + self.read_short(offset + 3);
+                    self.handlers.push(ExcHandler {
+                        push_offset: offset,
+                        try_offset,
+                        catch_offset,
+                        finally_offset,
+                    });
+                }
+                _ => {}
+            }
+            self.sizes[offset] = size;
+            offset += size;
+        }
+        Ok(())
+    }
+
+    fn check_instruction(&mut self, offset: usize, depth: usize) -> Result<(), VerifyError> {
+        // Exceptions truncate the stack to its size when the handler was pushed, so the code
+        // guarded by a handler mustn't pop anything below that.
+        for handler in &self.handlers {
+            if handler.guards(offset) {
+                match self.depths[handler.push_offset] {
+                    Some(init_depth) if depth >= init_depth => {}
+                    Some(_) => return Err(self.error(offset, VerifyErrorKind::StackUnderflow)),
+                    None => return Err(self.error(offset, VerifyErrorKind::MissingExcHandler)),
+                }
+            }
+        }
+
+        let next = offset + self.sizes[offset];
+        let byte = |i: usize| self.chunk.code[offset + i] as usize;
+        let opcode = self.opcode(offset)?;
+        let (pops, pushes) = match opcode {
+            OpCode::Constant => {
+                self.constant(offset, self.read_short(offset + 1))?;
+                (0, 1)
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1),
+            OpCode::Pop | OpCode::CloseUpvalue => (1, 0),
+            OpCode::CopyTop => (1, 2),
+            OpCode::GetLocal | OpCode::SetLocal => {
+                if byte(1) >= depth {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidLocal(byte(1))));
+                }
+                match opcode {
+                    OpCode::GetLocal => (0, 1),
+                    _ => (1, 1),
+                }
+            }
+            OpCode::GetLocals | OpCode::AddLocals => {
+                // The second local may be the copy of the first that's just been pushed.
+                if byte(1) >= depth {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidLocal(byte(1))));
+                }
+                if byte(2) > depth {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidLocal(byte(2))));
+                }
+                match opcode {
+                    OpCode::GetLocals => (0, 2),
+                    _ => (0, 1),
+                }
+            }
+            OpCode::GetLocalConstant => {
+                if byte(1) >= depth {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidLocal(byte(1))));
+                }
+                self.constant(offset, self.read_short(offset + 2))?;
+                (0, 2)
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                if byte(1) >= self.function.upvalue_count {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidUpvalue(byte(1))));
+                }
+                match opcode {
+                    OpCode::GetUpvalue => (0, 1),
+                    _ => (1, 1),
+                }
+            }
+            OpCode::GetGlobal | OpCode::DeclareClass | OpCode::StartImport => {
+                self.string_constant(offset)?;
+                match opcode {
+                    OpCode::StartImport => (0, 2),
+                    _ => (0, 1),
+                }
+            }
+            OpCode::SetGlobal | OpCode::MatchField => {
+                self.string_constant(offset)?;
+                (1, 1)
+            }
+            OpCode::GetProperty => {
+                self.string_constant(offset)?;
+                self.inline_cache(offset)?;
+                (1, 1)
+            }
+            OpCode::DefineGlobal | OpCode::Method | OpCode::StaticMethod => {
+                self.string_constant(offset)?;
+                (1, 0)
+            }
+            OpCode::SetProperty | OpCode::GetSuper => {
+                self.string_constant(offset)?;
+                (2, 1)
+            }
+            OpCode::GetClass
+            | OpCode::LogicalNot
+            | OpCode::BitwiseNot
+            | OpCode::Negate
+            | OpCode::FormatString
+            | OpCode::DefineClass
+            | OpCode::Yield
+            | OpCode::Await => (1, 1),
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::BitwiseAnd
+            | OpCode::BitwiseOr
+            | OpCode::BitwiseXor
+            | OpCode::Modulo
+            | OpCode::BitShiftLeft
+            | OpCode::BitShiftRight
+            | OpCode::BuildRange
+            | OpCode::Extend
+            | OpCode::Inherit => (2, 1),
+            OpCode::BuildHashMap => (2 * byte(1), 1),
+            OpCode::BuildString | OpCode::BuildTuple | OpCode::BuildVec => (byte(1), 1),
+            OpCode::Unpack => {
+                if byte(2) > UnpackRest::Vec as usize {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidOperand));
+                }
+                let has_rest = byte(2) != UnpackRest::None as usize;
+                (1, byte(1) + has_rest as usize)
+            }
+            OpCode::MatchSequence => {
+                if byte(2) > SequenceKind::Vec as usize {
+                    return Err(self.error(offset, VerifyErrorKind::InvalidOperand));
+                }
+                (1, 1)
+            }
+            OpCode::MatchRange => (3, 1),
+            OpCode::IterNext => (1, 2),
+            OpCode::FinishImport => (2, 1),
+            OpCode::Call => (byte(1) + 1, 1),
+            OpCode::Construct => (byte(1) + 1, byte(1) + 1),
+            OpCode::CallKw => (byte(1) + 2 * byte(2) + 1, 1),
+            OpCode::CallSpread => (2 * byte(1) + 2, 1),
+            OpCode::Invoke => {
+                self.string_constant(offset)?;
+                self.inline_cache(offset)?;
+                (byte(5) + 1, 1)
+            }
+            OpCode::InvokeKw => {
+                self.string_constant(offset)?;
+                (byte(3) + 2 * byte(4) + 1, 1)
+            }
+            OpCode::InvokeSpread => {
+                self.string_constant(offset)?;
+                (2 * byte(3) + 2, 1)
+            }
+            OpCode::SuperInvoke => {
+                self.string_constant(offset)?;
+                self.inline_cache(offset)?;
+                (byte(5) + 2, 1)
+            }
+            OpCode::SuperInvokeKw => {
+                self.string_constant(offset)?;
+                (byte(3) + 2 * byte(4) + 2, 1)
+            }
+            OpCode::SuperInvokeSpread => {
+                self.string_constant(offset)?;
+                (2 * byte(3) + 3, 1)
+            }
+            OpCode::Closure => {
+                let function = self.function_constant(offset)?;
+                let depth = self.pop(offset, depth, function.default_count)? + 1;
+                for i in 0..function.upvalue_count {
+                    let (is_local, index) = (byte(3 + 2 * i), byte(4 + 2 * i));
+                    let error = match is_local {
+                        0 if index >= self.function.upvalue_count => {
+                            Some(VerifyErrorKind::InvalidUpvalue(index))
+                        }
+                        1 if index >= depth => Some(VerifyErrorKind::InvalidLocal(index)),
+                        0 | 1 => None,
+                        _ => Some(VerifyErrorKind::InvalidOperand),
+                    };
+                    if let Some(kind) = error {
+                        return Err(self.error(offset, kind));
+                    }
+                }
+                return self.fall_through(offset, depth);
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfStopIter => {
+                let target = next + self.read_short(offset + 1);
+                if let OpCode::Jump = opcode {
+                    return self.jump(offset, target, depth);
+                }
+                self.pop(offset, depth, 1)?;
+                self.jump(offset, target, depth)?;
+                (0, 0)
+            }
+            OpCode::Loop => {
+                let target = next
+                    .checked_sub(self.read_short(offset + 1))
+                    .ok_or_else(|| self.error(offset, VerifyErrorKind::InvalidJump))?;
+                return self.jump(offset, target, depth);
+            }
+            OpCode::PushExcHandler => {
+                let handler = self.handlers.iter().find(|h| h.push_offset == offset);
+                let handler = handler.unwrap();
+                let (catch_offset, finally_offset) = (handler.catch_offset, handler.finally_offset);
+                // An exception is pushed onto the stack when it's caught. If there's no catch
+                // block, the exception stays beneath the finally block until it's rethrown.
+                if catch_offset == finally_offset {
+                    self.jump(offset, finally_offset, depth)?;
+                } else {
+                    self.jump(offset, catch_offset, depth + 1)?;
+                    self.jump(offset, finally_offset, depth)?;
+                }
+                (0, 0)
+            }
+            OpCode::PopExcHandler | OpCode::EndFinally => (0, 0),
+            OpCode::JumpFinally => {
+                // The return value is set aside while the finally block runs, then pushed back
+                // onto the stack for the return instruction that follows.
+                let depth = self.pop(offset, depth, 1)?;
+                let handler = self
+                    .handlers
+                    .iter()
+                    .filter(|h| h.guards(offset))
+                    .max_by_key(|h| h.try_offset)
+                    .ok_or_else(|| self.error(offset, VerifyErrorKind::MissingExcHandler))?;
+                let finally_offset = handler.finally_offset;
+                let init_depth = self.depths[handler.push_offset].unwrap();
+                if init_depth > depth {
+                    return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+                }
+                self.jump(offset, finally_offset, init_depth)?;
+                if next >= self.chunk.code.len() || self.chunk.code[next] != OpCode::Return as u8 {
+                    return Err(self.error(offset, VerifyErrorKind::MissingReturn));
+                }
+                return self.flow(offset, next, depth + 1);
+            }
+            OpCode::Rethrow => {
+                // The exception is left beneath the finally block, as if there were no catch
+                // block.
+                let depth = self.pop(offset, depth, 1)?;
+                let target = next + self.read_short(offset + 1);
+                return self.jump(offset, target, depth);
+            }
+            OpCode::Throw | OpCode::Return => {
+                self.pop(offset, depth, 1)?;
+                return Ok(());
+            }
+        };
+
+        let depth = self.pop(offset, depth, pops)? + pushes;
+        self.fall_through(offset, depth)
+    }
+
+    fn pop(&self, offset: usize, depth: usize, count: usize) -> Result<usize, VerifyError> {
+        depth
+            .checked_sub(count)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::StackUnderflow))
+    }
+
+    fn fall_through(&mut self, offset: usize, depth: usize) -> Result<(), VerifyError> {
+        let next = offset + self.sizes[offset];
+        if next >= self.chunk.code.len() {
+            return Err(self.error(offset, VerifyErrorKind::MissingReturn));
+        }
+        self.flow(offset, next, depth)
+    }
+
+    fn jump(&mut self, offset: usize, target: usize, depth: usize) -> Result<(), VerifyError> {
+        if target >= self.chunk.code.len() || self.sizes[target] == 0 {
+            return Err(self.error(offset, VerifyErrorKind::InvalidJump));
+        }
+        self.flow(offset, target, depth)
+    }
+
+    fn flow(&mut self, offset: usize, target: usize, depth: usize) -> Result<(), VerifyError> {
+        if depth > common::LOCALS_MAX {
+            return Err(self.error(offset, VerifyErrorKind::StackOverflow));
+        }
+        match self.depths[target] {
+            Some(expected) if expected != depth => Err(self.error(
+                offset,
+                VerifyErrorKind::StackMismatch {
+                    expected,
+                    found: depth,
+                },
+            )),
+            Some(_) => Ok(()),
+            None => {
+                self.depths[target] = Some(depth);
+                self.worklist.push(target);
+                Ok(())
+            }
+        }
+    }
+
+    fn opcode(&self, offset: usize) -> Result<OpCode, VerifyError> {
+        let byte = self.chunk.code[offset];
+        OpCode::from_byte(byte)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::UnknownOpcode(byte)))
+    }
+
+    fn read_short(&self, offset: usize) -> usize {
+        u16::from_le_bytes([self.chunk.code[offset], self.chunk.code[offset + 1]]) as usize
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        self.chunk
+            .constants
+            .get(index)
+            .map(|constant| constant.unpack())
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::InvalidConstant(index)))
+    }
+
+    fn string_constant(&self, offset: usize) -> Result<(), VerifyError> {
+        let index = self.read_short(offset + 1);
+        match self.constant(offset, index)? {
+            Value::ObjString(_) => Ok(()),
+            _ => Err(self.error(offset, VerifyErrorKind::InvalidConstant(index))),
+        }
+    }
+
+    fn function_constant(&self, offset: usize) -> Result<Gc<ObjFunction>, VerifyError> {
+        let index = self.read_short(offset + 1);
+        match self.constant(offset, index)? {
+            Value::ObjFunction(function) => Ok(function),
+            _ => Err(self.error(offset, VerifyErrorKind::InvalidConstant(index))),
+        }
+    }
+
+    fn inline_cache(&self, offset: usize) -> Result<(), VerifyError> {
+        // The cache index follows the name of the property or method.
+        let index = self.read_short(offset + 3);
+        if index >= self.chunk.inline_caches.len() {
+            return Err(self.error(offset, VerifyErrorKind::InvalidInlineCache(index)));
+        }
+        Ok(())
+    }
+
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.function.name.as_str().to_string(),
+            offset,
+            kind,
+        }
     }
 }
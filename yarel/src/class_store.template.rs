@@ -15,7 +15,7 @@
 
 use crate::core;
 use crate::memory::{Gc, Root};
-use crate::object::{self, ObjClass};
+use crate::object::{self, ObjClass, ObjString};
 use crate::vm::{self, Vm};
 
 include!(concat!(env!("OUT_DIR"), "/core.yl.rs"));
@@ -26,6 +26,8 @@ pub struct CoreClassStore {
     root_object_class: Option<Root<ObjClass>>,
     {% for spec in class_specs %}
     root_{{ spec.name }}: Option<Root<ObjClass>>,{% endfor %}
+    {% for spec in name_specs %}
+    root_{{ spec.name }}_name: Option<Root<ObjString>>,{% endfor %}
 }
 
 impl CoreClassStore {
@@ -38,10 +40,16 @@ impl CoreClassStore {
         root_base_metaclass: Root<ObjClass>,
         root_object_class: Root<ObjClass>,
     ) -> Self {
-        let empty = vm.new_gc_obj_string("");
+        let empty = vm.new_root_obj_string("");
         let methods = object::new_obj_string_value_map();
-        let mut build_empty_class =
-            || vm.new_root_obj_class(empty, root_base_metaclass.as_gc(), None, methods.clone());
+        let mut build_empty_class = || {
+            vm.new_root_obj_class(
+                empty.as_gc(),
+                root_base_metaclass.as_gc(),
+                None,
+                methods.clone(),
+            )
+        };
         {% for spec in class_specs %}
         let root_{{ spec.name }} = build_empty_class();{% endfor %}
 
@@ -50,6 +58,8 @@ impl CoreClassStore {
             root_object_class: Some(root_object_class),
             {% for spec in class_specs %}
             root_{{ spec.name }}: Some(root_{{ spec.name }}),{% endfor %}
+            {% for spec in name_specs %}
+            root_{{ spec.name }}_name: Some(vm.new_root_obj_string("{{ spec.repr }}")),{% endfor %}
         }
     }
 
@@ -66,9 +76,9 @@ impl CoreClassStore {
         }
 
         let mut build_value_type_class = |name| {
-            let name = vm.new_gc_obj_string(name);
+            let name = vm.new_root_obj_string(name);
             vm.new_root_obj_class(
-                name,
+                name.as_gc(),
                 root_base_metaclass.as_gc(),
                 Some(root_object_class.as_gc()),
                 object::new_obj_string_value_map(),
@@ -95,6 +105,8 @@ impl CoreClassStore {
             root_object_class: Some(root_object_class),
             {% for spec in class_specs %}
             root_{{ spec.name }}: Some(root_{{ spec.name }}),{% endfor %}
+            {% for spec in name_specs %}
+            root_{{ spec.name }}_name: Some(vm.new_root_obj_string("{{ spec.repr }}")),{% endfor %}
         }
     }
 
@@ -121,4 +133,13 @@ impl CoreClassStore {
             .as_gc()
     }
     {% endfor %}
+
+    {% for spec in name_specs %}
+    pub(crate) fn {{ spec.name }}_name(&self) -> Gc<ObjString> {
+        self.root_{{ spec.name }}_name
+            .as_ref()
+            .expect("Expected Root.")
+            .as_gc()
+    }
+    {% endfor %}
 }
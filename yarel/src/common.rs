@@ -18,6 +18,8 @@ pub const LOCALS_MAX: usize = u8::MAX as usize + 1;
 pub const UPVALUES_MAX: usize = u8::MAX as usize + 1;
 pub const JUMP_SIZE_MAX: usize = u16::MAX as usize + 1;
 pub const HEAP_INIT_BYTES_MAX: usize = 65536;
-pub const HEAP_GROWTH_FACTOR: usize = 2;
+pub const HEAP_GROWTH_FACTOR: f64 = 2.0;
+pub const GC_STEP_OBJECTS: usize = 64;
 pub const VEC_ELEMS_MAX: usize = isize::MAX as usize + 1;
 pub const INTERPOLATION_DEPTH_MAX: usize = 8;
+pub const FUNCTION_DEPTH_MAX: usize = 256;
@@ -19,12 +19,13 @@ use std::fmt::Write;
 use std::mem;
 use std::path::Path;
 
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, SequenceKind, UnpackRest};
 use crate::common;
 use crate::debug;
 use crate::error::{Error, ErrorKind};
 use crate::memory::{Gc, Root};
 use crate::object::{ObjFunction, ObjString};
+use crate::optimizer;
 use crate::scanner::{Scanner, Token, TokenKind};
 use crate::value::{self, Value};
 use crate::vm::Vm;
@@ -120,6 +121,54 @@ struct Upvalue {
     is_local: bool,
 }
 
+#[derive(Default)]
+struct ElementList {
+    count: usize,
+    has_spread: bool,
+}
+
+struct DestructuringPattern {
+    names: Vec<Token>,
+    rest: UnpackRest,
+}
+
+enum Pattern {
+    Wildcard,
+    Binding(Token),
+    Literal(Value),
+    Range(f64, f64),
+    Sequence {
+        kind: SequenceKind,
+        elements: Vec<Pattern>,
+        has_rest: bool,
+        rest_binding: Option<Token>,
+    },
+    Class {
+        name: Token,
+        fields: Vec<(Token, Pattern)>,
+    },
+}
+
+impl Pattern {
+    fn has_bindings(&self) -> bool {
+        match self {
+            Pattern::Binding(_) => true,
+            Pattern::Sequence {
+                elements,
+                rest_binding,
+                ..
+            } => rest_binding.is_some() || elements.iter().any(Pattern::has_bindings),
+            Pattern::Class { fields, .. } => fields.iter().any(|(_, p)| p.has_bindings()),
+            _ => false,
+        }
+    }
+}
+
+enum PatternAccess {
+    Element(usize),
+    Field(Token),
+}
+
 struct Compiler {
     function: ObjFunction,
     kind: FunctionKind,
@@ -171,8 +220,9 @@ impl Compiler {
 
     fn allocate_function(&mut self, vm: &mut Vm) -> Root<ObjFunction> {
         let chunk = mem::replace(&mut self.chunk, Chunk::new());
-        let chunk = vm.add_chunk(chunk);
-        self.function.chunk = chunk;
+        // The chunk is only kept alive by the function once the function has been allocated.
+        let chunk = vm.new_root_chunk(chunk);
+        self.function.chunk = chunk.as_gc();
         let function = mem::take(&mut self.function);
         Root::new(function)
     }
@@ -237,7 +287,7 @@ impl Compiler {
             return Err(CompilerError::JumpTooLarge);
         }
 
-        let bytes = (jump as u16).to_ne_bytes();
+        let bytes = (jump as u16).to_le_bytes();
 
         self.chunk.code[offset] = bytes[0];
         self.chunk.code[offset + 1] = bytes[1];
@@ -297,6 +347,7 @@ struct Attribute {
 struct Parser<'a> {
     current: Token,
     previous: Token,
+    next: Option<Token>,
     panic_mode: Cell<bool>,
     single_target_mode: bool,
     scanner: &'a mut Scanner,
@@ -304,6 +355,9 @@ struct Parser<'a> {
     class_compilers: Vec<ClassCompiler>,
     errors: RefCell<Vec<String>>,
     compiled_functions: Vec<Root<ObjFunction>>,
+    // Interned strings aren't kept alive by the VM, so those used by the functions being compiled
+    // are rooted until compilation finishes.
+    strings: Vec<Root<ObjString>>,
     module_path: Gc<ObjString>,
     attributes: HashMap<String, Attribute>,
     attribute_opener: Option<Token>,
@@ -312,11 +366,12 @@ struct Parser<'a> {
 
 impl<'a> Parser<'a> {
     fn new(vm: &'a mut Vm, scanner: &'a mut Scanner, module_path: Option<&str>) -> Parser<'a> {
-        let module_path = vm.new_gc_obj_string(module_path.unwrap_or("main"));
-        let empty = vm.new_gc_obj_string("");
+        let module_path = vm.new_root_obj_string(module_path.unwrap_or("main"));
+        let empty = vm.new_root_obj_string("");
         let mut ret = Parser {
             current: Token::new(),
             previous: Token::new(),
+            next: None,
             panic_mode: Cell::new(false),
             single_target_mode: false,
             scanner,
@@ -324,12 +379,21 @@ impl<'a> Parser<'a> {
             class_compilers: Vec::new(),
             errors: RefCell::new(Vec::new()),
             compiled_functions: Vec::new(),
-            module_path,
+            strings: Vec::new(),
+            module_path: module_path.as_gc(),
             attributes: HashMap::new(),
             attribute_opener: None,
             vm,
         };
-        ret.new_compiler(FunctionKind::Script, empty, module_path);
+        ret.new_compiler(FunctionKind::Script, empty.as_gc(), module_path.as_gc());
+        ret.strings.extend([module_path, empty]);
+        ret
+    }
+
+    fn new_gc_obj_string(&mut self, string: &str) -> Gc<ObjString> {
+        let string = self.vm.new_root_obj_string(string);
+        let ret = string.as_gc();
+        self.strings.push(string);
         ret
     }
 
@@ -361,7 +425,10 @@ impl<'a> Parser<'a> {
         self.previous = self.current.clone();
 
         loop {
-            self.current = self.scanner.scan_token();
+            self.current = self
+                .next
+                .take()
+                .unwrap_or_else(|| self.scanner.scan_token());
             if self.current.kind != TokenKind::Error {
                 break;
             }
@@ -383,6 +450,13 @@ impl<'a> Parser<'a> {
         self.current.kind == kind
     }
 
+    fn check_next(&mut self, kind: TokenKind) -> bool {
+        if self.next.is_none() {
+            self.next = Some(self.scanner.scan_token());
+        }
+        self.next.as_ref().unwrap().kind == kind
+    }
+
     fn check_any(&self, kinds: &[TokenKind]) -> bool {
         kinds.iter().any(|k| self.check(*k))
     }
@@ -438,6 +512,12 @@ impl<'a> Parser<'a> {
         self.emit_return();
 
         let mut compiler = self.compilers.pop().expect("Compiler stack empty.");
+        // Jumps in code containing errors may not have been patched, so it isn't optimised.
+        if self.errors.borrow().is_empty() {
+            let level = self.vm.optimization_level();
+            let strings = optimizer::optimize(self.vm, &mut compiler.chunk, level);
+            self.strings.extend(strings);
+        }
         let function = compiler.allocate_function(self.vm);
         self.compiled_functions.push(function.clone());
 
@@ -450,10 +530,11 @@ impl<'a> Parser<'a> {
         (function, compiler.upvalues)
     }
 
-    fn function(&mut self, kind: FunctionKind) {
+    fn function(&mut self, kind: FunctionKind, is_async: bool) {
         let name = self.previous.source.clone();
-        let name = self.vm.new_gc_obj_string(name.as_str());
+        let name = self.new_gc_obj_string(name.as_str());
         self.new_compiler(kind, name, self.module_path);
+        self.compiler_mut().function.is_async = is_async;
         self.begin_scope();
 
         self.consume(TokenKind::LeftParen, "Expected '(' after function name.");
@@ -501,6 +582,9 @@ impl<'a> Parser<'a> {
         let constructor_attr = self.take_attribute("constructor", 0);
         self.check_supported_attributes("method");
 
+        let async_token = self
+            .match_token(TokenKind::Async)
+            .then(|| self.previous.clone());
         self.consume(TokenKind::Fn, "Expected 'fn' before method name.");
         self.consume(TokenKind::Identifier, "Expected method name.");
         let previous = self.previous.clone();
@@ -510,13 +594,16 @@ impl<'a> Parser<'a> {
             if let Some(attr) = static_attr {
                 self.error_at(attr.name, "Constructors cannot be static.");
             }
+            if let Some(token) = async_token.clone() {
+                self.error_at(token, "Constructors cannot be async.");
+            }
             FunctionKind::Initialiser
         } else if static_attr.is_some() {
             FunctionKind::StaticMethod
         } else {
             FunctionKind::Method
         };
-        self.function(kind);
+        self.function(kind, async_token.is_some());
         let opcode = if kind == FunctionKind::Method {
             OpCode::Method
         } else {
@@ -529,7 +616,7 @@ impl<'a> Parser<'a> {
         let name_constant = self.identifier_constant(&name);
         let kind = FunctionKind::Initialiser;
 
-        let name = self.vm.new_gc_obj_string(name.source.as_str());
+        let name = self.new_gc_obj_string(name.source.as_str());
         self.new_compiler(kind, name, self.module_path);
         self.begin_scope();
         self.emit_bytes([OpCode::Construct as u8, 0]);
@@ -601,11 +688,11 @@ impl<'a> Parser<'a> {
         self.class_compilers.pop();
     }
 
-    fn fn_declaration(&mut self) {
+    fn fn_declaration(&mut self, is_async: bool) {
         self.check_supported_attributes("function");
         let global = self.parse_variable("Expected function name.");
         self.mark_initialised();
-        self.function(FunctionKind::Function);
+        self.function(FunctionKind::Function, is_async);
         self.define_variable(global);
     }
 
@@ -693,6 +780,10 @@ impl<'a> Parser<'a> {
 
     fn var_declaration(&mut self) {
         self.check_no_attributes();
+        if let Some(pattern) = self.destructuring_pattern() {
+            self.destructuring_declaration(pattern);
+            return;
+        }
         let global = self.parse_variable("Expected variable name.");
 
         if self.match_token(TokenKind::Equal) {
@@ -708,6 +799,23 @@ impl<'a> Parser<'a> {
         self.define_variable(global);
     }
 
+    fn destructuring_declaration(&mut self, pattern: DestructuringPattern) {
+        let variables = self.declare_variables(&pattern.names);
+
+        self.consume(
+            TokenKind::Equal,
+            "Expected '=' after destructuring pattern.",
+        );
+        self.expression();
+        self.consume(
+            TokenKind::SemiColon,
+            "Expected ';' after variable declaration.",
+        );
+
+        self.emit_unpack(&pattern);
+        self.define_variables(&variables);
+    }
+
     fn expression_statement(&mut self) {
         self.expression();
         self.consume(TokenKind::SemiColon, "Expected ';' after expression.");
@@ -752,6 +860,7 @@ impl<'a> Parser<'a> {
         self.begin_scope();
 
         let loop_iter_name = "... temp-iter-var ...";
+        let loop_var_name = "... temp-loop-var ...";
 
         // For loops take the following form:
         // for v in [1, 2, 3] {
@@ -765,12 +874,18 @@ impl<'a> Parser<'a> {
         //     ... loop body ...
         // }
 
-        // Set up loop variable
-        if !self.match_token(TokenKind::Identifier) {
+        // Set up loop variable. If the loop variable is a destructuring pattern, each value is
+        // stored in a hidden variable and unpacked at the start of the loop body.
+        let pattern = self.destructuring_pattern();
+        if pattern.is_some() {
+            self.compiler_mut()
+                .add_local(&Token::from_string(loop_var_name));
+        } else if self.match_token(TokenKind::Identifier) {
+            self.declare_variable();
+        } else {
             self.error_at_current("Expected loop variable name.");
             return;
         }
-        self.declare_variable();
         let loop_var = self.compiler().locals.len() - 1;
         self.emit_byte(OpCode::Nil as u8);
 
@@ -804,6 +919,12 @@ impl<'a> Parser<'a> {
 
         self.consume(TokenKind::LeftBrace, "Expected '{' after loop expression.");
         self.begin_scope();
+        if let Some(pattern) = pattern {
+            let variables = self.declare_variables(&pattern.names);
+            self.emit_bytes([OpCode::GetLocal as u8, loop_var as u8]);
+            self.emit_unpack(&pattern);
+            self.define_variables(&variables);
+        }
         self.block();
         self.end_scope();
 
@@ -818,6 +939,76 @@ impl<'a> Parser<'a> {
         self.end_scope();
     }
 
+    fn match_statement(&mut self) {
+        self.begin_scope();
+
+        // The matched value is stored in a hidden local so that each arm can test it against its
+        // pattern. Patterns are checked in full before any variables are bound, so a failed check
+        // only ever has to discard the result of the check itself.
+        self.expression();
+        self.compiler_mut()
+            .add_local(&Token::from_string("... temp-match-var ..."));
+        self.mark_initialised();
+        let subject = (self.compiler().locals.len() - 1) as u8;
+
+        self.consume(TokenKind::LeftBrace, "Expected '{' after match value.");
+        let mut end_jumps = Vec::new();
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            end_jumps.push(self.match_arm(subject));
+        }
+        self.consume(TokenKind::RightBrace, "Expected '}' after match arms.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    fn match_arm(&mut self, subject: u8) -> usize {
+        let pattern = self.pattern();
+
+        let mut check_jumps = Vec::new();
+        self.emit_pattern_checks(&pattern, subject, &mut Vec::new(), &mut check_jumps);
+
+        self.begin_scope();
+        self.emit_pattern_bindings(&pattern, subject);
+
+        let mut guard_fail_jump = None;
+        if self.match_token(TokenKind::If) {
+            self.expression();
+            let guard_jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit_byte(OpCode::Pop as u8);
+            let body_jump = self.emit_jump(OpCode::Jump);
+
+            // If the guard fails then the variables bound by the pattern need to be discarded
+            // before moving on to the next arm.
+            self.patch_jump(guard_jump);
+            self.emit_byte(OpCode::Pop as u8);
+            let scope_depth = self.compiler().scope_depth - 1;
+            self.emit_scope_end(false, scope_depth);
+            guard_fail_jump = Some(self.emit_jump(OpCode::Jump));
+
+            self.patch_jump(body_jump);
+        }
+
+        self.consume(TokenKind::EqualGreater, "Expected '=>' after pattern.");
+        self.statement();
+        self.end_scope();
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        if !check_jumps.is_empty() {
+            for jump in check_jumps {
+                self.patch_jump(jump);
+            }
+            self.emit_byte(OpCode::Pop as u8);
+        }
+        if let Some(jump) = guard_fail_jump {
+            self.patch_jump(jump);
+        }
+
+        end_jump
+    }
+
     fn if_statement(&mut self) {
         self.expression();
 
@@ -862,21 +1053,45 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn yield_statement(&mut self) {
+        let (kind, is_async) = (self.compiler().kind, self.compiler().function.is_async);
+        match kind {
+            FunctionKind::Script => self.error("Cannot yield from top-level code."),
+            FunctionKind::Initialiser => self.error("Cannot yield from an initialiser."),
+            _ if is_async => self.error("Cannot yield from an async function."),
+            _ => {}
+        }
+        // Any function containing a yield statement is a generator, so calling it produces a
+        // generator object instead of running the function body.
+        self.compiler_mut().function.is_generator = true;
+        if self.match_token(TokenKind::SemiColon) {
+            self.emit_byte(OpCode::Nil as u8);
+        } else {
+            self.expression();
+            self.consume(TokenKind::SemiColon, "Expected ';' after yield value.");
+        }
+        // The yielded value is left in place while the generator is suspended and then discarded
+        // when it's resumed.
+        self.emit_byte(OpCode::Yield as u8);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
     fn break_statement(&mut self) {
-        let break_pos = self.emit_jump(OpCode::Jump);
-        match self.compiler_mut().push_break(break_pos) {
-            Ok(_) => {}
-            Err(e) => {
-                self.compiler_error(e);
+        let scope_depth = match self.compiler().current_loop_header() {
+            Some((_, depth)) => depth,
+            None => {
+                self.compiler_error(CompilerError::InvalidControlStatement);
                 return;
             }
-        }
-        let scope_depth = self
-            .compiler()
-            .current_loop_header()
-            .expect("Expected tuple.")
-            .1;
+        };
+        // The loop's locals are popped before jumping, so the stack is the same size as when the
+        // loop exits normally.
         self.emit_scope_end(false, scope_depth);
+        let break_pos = self.emit_jump(OpCode::Jump);
+        if let Err(e) = self.compiler_mut().push_break(break_pos) {
+            self.compiler_error(e);
+            return;
+        }
         self.consume(TokenKind::SemiColon, "Expected ';' after 'break'.");
     }
 
@@ -895,6 +1110,15 @@ impl<'a> Parser<'a> {
 
     fn throw_statement(&mut self) {
         self.expression();
+        // 'from' isn't reserved, since it's also used as a method name (e.g. 'String.from').
+        if self.check(TokenKind::Identifier) && self.current.source == "from" {
+            self.advance();
+            self.emit_byte(OpCode::CopyTop as u8);
+            self.expression();
+            let cause = self.identifier_constant(&Token::from_string("cause"));
+            self.emit_constant_op(OpCode::SetProperty, cause);
+            self.emit_byte(OpCode::Pop as u8);
+        }
         self.consume(TokenKind::SemiColon, "Expected ';' after throw value.");
         self.emit_byte(OpCode::Throw as u8);
     }
@@ -920,23 +1144,52 @@ impl<'a> Parser<'a> {
         self.patch_offset_at(handler_catch_arg_pos, post_handler_args_ip_pos);
         let catch_start_pos = self.chunk().code.len();
 
-        let have_catch = self.match_token(TokenKind::Catch);
+        let have_catch = self.check(TokenKind::Catch);
 
         if have_catch {
-            self.emit_byte(OpCode::PopExcHandler as u8);
-            if !self.match_token(TokenKind::Identifier) {
-                self.error_at_current("Expected exception variable name.");
-                return;
-            }
+            // The exception handler has already been popped by the time the catch clauses run,
+            // so exceptions thrown from here propagate to any enclosing handler.
             self.begin_scope();
 
-            self.declare_variable();
+            // The exception is stored in a hidden local so that each catch clause can check its
+            // type. If no clause matches, the exception continues to unwind the stack, running
+            // any finally block first.
+            self.compiler_mut()
+                .add_local(&Token::from_string("... temp-exc-var ..."));
             self.mark_initialised();
+            let exc = (self.compiler().locals.len() - 1) as u8;
 
-            self.consume(TokenKind::LeftBrace, "Expected '{' after variable.");
+            let mut end_jumps = Vec::new();
+            let mut have_catch_all = false;
+            while self.match_token(TokenKind::Catch) {
+                if have_catch_all {
+                    self.error("Cannot have a catch clause after one without exception types.");
+                }
+                match self.catch_clause(exc) {
+                    Some((end_jump, is_catch_all)) => {
+                        end_jumps.push(end_jump);
+                        have_catch_all = is_catch_all;
+                    }
+                    None => return,
+                }
+            }
 
-            self.block();
+            let mut rethrow_jump = None;
+            if !have_catch_all {
+                if self.check(TokenKind::Finally) {
+                    rethrow_jump = Some(self.emit_jump(OpCode::Rethrow));
+                } else {
+                    self.emit_byte(OpCode::Throw as u8);
+                }
+            }
+
+            for jump in end_jumps {
+                self.patch_jump(jump);
+            }
             self.end_scope();
+            if let Some(jump) = rethrow_jump {
+                self.patch_jump(jump);
+            }
         }
 
         self.patch_jump(catch_jump_pos);
@@ -958,6 +1211,63 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn catch_clause(&mut self, exc: u8) -> Option<(usize, bool)> {
+        if !self.match_token(TokenKind::Identifier) {
+            self.error_at_current("Expected exception variable name.");
+            return None;
+        }
+        let name = self.previous.clone();
+
+        let mut next_jump = None;
+        let is_catch_all = !self.match_token(TokenKind::Colon);
+        if !is_catch_all {
+            let mut body_jumps = Vec::new();
+            loop {
+                self.emit_bytes([OpCode::GetLocal as u8, exc]);
+                self.parse_precedence(Precedence::BitwiseXor);
+                let derives = self.identifier_constant(&Token::from_string("derives"));
+                self.emit_constant_op(OpCode::Invoke, derives);
+                self.emit_byte(1);
+
+                if !self.match_token(TokenKind::Bar) {
+                    break;
+                }
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop as u8);
+                body_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(else_jump);
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            next_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8);
+            for jump in body_jumps {
+                self.patch_jump(jump);
+            }
+        }
+
+        self.begin_scope();
+        self.emit_bytes([OpCode::GetLocal as u8, exc]);
+        self.previous = name;
+        self.declare_variable();
+        self.mark_initialised();
+
+        let msg = if is_catch_all {
+            "Expected '{' after variable."
+        } else {
+            "Expected '{' after exception types."
+        };
+        self.consume(TokenKind::LeftBrace, msg);
+        self.block();
+        self.end_scope();
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        if let Some(jump) = next_jump {
+            self.patch_jump(jump);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+        Some((end_jump, is_catch_all))
+    }
+
     fn while_statement(&mut self) {
         self.compiler_mut().push_loop();
         let loop_start = self.chunk().code.len();
@@ -995,13 +1305,16 @@ impl<'a> Parser<'a> {
                 TokenKind::Hash => return,
                 TokenKind::Class => return,
                 TokenKind::Fn => return,
+                TokenKind::Async => return,
                 TokenKind::Var => return,
                 TokenKind::For => return,
                 TokenKind::If => return,
+                TokenKind::Match => return,
                 TokenKind::While => return,
                 TokenKind::Break => return,
                 TokenKind::Continue => return,
                 TokenKind::Return => return,
+                TokenKind::Yield => return,
                 _ => {}
             }
 
@@ -1027,6 +1340,8 @@ impl<'a> Parser<'a> {
             self.for_statement();
         } else if self.match_token(TokenKind::If) {
             self.if_statement();
+        } else if self.match_token(TokenKind::Match) {
+            self.match_statement();
         } else if self.match_token(TokenKind::Return) {
             self.return_statement();
         } else if self.match_token(TokenKind::Break) {
@@ -1039,6 +1354,8 @@ impl<'a> Parser<'a> {
             self.try_statement();
         } else if self.match_token(TokenKind::While) {
             self.while_statement();
+        } else if self.match_token(TokenKind::Yield) {
+            self.yield_statement();
         } else if self.match_token(TokenKind::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -1052,7 +1369,10 @@ impl<'a> Parser<'a> {
         if self.match_token(TokenKind::Class) {
             self.class_declaration();
         } else if self.match_token(TokenKind::Fn) {
-            self.fn_declaration();
+            self.fn_declaration(false);
+        } else if self.match_token(TokenKind::Async) {
+            self.consume(TokenKind::Fn, "Expected 'fn' after 'async'.");
+            self.fn_declaration(true);
         } else if self.match_token(TokenKind::Hash) {
             self.attributes_declaration();
         } else if self.match_token(TokenKind::Var) {
@@ -1083,7 +1403,11 @@ impl<'a> Parser<'a> {
 
     fn emit_constant_op(&mut self, opcode: OpCode, constant: u16) {
         self.emit_byte(opcode as u8);
-        self.emit_bytes(constant.to_ne_bytes());
+        self.emit_bytes(constant.to_le_bytes());
+        if let OpCode::GetProperty | OpCode::Invoke | OpCode::SuperInvoke = opcode {
+            let cache = self.make_inline_cache();
+            self.emit_bytes(cache.to_le_bytes());
+        }
     }
 
     fn emit_variable_op(&mut self, opcode: OpCode, variable: u16) {
@@ -1102,7 +1426,7 @@ impl<'a> Parser<'a> {
             self.error("Loop body too large.");
         }
 
-        let bytes = (offset as u16).to_ne_bytes();
+        let bytes = (offset as u16).to_le_bytes();
 
         self.emit_byte(bytes[0]);
         self.emit_byte(bytes[1]);
@@ -1156,10 +1480,19 @@ impl<'a> Parser<'a> {
         constant as u16
     }
 
+    fn make_inline_cache(&mut self) -> u16 {
+        let cache = self.chunk().add_inline_cache();
+        if cache > u16::MAX as usize {
+            self.error("Too many property accesses in one chunk.");
+            return 0;
+        }
+        cache as u16
+    }
+
     fn emit_constant(&mut self, value: value::Value) {
         let constant = self.make_constant(value);
         self.emit_byte(OpCode::Constant as u8);
-        self.emit_bytes(constant.to_ne_bytes());
+        self.emit_bytes(constant.to_le_bytes());
     }
 
     fn patch_jump(&mut self, offset: usize) {
@@ -1175,7 +1508,7 @@ impl<'a> Parser<'a> {
             self.error("Too much code in block.");
         }
 
-        let bytes = (jump as u16).to_ne_bytes();
+        let bytes = (jump as u16).to_le_bytes();
 
         self.chunk().code[pos] = bytes[0];
         self.chunk().code[pos + 1] = bytes[1];
@@ -1207,7 +1540,7 @@ impl<'a> Parser<'a> {
     }
 
     fn identifier_constant(&mut self, token: &Token) -> u16 {
-        let value = Value::ObjString(self.vm.new_gc_obj_string(&token.source));
+        let value = Value::ObjString(self.new_gc_obj_string(&token.source));
         self.make_constant(value)
     }
 
@@ -1253,6 +1586,381 @@ impl<'a> Parser<'a> {
         self.compiler_mut().mark_last_initialised();
     }
 
+    fn destructuring_pattern(&mut self) -> Option<DestructuringPattern> {
+        let (closing_kind, rest_kind, message) = if self.match_token(TokenKind::LeftParen) {
+            (
+                TokenKind::RightParen,
+                UnpackRest::Tuple,
+                "Expected ')' after destructuring pattern.",
+            )
+        } else if self.match_token(TokenKind::LeftBracket) {
+            (
+                TokenKind::RightBracket,
+                UnpackRest::Vec,
+                "Expected ']' after destructuring pattern.",
+            )
+        } else {
+            return None;
+        };
+
+        let mut pattern = DestructuringPattern {
+            names: Vec::new(),
+            rest: UnpackRest::None,
+        };
+        while !self.check(closing_kind) {
+            let is_rest = self.match_token(TokenKind::DotDotDot);
+            self.consume(TokenKind::Identifier, "Expected variable name.");
+            pattern.names.push(self.previous.clone());
+            if is_rest {
+                pattern.rest = rest_kind;
+                break;
+            }
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(closing_kind, message);
+
+        if pattern.names.is_empty() {
+            self.error("Expected variable name.");
+        } else if pattern.names.len() > u8::MAX as usize {
+            self.error("Too many variables in destructuring pattern.");
+        }
+        Some(pattern)
+    }
+
+    fn declare_variables(&mut self, names: &[Token]) -> Vec<u16> {
+        let mut globals = Vec::with_capacity(names.len());
+        for name in names {
+            // As with imports, the variable name has to be injected as the previous token in
+            // order to declare it.
+            self.previous = name.clone();
+            self.declare_variable();
+            globals.push(if self.compiler().scope_depth > 0 {
+                0
+            } else {
+                self.identifier_constant(name)
+            });
+        }
+        globals
+    }
+
+    fn define_variables(&mut self, globals: &[u16]) {
+        if self.compiler().scope_depth > 0 {
+            let num_locals = self.compiler().locals.len();
+            for local in num_locals - globals.len()..num_locals {
+                self.compiler_mut().mark_initialised(local);
+            }
+            return;
+        }
+
+        // The unpacked values are on the stack in declaration order, so they're popped in
+        // reverse.
+        for &global in globals.iter().rev() {
+            self.emit_byte(OpCode::DefineGlobal as u8);
+            self.emit_bytes(global.to_le_bytes());
+        }
+    }
+
+    fn emit_unpack(&mut self, pattern: &DestructuringPattern) {
+        let num_elements = pattern.names.len() - (pattern.rest != UnpackRest::None) as usize;
+        self.emit_byte(OpCode::Unpack as u8);
+        self.emit_bytes([num_elements as u8, pattern.rest as u8]);
+    }
+
+    fn pattern(&mut self) -> Pattern {
+        if self.match_token(TokenKind::LeftParen) {
+            return self.sequence_pattern(
+                SequenceKind::Tuple,
+                TokenKind::RightParen,
+                "Expected ')' after tuple pattern.",
+            );
+        }
+        if self.match_token(TokenKind::LeftBracket) {
+            return self.sequence_pattern(
+                SequenceKind::Vec,
+                TokenKind::RightBracket,
+                "Expected ']' after vec pattern.",
+            );
+        }
+        if self.match_token(TokenKind::Identifier) {
+            let name = self.previous.clone();
+            if self.match_token(TokenKind::LeftBrace) {
+                return self.class_pattern(name);
+            }
+            return if name.source == "_" {
+                Pattern::Wildcard
+            } else {
+                Pattern::Binding(name)
+            };
+        }
+
+        let literal = self.literal_pattern();
+        if !self.match_token(TokenKind::DotDot) {
+            return Pattern::Literal(literal);
+        }
+        let end = self.literal_pattern();
+        match (literal, end) {
+            (Value::Number(begin), Value::Number(end)) => Pattern::Range(begin, end),
+            _ => {
+                self.error("Expected numbers in range pattern.");
+                Pattern::Wildcard
+            }
+        }
+    }
+
+    fn literal_pattern(&mut self) -> Value {
+        if self.match_token(TokenKind::Str) {
+            let source = self.previous.source.clone();
+            return Value::ObjString(self.new_gc_obj_string(&source));
+        }
+        if self.match_token(TokenKind::True) {
+            return Value::Boolean(true);
+        }
+        if self.match_token(TokenKind::False) {
+            return Value::Boolean(false);
+        }
+        if self.match_token(TokenKind::Nil) {
+            return Value::None;
+        }
+
+        let negate = self.match_token(TokenKind::Minus);
+        if !self.match_token(TokenKind::Number) {
+            self.advance();
+            self.error("Expected pattern.");
+            return Value::None;
+        }
+        match self.previous.source.as_str().parse::<f64>() {
+            Ok(n) => Value::Number(if negate { -n } else { n }),
+            Err(_) => {
+                self.error("Unable to parse number.");
+                Value::None
+            }
+        }
+    }
+
+    fn sequence_pattern(
+        &mut self,
+        kind: SequenceKind,
+        closing_kind: TokenKind,
+        message: &str,
+    ) -> Pattern {
+        let mut elements = Vec::new();
+        let mut has_rest = false;
+        let mut rest_binding = None;
+        while !self.check(closing_kind) && !self.check(TokenKind::Eof) {
+            if self.match_token(TokenKind::DotDotDot) {
+                self.consume(TokenKind::Identifier, "Expected variable name.");
+                has_rest = true;
+                if self.previous.source != "_" {
+                    rest_binding = Some(self.previous.clone());
+                }
+                break;
+            }
+            elements.push(self.pattern());
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(closing_kind, message);
+
+        if elements.len() > u8::MAX as usize {
+            self.error("Too many elements in sequence pattern.");
+        }
+        Pattern::Sequence {
+            kind,
+            elements,
+            has_rest,
+            rest_binding,
+        }
+    }
+
+    fn class_pattern(&mut self, name: Token) -> Pattern {
+        let mut fields = Vec::new();
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.consume(TokenKind::Identifier, "Expected field name.");
+            let field = self.previous.clone();
+            let pattern = if self.match_token(TokenKind::Colon) {
+                self.pattern()
+            } else {
+                Pattern::Binding(field.clone())
+            };
+            fields.push((field, pattern));
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBrace, "Expected '}' after class pattern.");
+        Pattern::Class { name, fields }
+    }
+
+    fn emit_pattern_load(&mut self, subject: u8, path: &[PatternAccess]) {
+        self.emit_bytes([OpCode::GetLocal as u8, subject]);
+        for access in path {
+            match access {
+                PatternAccess::Element(index) => {
+                    self.emit_constant(Value::Number(*index as f64));
+                    let name = self.identifier_constant(&Token::from_string("__getitem__"));
+                    self.emit_constant_op(OpCode::Invoke, name);
+                    self.emit_byte(1);
+                }
+                PatternAccess::Field(field) => {
+                    let name = self.identifier_constant(field);
+                    self.emit_constant_op(OpCode::GetProperty, name);
+                }
+            }
+        }
+    }
+
+    fn emit_pattern_checks(
+        &mut self,
+        pattern: &Pattern,
+        subject: u8,
+        path: &mut Vec<PatternAccess>,
+        jumps: &mut Vec<usize>,
+    ) {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => return,
+            Pattern::Literal(value) => {
+                self.emit_pattern_load(subject, path);
+                self.emit_constant(*value);
+                self.emit_byte(OpCode::Equal as u8);
+            }
+            Pattern::Range(begin, end) => {
+                self.emit_pattern_load(subject, path);
+                self.emit_constant(Value::Number(*begin));
+                self.emit_constant(Value::Number(*end));
+                self.emit_byte(OpCode::MatchRange as u8);
+            }
+            Pattern::Sequence {
+                kind,
+                elements,
+                has_rest,
+                ..
+            } => {
+                self.emit_pattern_load(subject, path);
+                self.emit_byte(OpCode::MatchSequence as u8);
+                self.emit_bytes([elements.len() as u8, *kind as u8]);
+                self.emit_byte(*has_rest as u8);
+            }
+            Pattern::Class { name, .. } => {
+                self.emit_pattern_load(subject, path);
+                self.named_variable(name.clone(), false);
+                let derives = self.identifier_constant(&Token::from_string("derives"));
+                self.emit_constant_op(OpCode::Invoke, derives);
+                self.emit_byte(1);
+            }
+        }
+        jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+        self.emit_byte(OpCode::Pop as u8);
+
+        // The pattern's elements are only checked once the pattern itself is known to match. A
+        // class pattern's fields may still be missing, so each one is checked for before it's
+        // accessed.
+        match pattern {
+            Pattern::Sequence { elements, .. } => {
+                for (i, element) in elements.iter().enumerate() {
+                    path.push(PatternAccess::Element(i));
+                    self.emit_pattern_checks(element, subject, path, jumps);
+                    path.pop();
+                }
+            }
+            Pattern::Class { fields, .. } => {
+                for (field, field_pattern) in fields {
+                    self.emit_pattern_load(subject, path);
+                    let name = self.identifier_constant(field);
+                    self.emit_constant_op(OpCode::MatchField, name);
+                    jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+                    self.emit_byte(OpCode::Pop as u8);
+
+                    path.push(PatternAccess::Field(field.clone()));
+                    self.emit_pattern_checks(field_pattern, subject, path, jumps);
+                    path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn emit_pattern_bindings(&mut self, pattern: &Pattern, source: u8) {
+        match pattern {
+            Pattern::Binding(name) => {
+                self.emit_bytes([OpCode::GetLocal as u8, source]);
+                self.declare_pattern_local(Some(name));
+            }
+            Pattern::Sequence {
+                kind,
+                elements,
+                has_rest,
+                rest_binding,
+            } => {
+                if !pattern.has_bindings() {
+                    return;
+                }
+                let rest = match (*has_rest, kind) {
+                    (false, _) => UnpackRest::None,
+                    (true, SequenceKind::Tuple) => UnpackRest::Tuple,
+                    (true, SequenceKind::Vec) => UnpackRest::Vec,
+                };
+                self.emit_bytes([OpCode::GetLocal as u8, source]);
+                self.emit_byte(OpCode::Unpack as u8);
+                self.emit_bytes([elements.len() as u8, rest as u8]);
+
+                let mut slots = Vec::with_capacity(elements.len());
+                for element in elements {
+                    let name = match element {
+                        Pattern::Binding(name) => Some(name),
+                        _ => None,
+                    };
+                    slots.push(self.declare_pattern_local(name));
+                }
+                if *has_rest {
+                    self.declare_pattern_local(rest_binding.as_ref());
+                }
+                for (element, slot) in elements.iter().zip(slots) {
+                    if !matches!(element, Pattern::Binding(_)) {
+                        self.emit_pattern_bindings(element, slot);
+                    }
+                }
+            }
+            Pattern::Class { fields, .. } => {
+                for (field, field_pattern) in fields {
+                    if !field_pattern.has_bindings() {
+                        continue;
+                    }
+                    self.emit_bytes([OpCode::GetLocal as u8, source]);
+                    let name = self.identifier_constant(field);
+                    self.emit_constant_op(OpCode::GetProperty, name);
+                    if let Pattern::Binding(name) = field_pattern {
+                        self.declare_pattern_local(Some(name));
+                    } else {
+                        let slot = self.declare_pattern_local(None);
+                        self.emit_pattern_bindings(field_pattern, slot);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn declare_pattern_local(&mut self, name: Option<&Token>) -> u8 {
+        // Values that aren't bound to a variable are stored in hidden locals so that any nested
+        // patterns can be unpacked from them.
+        if let Some(name) = name {
+            // As with imports, the variable name has to be injected as the previous token in
+            // order to declare it.
+            self.previous = name.clone();
+            self.declare_variable();
+        } else if !self
+            .compiler_mut()
+            .add_local(&Token::from_string("... temp-match-elem ..."))
+        {
+            self.error("Too many variables in function.");
+        }
+        self.compiler_mut().mark_last_initialised();
+        (self.compiler().locals.len() - 1) as u8
+    }
+
     fn define_variable(&mut self, global: u16) {
         if self.compiler().scope_depth > 0 {
             self.mark_initialised();
@@ -1260,18 +1968,20 @@ impl<'a> Parser<'a> {
         }
 
         self.emit_byte(OpCode::DefineGlobal as u8);
-        self.emit_bytes(global.to_ne_bytes());
+        self.emit_bytes(global.to_le_bytes());
     }
 
-    fn argument_list(&mut self, right_delim: TokenKind, count_msg: &str, delim_msg: &str) -> u8 {
-        let mut arg_count: usize = 0;
+    fn argument_list(
+        &mut self,
+        accumulator: OpCode,
+        right_delim: TokenKind,
+        count_msg: &str,
+        delim_msg: &str,
+    ) -> Option<u8> {
+        let mut elements = ElementList::default();
         if !self.check(right_delim) {
             loop {
-                self.expression();
-                if arg_count == 255 {
-                    self.error(count_msg);
-                }
-                arg_count += 1;
+                self.list_element(&mut elements, accumulator, count_msg);
 
                 if !self.match_token(TokenKind::Comma) {
                     break;
@@ -1280,7 +1990,46 @@ impl<'a> Parser<'a> {
         }
 
         self.consume(right_delim, delim_msg);
-        arg_count as u8
+        self.finish_list(&elements)
+    }
+
+    fn list_element(&mut self, elements: &mut ElementList, accumulator: OpCode, count_msg: &str) {
+        // Once a spread element is encountered, the elements before it are gathered into an
+        // accumulator object, which every subsequent element is then appended to.
+        if self.match_token(TokenKind::DotDotDot) {
+            if elements.has_spread {
+                self.flush_list(elements);
+            } else {
+                self.emit_bytes([accumulator as u8, elements.count as u8]);
+            }
+            elements.count = 0;
+            elements.has_spread = true;
+            self.expression();
+            self.emit_byte(OpCode::Extend as u8);
+            return;
+        }
+
+        self.expression();
+        if elements.count == 255 {
+            self.error(count_msg);
+        }
+        elements.count += 1;
+    }
+
+    fn flush_list(&mut self, elements: &ElementList) {
+        if elements.count > 0 {
+            self.emit_bytes([OpCode::BuildTuple as u8, elements.count as u8]);
+            self.emit_byte(OpCode::Extend as u8);
+        }
+    }
+
+    fn finish_list(&mut self, elements: &ElementList) -> Option<u8> {
+        if elements.has_spread {
+            self.flush_list(elements);
+            None
+        } else {
+            Some(elements.count as u8)
+        }
     }
 
     fn parameter_list(&mut self, right_delim: TokenKind, count_msg: &str, param_msg: &str) {
@@ -1291,9 +2040,32 @@ impl<'a> Parser<'a> {
                     self.error_at_current(count_msg);
                 }
 
+                let is_variadic = self.match_token(TokenKind::DotDotDot);
                 let param_constant = self.parse_variable(param_msg);
+                if is_variadic {
+                    self.compiler_mut().function.is_variadic = true;
+                } else {
+                    let name = self.previous.source.clone();
+                    let name = self.new_gc_obj_string(name.as_str());
+                    self.compiler_mut().function.parameters.push(name);
+                }
                 self.define_variable(param_constant);
 
+                if is_variadic {
+                    if self.match_token(TokenKind::Equal) {
+                        self.error("Variadic parameter cannot have a default value.");
+                        self.default_value(right_delim);
+                    }
+                    if self.check(TokenKind::Comma) {
+                        self.error_at_current("Variadic parameter must be the last parameter.");
+                    }
+                } else if self.match_token(TokenKind::Equal) {
+                    self.default_value(right_delim);
+                    self.compiler_mut().function.default_count += 1;
+                } else if self.compiler().function.default_count > 0 {
+                    self.error("Cannot have a parameter without a default value after one with a default value.");
+                }
+
                 if !self.match_token(TokenKind::Comma) {
                     break;
                 }
@@ -1301,6 +2073,70 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn default_value(&mut self, right_delim: TokenKind) {
+        // Default values are evaluated once, when the enclosing function creates the closure, so
+        // they're compiled into the enclosing function's chunk.
+        let compiler = self.compilers.pop().expect("Compiler stack empty.");
+        if right_delim == TokenKind::Bar {
+            self.parse_precedence(Precedence::BitwiseXor);
+        } else {
+            self.expression();
+        }
+        self.compilers.push(compiler);
+    }
+
+    fn call_argument_list(&mut self) -> (u8, u8, bool) {
+        let mut positional = ElementList::default();
+        let mut keywords: Vec<String> = Vec::new();
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if self.check(TokenKind::Identifier) && self.check_next(TokenKind::Colon) {
+                    if keywords.is_empty() && positional.has_spread {
+                        self.flush_list(&positional);
+                    }
+                    self.advance();
+                    let name = self.previous.clone();
+                    if keywords.contains(&name.source) {
+                        self.error("Duplicate keyword argument.");
+                    }
+                    let constant = self.identifier_constant(&name);
+                    self.emit_constant_op(OpCode::Constant, constant);
+                    self.advance();
+                    self.expression();
+                    if keywords.len() == 255 {
+                        self.error("Cannot have more than 255 keyword arguments.");
+                    }
+                    keywords.push(name.source);
+                } else {
+                    if !keywords.is_empty() {
+                        self.error_at_current(
+                            "Cannot have positional arguments after keyword arguments.",
+                        );
+                    }
+                    self.list_element(
+                        &mut positional,
+                        OpCode::BuildVec,
+                        "Cannot have more than 255 arguments.",
+                    );
+                }
+
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightParen, "Expected ')' after arguments.");
+        if keywords.is_empty() && positional.has_spread {
+            self.flush_list(&positional);
+        }
+        (
+            positional.count as u8,
+            keywords.len() as u8,
+            positional.has_spread,
+        )
+    }
+
     fn get_rule(&self, kind: TokenKind) -> &ParseRule {
         &RULES[kind as usize]
     }
@@ -1487,28 +2323,30 @@ impl<'a> Parser<'a> {
 
     fn grouping(s: &mut Parser, _can_assign: bool) {
         let mut single_elem_tuple = false;
-        let mut num_elems: usize = 0;
+        let mut elements = ElementList::default();
         if !s.check(TokenKind::RightParen) {
             loop {
-                s.expression();
-                if num_elems == 255 {
-                    s.error("Cannot have more than 255 Tuple elements.");
-                }
-                num_elems += 1;
+                s.list_element(
+                    &mut elements,
+                    OpCode::BuildTuple,
+                    "Cannot have more than 255 Tuple elements.",
+                );
 
                 if !s.match_token(TokenKind::Comma) {
                     break;
                 }
-                if num_elems == 1 && s.check(TokenKind::RightParen) {
+                if elements.count == 1 && !elements.has_spread && s.check(TokenKind::RightParen) {
                     single_elem_tuple = true;
                     break;
                 }
             }
         }
 
-        let is_tuple = num_elems != 1 || single_elem_tuple;
+        let is_tuple = elements.count != 1 || single_elem_tuple || elements.has_spread;
         if is_tuple {
-            s.emit_bytes([OpCode::BuildTuple as u8, num_elems as u8]);
+            if let Some(num_elems) = s.finish_list(&elements) {
+                s.emit_bytes([OpCode::BuildTuple as u8, num_elems]);
+            }
         }
 
         let msg = &format!(
@@ -1545,16 +2383,23 @@ impl<'a> Parser<'a> {
     }
 
     fn call(s: &mut Parser, _can_assign: bool) {
-        let arg_count = s.argument_list(
-            TokenKind::RightParen,
-            "Cannot have more than 255 arguments.",
-            "Expected ')' after arguments.",
-        );
-        s.emit_bytes([OpCode::Call as u8, arg_count]);
+        let (arg_count, kwarg_count, has_spread) = s.call_argument_list();
+        if has_spread {
+            s.emit_bytes([OpCode::CallSpread as u8, kwarg_count]);
+        } else if kwarg_count == 0 {
+            s.emit_bytes([OpCode::Call as u8, arg_count]);
+        } else {
+            s.emit_bytes([OpCode::CallKw as u8, arg_count]);
+            s.emit_byte(kwarg_count);
+        }
     }
 
     fn dot(s: &mut Parser, can_assign: bool) {
-        s.consume(TokenKind::Identifier, "Expected property name after '.'.");
+        // `yield` and `try` are keywords, but are still needed as property names for
+        // `Fiber.yield` and `Fiber.try`.
+        if !s.match_token(TokenKind::Yield) && !s.match_token(TokenKind::Try) {
+            s.consume(TokenKind::Identifier, "Expected property name after '.'.");
+        }
         let previous = s.previous.clone();
         let name = s.identifier_constant(&previous);
 
@@ -1566,13 +2411,17 @@ impl<'a> Parser<'a> {
             s.binary_assign(OpCode::GetProperty, name);
             s.emit_constant_op(OpCode::SetProperty, name);
         } else if s.match_token(TokenKind::LeftParen) {
-            let arg_count = s.argument_list(
-                TokenKind::RightParen,
-                "Cannot have more than 255 arguments.",
-                "Expected ')' after arguments.",
-            );
-            s.emit_constant_op(OpCode::Invoke, name);
-            s.emit_byte(arg_count);
+            let (arg_count, kwarg_count, has_spread) = s.call_argument_list();
+            if has_spread {
+                s.emit_constant_op(OpCode::InvokeSpread, name);
+                s.emit_byte(kwarg_count);
+            } else if kwarg_count == 0 {
+                s.emit_constant_op(OpCode::Invoke, name);
+                s.emit_byte(arg_count);
+            } else {
+                s.emit_constant_op(OpCode::InvokeKw, name);
+                s.emit_bytes([arg_count, kwarg_count]);
+            }
         } else {
             s.emit_constant_op(OpCode::GetProperty, name);
         }
@@ -1600,8 +2449,7 @@ impl<'a> Parser<'a> {
     fn lambda(s: &mut Parser, _can_assign: bool) {
         let lambda_count = s.compiler().lambda_count;
         s.compiler_mut().lambda_count += 1;
-        let name =
-            s.vm.new_gc_obj_string(format!("lambda-{}", lambda_count).as_str());
+        let name = s.new_gc_obj_string(format!("lambda-{}", lambda_count).as_str());
         s.new_compiler(FunctionKind::Function, name, s.module_path);
         s.begin_scope();
 
@@ -1657,12 +2505,23 @@ impl<'a> Parser<'a> {
 
     fn vector(s: &mut Parser, _can_assign: bool) {
         let num_elems = s.argument_list(
+            OpCode::BuildVec,
             TokenKind::RightBracket,
             "Cannot have more than 255 Vec elements.",
             "Expected ']' after elements.",
         );
 
-        s.emit_bytes([OpCode::BuildVec as u8, num_elems as u8]);
+        if let Some(num_elems) = num_elems {
+            s.emit_bytes([OpCode::BuildVec as u8, num_elems]);
+        }
+    }
+
+    fn await_(s: &mut Parser, _can_assign: bool) {
+        if !s.compiler().function.is_async {
+            s.error("Cannot use 'await' outside an async function.");
+        }
+        s.parse_precedence(Precedence::Unary);
+        s.emit_byte(OpCode::Await as u8);
     }
 
     fn unary(s: &mut Parser, _can_assign: bool) {
@@ -1704,7 +2563,8 @@ impl<'a> Parser<'a> {
     }
 
     fn string(s: &mut Parser, _can_assign: bool) {
-        let value = Value::ObjString(s.vm.new_gc_obj_string(&s.previous.source));
+        let source = s.previous.source.clone();
+        let value = Value::ObjString(s.new_gc_obj_string(&source));
         s.emit_constant(value);
     }
 
@@ -1712,7 +2572,8 @@ impl<'a> Parser<'a> {
         let mut arg_count = 0;
         loop {
             if !s.previous.source.is_empty() {
-                let value = Value::ObjString(s.vm.new_gc_obj_string(&s.previous.source));
+                let source = s.previous.source.clone();
+                let value = Value::ObjString(s.new_gc_obj_string(&source));
                 s.emit_constant(value);
                 arg_count += 1;
             }
@@ -1726,7 +2587,8 @@ impl<'a> Parser<'a> {
 
         s.advance();
         if !s.previous.source.is_empty() {
-            let value = Value::ObjString(s.vm.new_gc_obj_string(s.previous.source.as_str()));
+            let source = s.previous.source.clone();
+            let value = Value::ObjString(s.new_gc_obj_string(&source));
             s.emit_constant(value);
             arg_count += 1;
         }
@@ -1775,14 +2637,18 @@ impl<'a> Parser<'a> {
         let instance_local_name = s.compiler().locals[0].name.clone();
         s.named_variable(Token::from_string(instance_local_name.as_str()), false);
         if s.match_token(TokenKind::LeftParen) {
-            let arg_count = s.argument_list(
-                TokenKind::RightParen,
-                "Cannot have more than 255 arguments.",
-                "Expected ')' after arguments.",
-            );
+            let (arg_count, kwarg_count, has_spread) = s.call_argument_list();
             s.named_variable(Token::from_string("super"), false);
-            s.emit_constant_op(OpCode::SuperInvoke, name);
-            s.emit_byte(arg_count);
+            if has_spread {
+                s.emit_constant_op(OpCode::SuperInvokeSpread, name);
+                s.emit_byte(kwarg_count);
+            } else if kwarg_count == 0 {
+                s.emit_constant_op(OpCode::SuperInvoke, name);
+                s.emit_byte(arg_count);
+            } else {
+                s.emit_constant_op(OpCode::SuperInvokeKw, name);
+                s.emit_bytes([arg_count, kwarg_count]);
+            }
         } else {
             s.named_variable(Token::from_string("super"), false);
             s.emit_constant_op(OpCode::GetSuper, name);
@@ -1810,7 +2676,7 @@ impl<'a> Parser<'a> {
     }
 }
 
-const RULES: [ParseRule; 72] = [
+const RULES: [ParseRule; 78] = [
     // LeftParen
     ParseRule {
         prefix: Some(Parser::grouping),
@@ -1865,6 +2731,12 @@ const RULES: [ParseRule; 72] = [
         infix: Some(Parser::dotdot),
         precedence: Precedence::Range,
     },
+    // DotDotDot
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // Minus
     ParseRule {
         prefix: Some(Parser::unary),
@@ -1949,6 +2821,12 @@ const RULES: [ParseRule; 72] = [
         infix: Some(Parser::binary),
         precedence: Precedence::Equality,
     },
+    // EqualGreater
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // Greater
     ParseRule {
         prefix: None,
@@ -2159,12 +3037,30 @@ const RULES: [ParseRule; 72] = [
         infix: None,
         precedence: Precedence::None,
     },
+    // Async
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
+    // Await
+    ParseRule {
+        prefix: Some(Parser::await_),
+        infix: None,
+        precedence: Precedence::None,
+    },
     // In
     ParseRule {
         prefix: None,
         infix: None,
         precedence: Precedence::None,
     },
+    // Match
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // Nil
     ParseRule {
         prefix: Some(Parser::literal),
@@ -2231,6 +3127,12 @@ const RULES: [ParseRule; 72] = [
         infix: None,
         precedence: Precedence::None,
     },
+    // Yield
+    ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    },
     // Error
     ParseRule {
         prefix: None,
@@ -13,13 +13,19 @@
  * limitations under the License.
  */
 
+use std::cell::RefCell;
 use std::char;
+use std::mem;
+use std::thread;
 use std::time;
 
 use crate::common;
 use crate::error::{Error, ErrorKind};
-use crate::memory::{Gc, Root};
-use crate::object::{self, NativeFn, ObjClass, ObjNative, ObjStringValueMap};
+use crate::memory::{self, Gc, Root};
+use crate::object::{
+    self, ChannelWaiter, NativeFn, ObjChannel, ObjClass, ObjNative, ObjString, ObjStringValueMap,
+};
+use crate::packed_value::PackedValue;
 use crate::utils;
 use crate::value::Value;
 use crate::vm::Vm;
@@ -47,10 +53,10 @@ fn build_methods(
     let mut methods = extra_methods.unwrap_or(object::new_obj_string_value_map());
 
     for (name, native) in definitions {
-        let name = vm.new_gc_obj_string(name);
-        let obj_native = vm.new_root_obj_native(name, *native);
+        let name = vm.new_root_obj_string(name);
+        let obj_native = vm.new_root_obj_native(name.as_gc(), *native);
         roots.push(obj_native.clone());
-        methods.insert(name, Value::ObjNative(obj_native.as_gc()));
+        methods.insert(name.as_gc(), Value::ObjNative(obj_native.as_gc()));
     }
 
     (methods, roots)
@@ -73,9 +79,29 @@ pub(crate) fn clock(_vm: &mut Vm, _num_args: usize) -> Result<Value, Error> {
     Ok(Value::Number(seconds + nanos))
 }
 
+pub(crate) fn sleep_until(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 1)?;
+    let deadline = match vm.peek(0) {
+        Value::Number(n) => n,
+        value => {
+            return Err(error!(
+                ErrorKind::TypeError,
+                "Expected a number but found '{}'.", value
+            ));
+        }
+    };
+    // Deadlines are expressed in terms of the value returned by `clock`.
+    let now = clock(vm, 0)?.try_as_number().expect("Expected number.");
+    if deadline > now {
+        thread::sleep(time::Duration::from_secs_f64(deadline - now));
+    }
+    Ok(Value::None)
+}
+
 pub(crate) fn print(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     check_num_args(num_args, 1)?;
-    println!("{}", vm.peek(0));
+    let string = vm.format_value(vm.peek(0))?;
+    println!("{}", *string);
     Ok(Value::None)
 }
 
@@ -96,14 +122,14 @@ pub(crate) unsafe fn bind_type_class(_vm: &mut Vm, class: &mut Root<ObjClass>) {
     class.as_mut().methods = methods;
 }
 
-pub(crate) unsafe fn new_base_metaclass() -> Root<ObjClass> {
+pub(crate) unsafe fn new_base_metaclass(name: Gc<ObjString>) -> Root<ObjClass> {
     // # Safety
     // The root metaclass is its own metaclass, so we need to add a pointer to the metaclass to the
     // class's data. To do this we allocate the object and mutate it whilst an immutable reference
     // is held by a local `Root` instance. This is safe because the `Root` instance doesn't access
     // any fields on the pointer it holds whilst the metaclass assignment is being performed.
     let data = ObjClass {
-        name: Gc::dangling(),
+        name,
         metaclass: Gc::dangling(),
         superclass: None,
         methods: object::new_obj_string_value_map(),
@@ -119,7 +145,6 @@ pub(crate) unsafe fn new_base_metaclass() -> Root<ObjClass> {
 pub(crate) fn object_derives(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     check_num_args(num_args, 1)?;
 
-    let receiver_class = vm.get_class(vm.peek(1));
     let query_class = vm.peek(0).try_as_obj_class().ok_or_else(|| {
         error!(
             ErrorKind::ValueError,
@@ -128,17 +153,7 @@ pub(crate) fn object_derives(vm: &mut Vm, num_args: usize) -> Result<Value, Erro
         )
     })?;
 
-    if receiver_class == query_class {
-        return Ok(Value::Boolean(true));
-    }
-    let mut superclass = receiver_class.superclass;
-    while let Some(parent) = superclass {
-        if parent == query_class {
-            return Ok(Value::Boolean(true));
-        }
-        superclass = parent.superclass;
-    }
-    Ok(Value::Boolean(false))
+    Ok(Value::Boolean(vm.value_derives(vm.peek(1), query_class)))
 }
 
 pub(crate) unsafe fn bind_object_class(vm: &mut Vm, class: &mut Root<ObjClass>) {
@@ -326,9 +341,7 @@ fn string_from_code_points(vm: &mut Vm, num_args: usize) -> Result<Value, Error>
 fn string_from(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     check_num_args(num_args, 1)?;
 
-    Ok(Value::ObjString(
-        vm.new_gc_obj_string(format!("{}", vm.peek(0)).as_str()),
-    ))
+    Ok(Value::ObjString(vm.format_value(vm.peek(0))?))
 }
 
 fn string_get_item(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -479,6 +492,10 @@ fn string_replace(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
             &format!("Expected a string but found '{}'.", vm.peek(0)),
         )
     })?;
+    if new.len() > old.len() {
+        let num_matches = string.matches(old.as_str()).count();
+        memory::check_available(string.len() + num_matches * (new.len() - old.len()))?;
+    }
     let new_string = vm.new_gc_obj_string(&string.replace(old.as_str(), new.as_str()));
     Ok(Value::ObjString(new_string))
 }
@@ -502,7 +519,7 @@ fn string_split(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     let splits = vm.new_root_obj_vec();
     for substr in string.as_str().split(delim.as_str()) {
         let new_str = Value::ObjString(vm.new_gc_obj_string(substr));
-        splits.borrow_mut().elements.push(new_str);
+        splits.borrow_mut().elements.push(PackedValue::new(new_str));
     }
     Ok(Value::ObjVec(splits.as_gc()))
 }
@@ -559,7 +576,7 @@ fn string_to_bytes(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     vec.borrow_mut().elements = string
         .as_bytes()
         .iter()
-        .map(|&b| Value::Number(b as f64))
+        .map(|&b| PackedValue::new(Value::Number(b as f64)))
         .collect();
 
     Ok(Value::ObjVec(vec.as_gc()))
@@ -573,7 +590,7 @@ fn string_to_code_points(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     let vec = vm.new_root_obj_vec();
     vec.borrow_mut().elements = string
         .chars()
-        .map(|c| Value::Number((c as u32) as f64))
+        .map(|c| PackedValue::new(Value::Number((c as u32) as f64)))
         .collect();
 
     Ok(Value::ObjVec(vec.as_gc()))
@@ -605,10 +622,10 @@ pub fn new_root_obj_string_iter_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("StringIter");
+    let class_name = vm.new_root_obj_string("StringIter");
     let (methods, _native_roots) =
         build_methods(vm, &[("next", string_iter_next as NativeFn)], None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 /// Tuple implementation
@@ -618,14 +635,14 @@ pub fn new_root_obj_tuple_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("Tuple");
+    let class_name = vm.new_root_obj_string("Tuple");
     let method_map = [
         ("__getitem__", tuple_get_item as NativeFn),
         ("len", tuple_len as NativeFn),
         ("iter", tuple_iter as NativeFn),
     ];
     let (methods, _native_roots) = build_methods(vm, &method_map, None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn tuple_get_item(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -680,10 +697,10 @@ pub fn new_root_obj_tuple_iter_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("TupleIter");
+    let class_name = vm.new_root_obj_string("TupleIter");
     let (methods, _native_roots) =
         build_methods(vm, &[("next", tuple_iter_next as NativeFn)], None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn tuple_iter_next(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -706,7 +723,7 @@ pub fn new_root_obj_vec_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("Vec");
+    let class_name = vm.new_root_obj_string("Vec");
     let method_map = [
         ("push", vec_push as NativeFn),
         ("pop", vec_pop as NativeFn),
@@ -716,7 +733,7 @@ pub fn new_root_obj_vec_class(
         ("iter", vec_iter as NativeFn),
     ];
     let (methods, _native_roots) = build_methods(vm, &method_map, None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn vec_push(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -727,8 +744,9 @@ fn vec_push(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     if vec.borrow().elements.len() >= common::VEC_ELEMS_MAX {
         return Err(error!(ErrorKind::RuntimeError, "Vec max capcity reached."));
     }
+    memory::reserve(mem::size_of::<PackedValue>())?;
 
-    vec.borrow_mut().elements.push(vm.peek(0));
+    vec.borrow_mut().elements.push(PackedValue::new(vm.peek(0)));
 
     Ok(vm.peek(1))
 }
@@ -738,12 +756,16 @@ fn vec_pop(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
 
     let vec = vm.peek(0).try_as_obj_vec().expect("Expected ObjVec");
     let mut borrowed_vec = vec.borrow_mut();
-    borrowed_vec.elements.pop().ok_or_else(|| {
-        Error::with_message(
-            ErrorKind::RuntimeError,
-            "Cannot pop from empty Vec instance.",
-        )
-    })
+    borrowed_vec
+        .elements
+        .pop()
+        .map(|value| value.unpack())
+        .ok_or_else(|| {
+            Error::with_message(
+                ErrorKind::RuntimeError,
+                "Cannot pop from empty Vec instance.",
+            )
+        })
 }
 
 fn vec_get_item(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -758,7 +780,7 @@ fn vec_get_item(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
                 borrowed_vec.elements.len() as isize,
                 "Vec index parameter out of bounds.",
             )?;
-            Ok(borrowed_vec.elements[index])
+            Ok(borrowed_vec.elements[index].unpack())
         }
         Value::ObjRange(r) => {
             let vec_len = vec.borrow().elements.len() as isize;
@@ -786,7 +808,7 @@ fn vec_set_item(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         "Vec index parameter out of bounds.",
     )?;
     let mut borrowed_vec = vec.borrow_mut();
-    borrowed_vec.elements[index] = vm.peek(0);
+    borrowed_vec.elements[index] = PackedValue::new(vm.peek(0));
     Ok(Value::None)
 }
 
@@ -816,9 +838,9 @@ pub fn new_root_obj_vec_iter_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("VecIter");
+    let class_name = vm.new_root_obj_string("VecIter");
     let (methods, _native_roots) = build_methods(vm, &[("next", vec_iter_next as NativeFn)], None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn vec_iter_next(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -841,10 +863,10 @@ pub fn new_root_obj_range_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("Range");
+    let class_name = vm.new_root_obj_string("Range");
     let method_map = [("iter", range_iter as NativeFn)];
     let (methods, _native_roots) = build_methods(vm, &method_map, None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn range_iter(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -878,10 +900,10 @@ pub fn new_root_obj_range_iter_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("RangeIter");
+    let class_name = vm.new_root_obj_string("RangeIter");
     let (methods, _native_roots) =
         build_methods(vm, &[("next", range_iter_next as NativeFn)], None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 /// HashMap implementation
@@ -891,7 +913,7 @@ pub fn new_root_obj_hash_map_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("HashMap");
+    let class_name = vm.new_root_obj_string("HashMap");
     let method_map = [
         ("has_key", hash_map_has_key as NativeFn),
         ("get", hash_map_get as NativeFn),
@@ -904,7 +926,7 @@ pub fn new_root_obj_hash_map_class(
         ("items", hash_map_items as NativeFn),
     ];
     let (methods, _native_roots) = build_methods(vm, &method_map, None);
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn hash_map_has_key(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -915,10 +937,10 @@ fn hash_map_has_key(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap.");
 
-    let key = validate_hash_map_key(vm.peek(0))?;
+    let key = vm.hash_map_key(hash_map, vm.peek(0))?;
     let borrowed_hash_map = hash_map.borrow();
     Ok(Value::Boolean(
-        borrowed_hash_map.elements.contains_key(&key),
+        borrowed_hash_map.elements.contains_key(&key.value),
     ))
 }
 
@@ -930,10 +952,13 @@ fn hash_map_get(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap");
 
-    let key = validate_hash_map_key(vm.peek(0))?;
+    let key = vm.hash_map_key(hash_map, vm.peek(0))?;
 
     let borrowed_hash_map = hash_map.borrow();
-    Ok(*borrowed_hash_map.elements.get(&key).unwrap_or(&Value::None))
+    Ok(*borrowed_hash_map
+        .elements
+        .get(&key.value)
+        .unwrap_or(&Value::None))
 }
 
 fn hash_map_insert(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -944,14 +969,12 @@ fn hash_map_insert(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap");
 
-    let key = validate_hash_map_key(vm.peek(1))?;
+    let key = vm.hash_map_key(hash_map, vm.peek(1))?;
     let value = vm.peek(0);
+    memory::reserve(mem::size_of::<(Value, Value)>())?;
 
     let mut borrowed_hash_map = hash_map.borrow_mut();
-    Ok(borrowed_hash_map
-        .elements
-        .insert(key, value)
-        .unwrap_or(Value::None))
+    Ok(borrowed_hash_map.insert(key, value).unwrap_or(Value::None))
 }
 
 fn hash_map_remove(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -962,13 +985,10 @@ fn hash_map_remove(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap");
 
-    let key = validate_hash_map_key(vm.peek(0))?;
+    let key = vm.hash_map_key(hash_map, vm.peek(0))?;
 
     let mut borrowed_hash_map = hash_map.borrow_mut();
-    Ok(borrowed_hash_map
-        .elements
-        .remove(&key)
-        .unwrap_or(Value::None))
+    Ok(borrowed_hash_map.remove(key).unwrap_or(Value::None))
 }
 
 fn hash_map_clear(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -979,7 +999,7 @@ fn hash_map_clear(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap");
     let mut borrowed_hash_map = hash_map.borrow_mut();
-    borrowed_hash_map.elements.clear();
+    borrowed_hash_map.clear();
     Ok(Value::None)
 }
 
@@ -1002,7 +1022,11 @@ fn hash_map_keys(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap");
     let borrowed_hash_map = hash_map.borrow();
-    let keys: Vec<_> = borrowed_hash_map.elements.keys().map(|&v| v).collect();
+    let keys: Vec<_> = borrowed_hash_map
+        .elements
+        .keys()
+        .map(|&v| PackedValue::new(v))
+        .collect();
     let obj_keys = vm.new_root_obj_vec();
     obj_keys.borrow_mut().elements = keys;
     Ok(Value::ObjVec(obj_keys.as_gc()))
@@ -1016,7 +1040,11 @@ fn hash_map_values(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .try_as_obj_hash_map()
         .expect("Expected ObjHashMap");
     let borrowed_hash_map = hash_map.borrow();
-    let values: Vec<_> = borrowed_hash_map.elements.values().map(|&v| v).collect();
+    let values: Vec<_> = borrowed_hash_map
+        .elements
+        .values()
+        .map(|&v| PackedValue::new(v))
+        .collect();
     let obj_values = vm.new_root_obj_vec();
     obj_values.borrow_mut().elements = values;
     Ok(Value::ObjVec(obj_values.as_gc()))
@@ -1037,23 +1065,13 @@ fn hash_map_items(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         .collect();
     let vec_elements = root_obj_pairs
         .iter()
-        .map(|o| Value::ObjTuple(o.as_gc()))
+        .map(|o| PackedValue::new(Value::ObjTuple(o.as_gc())))
         .collect();
     let obj_items = vm.new_root_obj_vec();
     obj_items.borrow_mut().elements = vec_elements;
     Ok(Value::ObjVec(obj_items.as_gc()))
 }
 
-fn validate_hash_map_key(key: Value) -> Result<Value, Error> {
-    if !key.has_hash() {
-        return Err(error!(
-            ErrorKind::ValueError,
-            "Cannot use unhashable value '{}' as HashMap key.", key
-        ));
-    }
-    Ok(key)
-}
-
 /// Module implementation
 
 pub fn new_root_obj_module_class(
@@ -1061,9 +1079,9 @@ pub fn new_root_obj_module_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("Module");
+    let class_name = vm.new_root_obj_string("Module");
     vm.new_root_obj_class(
-        class_name,
+        class_name.as_gc(),
         metaclass,
         Some(superclass),
         object::new_obj_string_value_map(),
@@ -1077,16 +1095,17 @@ pub fn new_root_obj_fiber_metaclass(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("FiberClass");
+    let class_name = vm.new_root_obj_string("FiberClass");
     let (methods, _native_roots) = build_methods(
         vm,
         &[
             ("new", fiber_init as NativeFn),
             ("yield", fiber_yield as NativeFn),
+            ("abort", fiber_abort as NativeFn),
         ],
         None,
     );
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 pub fn new_root_obj_fiber_class(
@@ -1094,16 +1113,17 @@ pub fn new_root_obj_fiber_class(
     metaclass: Gc<ObjClass>,
     superclass: Gc<ObjClass>,
 ) -> Root<ObjClass> {
-    let class_name = vm.new_gc_obj_string("Fiber");
+    let class_name = vm.new_root_obj_string("Fiber");
     let (methods, _native_roots) = build_methods(
         vm,
         &[
             ("call", fiber_call as NativeFn),
+            ("try", fiber_try as NativeFn),
             ("has_finished", fiber_has_finished as NativeFn),
         ],
         None,
     );
-    vm.new_root_obj_class(class_name, metaclass, Some(superclass), methods)
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
 }
 
 fn fiber_init(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -1126,13 +1146,16 @@ fn fiber_init(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
 }
 
 fn fiber_call(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
-    // `Fiber.call` and `Fiber.yield` are the only two functions that take a
-    // variable number of arguments. To make handling call frames a little
-    // easier, we inject a dummy nil argument where none is provided. (The same
-    // approach is taken in `Fiber.yield`.) Also, because `Vm::call_native`
-    // truncates the stack based on the number of arguments after each call, we
-    // add a nil parameter after the closure has been pushed to account for the
-    // fact that the stacks before and after the call will be different.
+    resume_fiber(vm, num_args, false)
+}
+
+fn fiber_try(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    // Any exception that isn't handled within the fiber is returned instead of being rethrown.
+    resume_fiber(vm, num_args, true)
+}
+
+fn resume_fiber(vm: &mut Vm, num_args: usize, is_try: bool) -> Result<Value, Error> {
+    // The result of the call is written to the caller's stack when the fiber yields or returns.
     let fiber = vm
         .peek(num_args)
         .try_as_obj_fiber()
@@ -1152,15 +1175,12 @@ fn fiber_call(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
         }
     }
     let arg = if num_args == 1 {
-        let value = vm.peek(0);
-        Some(value)
+        Some(vm.peek(0))
     } else {
-        vm.push(Value::None);
         None
     };
-    vm.load_fiber(fiber, arg)?;
-
-    Ok(vm.peek(0))
+    vm.load_fiber(fiber, arg, is_try)?;
+    Ok(Value::None)
 }
 
 fn fiber_yield(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -1170,16 +1190,13 @@ fn fiber_yield(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
             "Expected at most 1 parameter but found {}.", num_args
         ));
     }
-    let arg = if num_args == 1 {
-        Some(vm.peek(0))
+    let value = if num_args == 1 {
+        vm.peek(0)
     } else {
-        None
+        Value::None
     };
-    if num_args == 1 {
-        vm.pop();
-    }
-    vm.unload_fiber(arg)?;
-    Ok(vm.peek(0))
+    vm.unload_fiber(value)?;
+    Ok(Value::None)
 }
 
 fn fiber_has_finished(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
@@ -1188,3 +1205,273 @@ fn fiber_has_finished(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
     let has_finished = fiber.borrow().has_finished();
     Ok(Value::Boolean(has_finished))
 }
+
+fn fiber_abort(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 1)?;
+    Err(vm.abort_fiber(vm.peek(0)))
+}
+
+/// Channel implementation
+
+pub fn new_root_obj_channel_metaclass(
+    vm: &mut Vm,
+    metaclass: Gc<ObjClass>,
+    superclass: Gc<ObjClass>,
+) -> Root<ObjClass> {
+    let class_name = vm.new_root_obj_string("ChannelClass");
+    let (methods, _native_roots) = build_methods(vm, &[("new", channel_init as NativeFn)], None);
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
+}
+
+pub fn new_root_obj_channel_class(
+    vm: &mut Vm,
+    metaclass: Gc<ObjClass>,
+    superclass: Gc<ObjClass>,
+) -> Root<ObjClass> {
+    let class_name = vm.new_root_obj_string("Channel");
+    let (methods, _native_roots) = build_methods(
+        vm,
+        &[
+            ("send", channel_send as NativeFn),
+            ("receive", channel_receive as NativeFn),
+            ("close", channel_close as NativeFn),
+            ("is_closed", channel_is_closed as NativeFn),
+            ("len", channel_len as NativeFn),
+            ("iter", channel_iter as NativeFn),
+            ("next", channel_receive as NativeFn),
+        ],
+        None,
+    );
+    vm.new_root_obj_class(class_name.as_gc(), metaclass, Some(superclass), methods)
+}
+
+fn channel_init(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    if num_args > 1 {
+        return Err(error!(
+            ErrorKind::TypeError,
+            "Expected at most 1 parameter but found {}.", num_args
+        ));
+    }
+    // Channels are unbounded unless a capacity is provided. A capacity of zero means every send
+    // blocks until a receiver takes the value.
+    let capacity = if num_args == 1 && !matches!(vm.peek(0), Value::None) {
+        let capacity = utils::validate_integer(vm.peek(0))?;
+        if capacity < 0 {
+            return Err(error!(
+                ErrorKind::ValueError,
+                "Expected a non-negative channel capacity but found {}.", capacity
+            ));
+        }
+        Some(capacity as usize)
+    } else {
+        None
+    };
+    let channel = vm.new_root_obj_channel(capacity);
+    Ok(Value::ObjChannel(channel.as_gc()))
+}
+
+fn channel_send(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 1)?;
+    let channel = vm
+        .peek(1)
+        .try_as_obj_channel()
+        .expect("Expected ObjChannel.");
+    let value = vm.peek(0);
+    if channel.borrow().closed {
+        return Err(error!(
+            ErrorKind::RuntimeError,
+            "Cannot send on a closed channel."
+        ));
+    }
+    let receiver = channel.borrow_mut().pop_receiver();
+    if let Some(receiver) = receiver {
+        wake_channel_waiter(vm, receiver, value)?;
+        return Ok(Value::None);
+    }
+    if channel.borrow().has_space() {
+        memory::reserve(mem::size_of::<Value>())?;
+        channel.borrow_mut().buffer.push_back(value);
+        return Ok(Value::None);
+    }
+    block_on_channel(vm, channel, Some(value))
+}
+
+fn channel_receive(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+    let channel = vm
+        .peek(0)
+        .try_as_obj_channel()
+        .expect("Expected ObjChannel.");
+    // Moving a blocked sender's value into the buffer before taking the value at the front keeps
+    // messages in order and also covers channels with no capacity.
+    let sender = channel.borrow_mut().pop_sender();
+    if let Some(sender) = sender {
+        channel.borrow_mut().buffer.push_back(sender.value);
+        wake_channel_waiter(vm, sender, Value::None)?;
+    }
+    let value = channel.borrow_mut().buffer.pop_front();
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    if channel.borrow().closed {
+        return Ok(Value::ObjInstance(vm.new_root_obj_stop_iter().as_gc()));
+    }
+    block_on_channel(vm, channel, None)
+}
+
+fn channel_close(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+    let channel = vm
+        .peek(0)
+        .try_as_obj_channel()
+        .expect("Expected ObjChannel.");
+    channel.borrow_mut().closed = true;
+    // Blocked senders are left in place so that receivers can still drain their values.
+    let stop_iter = vm.new_root_obj_stop_iter();
+    loop {
+        let receiver = channel.borrow_mut().pop_receiver();
+        match receiver {
+            Some(receiver) => {
+                wake_channel_waiter(vm, receiver, Value::ObjInstance(stop_iter.as_gc()))?
+            }
+            None => break,
+        }
+    }
+    Ok(Value::None)
+}
+
+fn channel_is_closed(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+    let channel = vm
+        .peek(0)
+        .try_as_obj_channel()
+        .expect("Expected ObjChannel.");
+    let is_closed = channel.borrow().closed;
+    Ok(Value::Boolean(is_closed))
+}
+
+fn channel_len(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+    let channel = vm
+        .peek(0)
+        .try_as_obj_channel()
+        .expect("Expected ObjChannel.");
+    let len = channel.borrow().buffer.len();
+    Ok(Value::Number(len as f64))
+}
+
+fn channel_iter(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+    Ok(vm.peek(0))
+}
+
+fn block_on_channel(
+    vm: &mut Vm,
+    channel: Gc<RefCell<ObjChannel>>,
+    value: Option<Value>,
+) -> Result<Value, Error> {
+    // The active fiber is suspended and a wait object is handed to its caller. The fiber can't be
+    // resumed until a peer acts on the channel and wakes it with the result of the blocked call.
+    let fiber = vm.suspendable_fiber().ok_or_else(|| {
+        error!(
+            ErrorKind::RuntimeError,
+            "Cannot block on a channel outside of a fiber."
+        )
+    })?;
+    let wait = vm.new_channel_wait()?;
+    let waiter = ChannelWaiter::new(fiber, wait, value.unwrap_or(Value::None));
+    fiber.borrow_mut().is_blocked = true;
+    if value.is_some() {
+        channel.borrow_mut().senders.push_back(waiter);
+    } else {
+        channel.borrow_mut().receivers.push_back(waiter);
+    }
+    vm.unload_fiber(wait)?;
+    Ok(wait)
+}
+
+fn wake_channel_waiter(vm: &mut Vm, waiter: ChannelWaiter, result: Value) -> Result<(), Error> {
+    waiter.fiber.borrow_mut().is_blocked = false;
+    let wake_string = vm.class_store.wake_name();
+    vm.invoke_method(
+        waiter.wait,
+        wake_string,
+        &[Value::ObjFiber(waiter.fiber), result],
+    )?;
+    Ok(())
+}
+
+/// Gc module implementation
+
+pub(crate) fn gc_collect(_vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+    memory::collect_garbage();
+    Ok(Value::None)
+}
+
+pub(crate) fn gc_stats(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 0)?;
+
+    let stats = memory::stats();
+    let live_objects = vm.new_root_obj_hash_map();
+    for (name, &count) in &stats.live_objects {
+        let name = vm.new_gc_obj_string(name);
+        live_objects
+            .borrow_mut()
+            .elements
+            .insert(Value::ObjString(name), Value::Number(count as f64));
+    }
+    let fields = [
+        ("collections", Value::Number(stats.collections as f64)),
+        ("heap_bytes", Value::Number(stats.heap_bytes as f64)),
+        (
+            "bytes_allocated",
+            Value::Number(stats.bytes_allocated as f64),
+        ),
+        ("bytes_freed", Value::Number(stats.bytes_freed as f64)),
+        ("live_objects", Value::ObjHashMap(live_objects.as_gc())),
+        ("last_pause", Value::Number(stats.last_pause.as_secs_f64())),
+        ("max_pause", Value::Number(stats.max_pause.as_secs_f64())),
+        (
+            "total_pause",
+            Value::Number(stats.total_pause.as_secs_f64()),
+        ),
+    ];
+    let hash_map = vm.new_root_obj_hash_map();
+    for (name, value) in fields {
+        let name = vm.new_gc_obj_string(name);
+        hash_map
+            .borrow_mut()
+            .elements
+            .insert(Value::ObjString(name), value);
+    }
+    Ok(Value::ObjHashMap(hash_map.as_gc()))
+}
+
+pub(crate) fn gc_set_threshold(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 1)?;
+
+    let bytes = utils::validate_integer(vm.peek(0))?;
+    if bytes < 0 {
+        return Err(error!(
+            ErrorKind::ValueError,
+            "Expected a non-negative integer but found '{}'.", bytes
+        ));
+    }
+    memory::set_collection_threshold(bytes as usize);
+    Ok(Value::None)
+}
+
+pub(crate) fn gc_set_growth_factor(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
+    check_num_args(num_args, 1)?;
+
+    let factor = vm.peek(0).try_as_number().ok_or_else(|| {
+        error!(
+            ErrorKind::TypeError,
+            "Expected a number but found '{}'.",
+            vm.peek(0)
+        )
+    })?;
+    memory::set_heap_growth_factor(factor)?;
+    Ok(Value::None)
+}
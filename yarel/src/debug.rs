@@ -13,7 +13,7 @@
  * limitations under the License.
  */
 
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, SequenceKind, UnpackRest};
 use crate::value::Value;
 
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
@@ -34,7 +34,13 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         print!("{:4} ", chunk.lines[offset]);
     }
 
-    let instruction = OpCode::from(chunk.code[offset]);
+    let instruction = match OpCode::from_byte(chunk.code[offset]) {
+        Some(instruction) => instruction,
+        None => {
+            println!("Unknown opcode {}", chunk.code[offset]);
+            return offset + 1;
+        }
+    };
     match instruction {
         OpCode::Constant => constant_instruction("CONSTANT", chunk, offset),
         OpCode::Nil => simple_instruction("NIL", offset),
@@ -49,7 +55,7 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         OpCode::SetGlobal => constant_instruction("SET_GLOBAL", chunk, offset),
         OpCode::GetUpvalue => byte_instruction("GET_UPVALUE", chunk, offset),
         OpCode::SetUpvalue => byte_instruction("SET_UPVALUE", chunk, offset),
-        OpCode::GetProperty => constant_instruction("GET_PROPERTY", chunk, offset),
+        OpCode::GetProperty => cached_constant_instruction("GET_PROPERTY", chunk, offset),
         OpCode::SetProperty => constant_instruction("SET_PROPERTY", chunk, offset),
         OpCode::GetClass => simple_instruction("GET_CLASS", offset),
         OpCode::GetSuper => constant_instruction("GET_SUPER", chunk, offset),
@@ -75,6 +81,36 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         OpCode::BuildTuple => byte_instruction("BUILD_TUPLE", chunk, offset),
         OpCode::FormatString => simple_instruction("FORMAT_STRING", offset),
         OpCode::BuildVec => byte_instruction("BUILD_VEC", chunk, offset),
+        OpCode::Extend => simple_instruction("EXTEND", offset),
+        OpCode::Unpack => {
+            let num_elements = chunk.code[offset + 1];
+            let rest = match UnpackRest::from(chunk.code[offset + 2]) {
+                UnpackRest::None => "",
+                UnpackRest::Tuple => " + rest tuple",
+                UnpackRest::Vec => " + rest vec",
+            };
+            println!("{:16} {:4}{}", "UNPACK", num_elements, rest);
+            offset + 3
+        }
+        OpCode::MatchSequence => {
+            let num_elements = chunk.code[offset + 1];
+            let kind = match SequenceKind::from(chunk.code[offset + 2]) {
+                SequenceKind::Tuple => "tuple",
+                SequenceKind::Vec => "vec",
+            };
+            let rest = if chunk.code[offset + 3] != 0 {
+                " + rest"
+            } else {
+                ""
+            };
+            println!(
+                "{:16} {:4} {}{}",
+                "MATCH_SEQUENCE", num_elements, kind, rest
+            );
+            offset + 4
+        }
+        OpCode::MatchRange => simple_instruction("MATCH_RANGE", offset),
+        OpCode::MatchField => constant_instruction("MATCH_FIELD", chunk, offset),
         OpCode::IterNext => simple_instruction("ITER_NEXT", offset),
         OpCode::Jump => jump_instruction("JUMP", 1, chunk, offset),
         OpCode::JumpIfFalse => jump_instruction("JUMP_IF_FALSE", 1, chunk, offset),
@@ -86,11 +122,11 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
             let start = offset;
             let offset = offset + 1;
             let try_size =
-                u16::from_ne_bytes([chunk.code[offset], chunk.code[offset + 1]]) as usize;
+                u16::from_le_bytes([chunk.code[offset], chunk.code[offset + 1]]) as usize;
             let catch_pos = start + try_size + 5;
             let offset = offset + 2;
             let catch_size =
-                u16::from_ne_bytes([chunk.code[offset], chunk.code[offset + 1]]) as usize;
+                u16::from_le_bytes([chunk.code[offset], chunk.code[offset + 1]]) as usize;
             let finally_pos = catch_pos + catch_size;
 
             println!(
@@ -102,22 +138,43 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         }
         OpCode::PopExcHandler => simple_instruction("POP_EXC_HANDLER", offset),
         OpCode::Throw => simple_instruction("THROW", offset),
+        OpCode::Rethrow => jump_instruction("RETHROW", 1, chunk, offset),
         OpCode::Call => byte_instruction("CALL", chunk, offset),
+        OpCode::CallKw => {
+            let positional = chunk.code[offset + 1];
+            let keywords = chunk.code[offset + 2];
+            println!(
+                "{:16} ({} args, {} kwargs)",
+                "CALL_KW", positional, keywords
+            );
+            offset + 3
+        }
+        OpCode::CallSpread => {
+            let keywords = chunk.code[offset + 1];
+            println!("{:16} (spread args, {} kwargs)", "CALL_SPREAD", keywords);
+            offset + 2
+        }
         OpCode::Invoke => invoke_instruction("INVOKE", chunk, offset),
+        OpCode::InvokeKw => invoke_kw_instruction("INVOKE_KW", chunk, offset),
+        OpCode::InvokeSpread => invoke_spread_instruction("INVOKE_SPREAD", chunk, offset),
         OpCode::Construct => byte_instruction("CONSTRUCT", chunk, offset),
         OpCode::SuperInvoke => invoke_instruction("SUPER_INVOKE", chunk, offset),
+        OpCode::SuperInvokeKw => invoke_kw_instruction("SUPER_INVOKE_KW", chunk, offset),
+        OpCode::SuperInvokeSpread => {
+            invoke_spread_instruction("SUPER_INVOKE_SPREAD", chunk, offset)
+        }
         OpCode::Closure => {
             let mut offset = offset + 1;
             let constant =
-                u16::from_ne_bytes([chunk.code[offset], chunk.code[offset + 1]]) as usize;
+                u16::from_le_bytes([chunk.code[offset], chunk.code[offset + 1]]) as usize;
             offset += 2;
             println!(
                 "{:16} {:4} {}",
                 "CLOSURE", constant, chunk.constants[constant]
             );
 
-            let function = match chunk.constants[constant] {
-                Value::ObjFunction(ref underlying) => underlying,
+            let function = match chunk.constants[constant].unpack() {
+                Value::ObjFunction(underlying) => underlying,
                 _ => panic!("Expected function object."),
             };
 
@@ -143,6 +200,8 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         }
         OpCode::CloseUpvalue => simple_instruction("CLOSE_UPVALUE", offset),
         OpCode::Return => simple_instruction("RETURN", offset),
+        OpCode::Yield => simple_instruction("YIELD", offset),
+        OpCode::Await => simple_instruction("AWAIT", offset),
         OpCode::DeclareClass => constant_instruction("DECLARE_CLASS", chunk, offset),
         OpCode::DefineClass => simple_instruction("DEFINE_CLASS", offset),
         OpCode::Inherit => simple_instruction("INHERIT", offset),
@@ -150,6 +209,17 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         OpCode::StaticMethod => constant_instruction("STATIC_METHOD", chunk, offset),
         OpCode::StartImport => constant_instruction("START_IMPORT", chunk, offset),
         OpCode::FinishImport => simple_instruction("FINISH_IMPORT", offset),
+        OpCode::GetLocals => two_byte_instruction("GET_LOCALS", chunk, offset),
+        OpCode::GetLocalConstant => {
+            let slot = chunk.code[offset + 1];
+            let constant = u16::from_le_bytes([chunk.code[offset + 2], chunk.code[offset + 3]]);
+            println!(
+                "{:16} {:4} {:4} '{}'",
+                "GET_LOCAL_CONSTANT", slot, constant, chunk.constants[constant as usize]
+            );
+            offset + 4
+        }
+        OpCode::AddLocals => two_byte_instruction("ADD_LOCALS", chunk, offset),
     }
 }
 
@@ -164,15 +234,21 @@ fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     offset + 2
 }
 
+fn two_byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (first, second) = (chunk.code[offset + 1], chunk.code[offset + 2]);
+    println!("{:16} {:4} {:4}", name, first, second);
+    offset + 3
+}
+
 fn jump_instruction(name: &str, sign: i32, chunk: &Chunk, offset: usize) -> usize {
-    let jump = u16::from_ne_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    let jump = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
     let target = (offset + 3) as isize + sign as isize * jump as isize;
     println!("{:16} {:4} -> {}", name, offset, target);
     offset + 3
 }
 
 fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = u16::from_ne_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    let constant = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
     println!(
         "{:16} {:4} '{}'",
         name, constant, chunk.constants[constant as usize]
@@ -180,12 +256,44 @@ fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     offset + 3
 }
 
+fn cached_constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    let cache = u16::from_le_bytes([chunk.code[offset + 3], chunk.code[offset + 4]]);
+    println!(
+        "{:16} {:4} '{}' (cache {})",
+        name, constant, chunk.constants[constant as usize], cache
+    );
+    offset + 5
+}
+
 fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = u16::from_ne_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
-    let arg_count = chunk.code[offset + 3];
+    let constant = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    let cache = u16::from_le_bytes([chunk.code[offset + 3], chunk.code[offset + 4]]);
+    let arg_count = chunk.code[offset + 5];
     println!(
-        "{:16} ({} args) {:4} '{}'",
-        name, arg_count, constant, chunk.constants[constant as usize]
+        "{:16} ({} args) {:4} '{}' (cache {})",
+        name, arg_count, constant, chunk.constants[constant as usize], cache
+    );
+    offset + 6
+}
+
+fn invoke_spread_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    let keywords = chunk.code[offset + 3];
+    println!(
+        "{:16} (spread args, {} kwargs) {:4} '{}'",
+        name, keywords, constant, chunk.constants[constant as usize]
     );
     offset + 4
 }
+
+fn invoke_kw_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+    let positional = chunk.code[offset + 3];
+    let keywords = chunk.code[offset + 4];
+    println!(
+        "{:16} ({} args, {} kwargs) {:4} '{}'",
+        name, positional, keywords, constant, chunk.constants[constant as usize]
+    );
+    offset + 5
+}
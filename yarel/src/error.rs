@@ -21,6 +21,8 @@ pub enum ErrorKind {
     CompileError,
     ImportError,
     IndexError,
+    InterruptError,
+    MemoryError,
     NameError,
     RuntimeError,
     TypeError,
@@ -24,7 +24,10 @@ mod debug;
 mod hash;
 pub mod memory;
 pub mod object;
+pub mod optimizer;
+pub mod packed_value;
 mod scanner;
+pub mod serialize;
 mod stack;
 mod utils;
 pub mod value;
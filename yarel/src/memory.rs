@@ -13,22 +13,41 @@
  * limitations under the License.
  */
 
+//! The garbage-collected heap. Each thread has a single heap, which is shared by every `Vm` on
+//! that thread, so the collector, its settings, its statistics and the memory limit all apply to
+//! every one of those VMs.
+
 // The code below is in part inspired by the mark-and-sweep GC implemented here:
 // https://github.com/Darksecond/lox
 
 use std::any;
-use std::cell::{Cell, RefCell};
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::HashMap;
 use std::marker::PhantomPinned;
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::ptr::NonNull;
+use std::rc::Weak;
+use std::time::{Duration, Instant};
 
 use crate::common;
+use crate::error::{Error, ErrorKind};
+use crate::packed_value::PackedValue;
 
 thread_local! {
     static HEAP: RefCell<Heap> = RefCell::new(Heap::new());
+    // Objects that have been marked but whose references haven't been traced yet. This is kept
+    // apart from the heap because objects are marked while the heap is busy collecting, and by
+    // write barriers while the program runs.
+    static GREY_OBJECTS: RefCell<Vec<DynGcBoxPtr>> = RefCell::new(Vec::new());
+    // Every object with at least one `Root` or `UniqueRoot`, so that marking the roots doesn't
+    // need to visit the rest of the heap. Each object records its position in this list, so that
+    // it can be removed in constant time.
+    static ROOTS: RefCell<Vec<DynGcBoxPtr>> = RefCell::new(Vec::new());
+    // Whether an incremental collection is part way through marking, in which case mutations
+    // need to be reported to the collector.
+    static MARKING: Cell<bool> = const { Cell::new(false) };
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -38,56 +57,242 @@ enum Colour {
     White,
 }
 
+/// The strategy used to find and free unreachable objects.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Collector {
+    /// Each collection marks and sweeps the entire heap before the program continues.
+    #[default]
+    StopTheWorld,
+    /// Collections are interleaved with the program, with each allocation marking or sweeping a
+    /// bounded number of objects. The first and last steps of marking also visit every root.
+    Incremental,
+}
+
 pub trait GcManaged {
+    /// Marks every object referenced by this one, so that they survive the current collection.
     fn mark(&self);
 
-    fn blacken(&self);
+    /// The number of bytes owned by the object outside of its GC allocation, such as the
+    /// contents of a string or vector. This is included when enforcing the memory limit.
+    fn heap_size(&self) -> usize {
+        0
+    }
+
+    /// The name of the object's type, used to group objects in `GcStats`.
+    fn type_name(&self) -> &'static str {
+        any::type_name::<Self>()
+    }
+}
+
+/// A table that refers to objects without keeping them alive, such as the table of interned
+/// strings. Entries for objects that haven't been marked are removed when marking finishes, before
+/// any of those objects are freed.
+pub(crate) trait WeakTable {
+    fn remove_unmarked(&mut self);
+}
+
+/// A summary of the collector's activity since the heap was created.
+#[derive(Clone, Debug, Default)]
+pub struct GcStats {
+    /// The number of collections that have been completed.
+    pub collections: usize,
+    /// The number of bytes currently held by the heap.
+    pub heap_bytes: usize,
+    /// The total number of bytes that have been allocated.
+    pub bytes_allocated: usize,
+    /// The total number of bytes that have been freed.
+    pub bytes_freed: usize,
+    /// The number of objects on the heap, keyed by the name of their type. Objects that a sweep in
+    /// progress has found to be unreachable aren't counted.
+    pub live_objects: HashMap<String, usize>,
+    /// The time spent in the most recent pause for garbage collection. For the incremental
+    /// collector each step is a separate pause.
+    pub last_pause: Duration,
+    /// The longest time spent in a single pause for garbage collection.
+    pub max_pause: Duration,
+    /// The total time spent collecting garbage.
+    pub total_pause: Duration,
+    /// The most objects visited by a single step of the incremental collector, counting the roots
+    /// scanned as well as the objects traced or swept.
+    pub max_step_objects: usize,
+}
+
+/// Sets the maximum number of bytes the heap may hold after a collection.
+pub fn set_memory_limit(limit: Option<usize>) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.memory_limit = limit;
+        heap.limit_exceeded = false;
+    })
+}
+
+/// Sets the strategy used to collect garbage, completing any collection that's in progress
+/// first.
+pub fn set_collector(collector: Collector) {
+    HEAP.with(|heap| heap.borrow_mut().set_collector(collector))
+}
+
+/// Sets the number of bytes the heap may grow to before the next collection starts. After each
+/// collection the threshold is set using the growth factor.
+pub fn set_collection_threshold(bytes: usize) {
+    HEAP.with(|heap| heap.borrow_mut().collection_threshold = bytes)
+}
+
+/// Sets how far the heap may grow after a collection before the next one starts, as a multiple of
+/// the size of the live heap. Fails if the factor is less than one.
+pub fn set_heap_growth_factor(factor: f64) -> Result<(), Error> {
+    if !(factor >= 1.0 && factor.is_finite()) {
+        return Err(error!(
+            ErrorKind::ValueError,
+            "Expected a growth factor of at least 1 but found '{}'.", factor
+        ));
+    }
+    HEAP.with(|heap| heap.borrow_mut().growth_factor = factor);
+    Ok(())
+}
+
+/// Performs a complete collection, finishing any incremental collection that's in progress.
+pub fn collect_garbage() {
+    HEAP.with(|heap| heap.borrow_mut().collect())
+}
+
+/// Takes a snapshot of the collector's statistics.
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| heap.borrow().stats())
+}
+
+/// Registers a table to be pruned after each collection has marked every reachable object. The
+/// table is forgotten once it's dropped.
+pub(crate) fn register_weak_table(table: Weak<RefCell<dyn WeakTable>>) {
+    HEAP.with(|heap| heap.borrow_mut().weak_tables.push(table))
+}
+
+/// Accounts for growth of an existing object, collecting garbage if required. Fails if the live
+/// heap would exceed the memory limit.
+pub(crate) fn reserve(bytes: usize) -> Result<(), Error> {
+    HEAP.with(|heap| heap.borrow_mut().reserve(bytes))
+}
+
+/// Checks that a large allocation that's about to be made won't take the live heap over the
+/// memory limit, collecting garbage if required. Unlike `reserve`, the bytes aren't accounted for,
+/// as the allocation itself does that.
+pub(crate) fn check_available(bytes: usize) -> Result<(), Error> {
+    HEAP.with(|heap| heap.borrow_mut().check_available(bytes))
+}
+
+/// Checks whether an allocation has left the live heap over the memory limit since the last
+/// check. Allocations can't fail, so the VM checks this periodically and raises a MemoryError.
+pub(crate) fn take_limit_exceeded() -> bool {
+    HEAP.with(|heap| heap.borrow_mut().take_limit_exceeded())
+}
+
+/// Reports that a reference to `value` has been stored somewhere the collector can't see being
+/// modified, such as a `Cell` or the stack of an inactive fiber. During incremental marking the
+/// value is marked, as the object it was stored in may already have been traced. Mutations made
+/// using `borrow_mut` on a `Gc` or `Root`, or through a `Root`, are handled automatically.
+pub(crate) fn write_barrier(value: &impl GcManaged) {
+    if is_marking() {
+        value.mark();
+    }
+}
+
+fn is_marking() -> bool {
+    MARKING.with(|marking| marking.get())
+}
+
+fn push_grey(ptr: DynGcBoxPtr) {
+    GREY_OBJECTS.with(|greys| greys.borrow_mut().push(ptr));
+}
+
+fn pop_grey() -> Option<DynGcBoxPtr> {
+    GREY_OBJECTS.with(|greys| greys.borrow_mut().pop())
+}
+
+fn add_root(ptr: DynGcBoxPtr) {
+    ROOTS.with(|roots| {
+        let mut roots = roots.borrow_mut();
+        unsafe { ptr.as_ref() }.root_index.set(roots.len());
+        roots.push(ptr);
+    })
+}
+
+fn remove_root(index: usize) {
+    // Roots held by objects that are freed when the thread exits may be dropped after the list
+    // itself.
+    let _ = ROOTS.try_with(|roots| {
+        let mut roots = roots.borrow_mut();
+        roots.swap_remove(index);
+        if let Some(moved) = roots.get(index) {
+            unsafe { moved.as_ref() }.root_index.set(index);
+        }
+    });
 }
 
 type GcBoxPtr<T> = NonNull<GcBox<T>>;
 
+type DynGcBoxPtr = NonNull<GcBox<dyn GcManaged>>;
+
 struct GcBox<T: GcManaged + ?Sized> {
     colour: Cell<Colour>,
     num_roots: Cell<usize>,
+    // The position of the object in ROOTS, if it's rooted.
+    root_index: Cell<usize>,
     _pin: PhantomPinned,
     pub(crate) data: T,
 }
 
-impl<T: 'static + GcManaged + ?Sized> GcBox<T> {
-    fn unmark(&self) {
-        self.colour.set(Colour::White);
-    }
-
+impl<T: 'static + GcManaged> GcBox<T> {
     fn mark(&self) {
-        if self.colour.replace(Colour::Grey) == Colour::Grey {
+        if self.colour.get() != Colour::White {
             return;
         }
         if cfg!(feature = "debug_trace_gc") {
             println!("{:?} mark", self as *const _);
         }
-        self.data.mark();
+        self.grey();
     }
 
-    fn blacken(&self) {
-        if self.colour.replace(Colour::Black) == Colour::Black {
-            return;
+    /// Ensures the object is traced again if it's mutated after being traced by an incremental
+    /// collection.
+    fn write_barrier(&self) {
+        if self.colour.get() == Colour::Black && is_marking() {
+            self.grey();
         }
-        if cfg!(feature = "debug_trace_gc") {
-            println!("{:?} blacken", self as *const _);
-        }
-        self.data.blacken();
+    }
+
+    fn grey(&self) {
+        self.colour.set(Colour::Grey);
+        push_grey(NonNull::from(self as &GcBox<dyn GcManaged>));
     }
 
     fn inc_num_roots(&self) {
-        self.num_roots.replace(self.num_roots.get() + 1);
+        let num_roots = self.num_roots.get();
+        if num_roots == 0 {
+            add_root(NonNull::from(self as &GcBox<dyn GcManaged>));
+        }
+        self.num_roots.set(num_roots + 1);
     }
 
     fn dec_num_roots(&self) {
-        self.num_roots.replace(self.num_roots.get() - 1);
+        let num_roots = self.num_roots.get() - 1;
+        self.num_roots.set(num_roots);
+        if num_roots == 0 {
+            remove_root(self.root_index.get());
+        }
     }
 }
 
-pub struct Root<T: 'static + GcManaged + ?Sized> {
+impl GcBox<dyn GcManaged> {
+    fn blacken(&self) {
+        if cfg!(feature = "debug_trace_gc") {
+            println!("{:?} blacken", self as *const _);
+        }
+        self.colour.set(Colour::Black);
+        self.data.mark();
+    }
+}
+
+pub struct Root<T: 'static + GcManaged> {
     ptr: GcBoxPtr<T>,
 }
 
@@ -103,9 +308,7 @@ impl<T: GcManaged> Root<T> {
     pub unsafe fn as_mut(&mut self) -> &mut T {
         &mut self.gc_box_mut().data
     }
-}
 
-impl<T: 'static + GcManaged + ?Sized> Root<T> {
     fn inc_num_roots(&self) {
         self.gc_box().inc_num_roots();
     }
@@ -113,9 +316,7 @@ impl<T: 'static + GcManaged + ?Sized> Root<T> {
     fn dec_num_roots(&self) {
         self.gc_box().dec_num_roots();
     }
-}
 
-impl<T: GcManaged + ?Sized> Root<T> {
     fn gc_box(&self) -> &GcBox<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -125,17 +326,20 @@ impl<T: GcManaged + ?Sized> Root<T> {
     }
 }
 
-impl<T: 'static + GcManaged + ?Sized> GcManaged for Root<T> {
-    fn mark(&self) {
-        self.gc_box().mark();
+impl<T: 'static + GcManaged> Root<RefCell<T>> {
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.gc_box().write_barrier();
+        self.gc_box().data.borrow_mut()
     }
+}
 
-    fn blacken(&self) {
-        self.gc_box().blacken();
+impl<T: 'static + GcManaged> GcManaged for Root<T> {
+    fn mark(&self) {
+        self.gc_box().mark();
     }
 }
 
-impl<T: 'static + GcManaged + ?Sized> Clone for Root<T> {
+impl<T: 'static + GcManaged> Clone for Root<T> {
     fn clone(&self) -> Root<T> {
         let ret = Root { ptr: self.ptr };
         ret.inc_num_roots();
@@ -151,9 +355,12 @@ impl<T: 'static + GcManaged> Deref for Root<T> {
     }
 }
 
-impl<T: 'static + GcManaged + ?Sized> Drop for Root<T> {
+impl<T: 'static + GcManaged> Drop for Root<T> {
     fn drop(&mut self) {
         self.dec_num_roots();
+        // Rooted objects may be modified without the collector knowing, for example through
+        // `as_mut`, so they're traced again once they're no longer rooted.
+        self.gc_box().write_barrier();
     }
 }
 
@@ -181,7 +388,7 @@ impl<T: GcManaged> From<UniqueRoot<T>> for Root<T> {
     }
 }
 
-pub struct UniqueRoot<T: 'static + GcManaged + ?Sized> {
+pub struct UniqueRoot<T: 'static + GcManaged> {
     ptr: GcBoxPtr<T>,
 }
 
@@ -189,9 +396,7 @@ impl<T: GcManaged> UniqueRoot<T> {
     pub fn new(data: T) -> UniqueRoot<T> {
         HEAP.with(|heap| heap.borrow_mut().allocate_unique(data))
     }
-}
 
-impl<T: 'static + GcManaged + ?Sized> UniqueRoot<T> {
     fn inc_num_roots(&self) {
         self.gc_box().inc_num_roots();
     }
@@ -199,9 +404,7 @@ impl<T: 'static + GcManaged + ?Sized> UniqueRoot<T> {
     fn dec_num_roots(&self) {
         self.gc_box().dec_num_roots();
     }
-}
 
-impl<T: GcManaged + ?Sized> UniqueRoot<T> {
     fn gc_box(&self) -> &GcBox<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -211,14 +414,10 @@ impl<T: GcManaged + ?Sized> UniqueRoot<T> {
     }
 }
 
-impl<T: 'static + GcManaged + ?Sized> GcManaged for UniqueRoot<T> {
+impl<T: 'static + GcManaged> GcManaged for UniqueRoot<T> {
     fn mark(&self) {
         self.gc_box().mark();
     }
-
-    fn blacken(&self) {
-        self.gc_box().blacken();
-    }
 }
 
 impl<T: 'static + GcManaged> Deref for UniqueRoot<T> {
@@ -235,12 +434,15 @@ impl<T: 'static + GcManaged> DerefMut for UniqueRoot<T> {
     }
 }
 
-impl<T: 'static + GcManaged + ?Sized> Drop for UniqueRoot<T> {
+impl<T: 'static + GcManaged> Drop for UniqueRoot<T> {
     fn drop(&mut self) {
         self.dec_num_roots();
+        self.gc_box().write_barrier();
     }
 }
 
+// NaN-boxed values rely on a `Gc` having the same layout as a pointer.
+#[cfg_attr(feature = "nan_boxing", repr(transparent))]
 pub struct Gc<T: GcManaged + ?Sized> {
     ptr: GcBoxPtr<T>,
 }
@@ -261,6 +463,12 @@ impl<T: 'static + GcManaged> Gc<T> {
     pub fn as_ptr(&self) -> *const T {
         &self.gc_box().data
     }
+
+    /// Whether the object has been marked by the current collection. This is only meaningful
+    /// while a `WeakTable` is being pruned.
+    pub(crate) fn is_marked(&self) -> bool {
+        self.gc_box().colour.get() != Colour::White
+    }
 }
 
 impl<T: 'static + GcManaged + ?Sized> Gc<T> {
@@ -269,13 +477,16 @@ impl<T: 'static + GcManaged + ?Sized> Gc<T> {
     }
 }
 
-impl<T: 'static + GcManaged + ?Sized> GcManaged for Gc<T> {
-    fn mark(&self) {
-        self.gc_box().mark();
+impl<T: 'static + GcManaged> Gc<RefCell<T>> {
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.gc_box().write_barrier();
+        self.gc_box().data.borrow_mut()
     }
+}
 
-    fn blacken(&self) {
-        self.gc_box().blacken();
+impl<T: 'static + GcManaged> GcManaged for Gc<T> {
+    fn mark(&self) {
+        self.gc_box().mark();
     }
 }
 
@@ -301,19 +512,62 @@ impl<T: GcManaged> PartialEq for Gc<T> {
     }
 }
 
+#[derive(Copy, Clone, Default, PartialEq)]
+enum Phase {
+    #[default]
+    Idle,
+    Marking,
+    Sweeping,
+}
+
+/// The heap holds every GC-managed object. Live objects are found by marking those that are
+/// rooted, then tracing references from a worklist of grey objects until it's empty. Between
+/// collections every object is white.
 #[derive(Default)]
 pub struct Heap {
+    collector: Collector,
+    phase: Phase,
     collection_threshold: usize,
+    growth_factor: f64,
     bytes_allocated: usize,
     objects: Vec<Pin<Box<GcBox<dyn GcManaged>>>>,
+    // The objects that were allocated before the current collection finished marking, which
+    // haven't been swept yet.
+    unswept: Vec<Pin<Box<GcBox<dyn GcManaged>>>>,
+    bytes_allocated_before_sweep: usize,
+    bytes_live: usize,
+    bytes_freed: usize,
+    // The number of objects visited by the collector, used to find the work done by each step.
+    objects_visited: usize,
+    memory_limit: Option<usize>,
+    limit_exceeded: bool,
+    weak_tables: Vec<Weak<RefCell<dyn WeakTable>>>,
+    // Everything except the object counts, which are found when a snapshot is taken.
+    stats: GcStats,
 }
 
 impl Heap {
     pub(crate) fn new() -> Self {
         Heap {
+            collector: if cfg!(feature = "debug_incremental_gc") {
+                Collector::Incremental
+            } else {
+                Collector::StopTheWorld
+            },
+            phase: Phase::Idle,
             collection_threshold: common::HEAP_INIT_BYTES_MAX,
+            growth_factor: common::HEAP_GROWTH_FACTOR,
             bytes_allocated: 0,
             objects: Vec::new(),
+            unswept: Vec::new(),
+            bytes_allocated_before_sweep: 0,
+            bytes_live: 0,
+            bytes_freed: 0,
+            objects_visited: 0,
+            memory_limit: None,
+            limit_exceeded: false,
+            weak_tables: Vec::new(),
+            stats: GcStats::default(),
         }
     }
 
@@ -334,24 +588,43 @@ impl Heap {
     }
 
     fn allocate_raw<T: 'static + GcManaged>(&mut self, data: T) -> GcBoxPtr<T> {
-        if cfg!(any(debug_assertions, feature = "debug_stress_gc")) {
-            self.collect();
-        } else {
-            self.collect_if_required();
+        let size = mem::size_of::<T>() + data.heap_size();
+        let stress = cfg!(any(debug_assertions, feature = "debug_stress_gc"));
+        match self.collector {
+            Collector::StopTheWorld if stress => self.collect(),
+            Collector::StopTheWorld => self.collect_if_required(),
+            Collector::Incremental => self.step(stress),
+        }
+        if self.is_over_limit(size) {
+            if !(stress && self.collector == Collector::StopTheWorld) {
+                self.collect();
+            }
+            self.limit_exceeded = self.is_over_limit(size);
         }
         let mut boxed = Box::pin(GcBox {
             colour: Cell::new(Colour::White),
             num_roots: Cell::new(0),
+            root_index: Cell::new(0),
             _pin: PhantomPinned,
             data,
         });
 
         let gc_box_ptr = unsafe { GcBoxPtr::new_unchecked(boxed.as_mut().get_unchecked_mut()) };
+        assert!(
+            PackedValue::can_hold_address(gc_box_ptr.as_ptr() as usize),
+            "Unable to box object address {:p}.",
+            gc_box_ptr
+        );
+        // Objects allocated while marking may be given references to objects that haven't been
+        // marked yet, so they're traced before the collection finishes.
+        if self.phase == Phase::Marking {
+            unsafe { gc_box_ptr.as_ref() }.grey();
+        }
 
         self.objects.push(boxed);
-        let size = mem::size_of::<T>();
 
         self.bytes_allocated += size;
+        self.stats.bytes_allocated += size;
 
         if cfg!(feature = "debug_trace_gc") {
             let new_ptr = self.objects.last().unwrap();
@@ -366,76 +639,270 @@ impl Heap {
         gc_box_ptr
     }
 
+    /// Performs a complete collection, finishing any incremental collection that's in progress
+    /// first, since objects that became unreachable after it started may not be freed by it.
     fn collect(&mut self) {
+        let start = Instant::now();
+        if self.phase != Phase::Idle {
+            self.finish_collection();
+        }
         if cfg!(feature = "debug_trace_gc") {
             println!("-- gc begin")
         }
+        self.begin_marking();
+        self.trace_references(usize::MAX);
+        self.end_marking();
+        self.sweep(usize::MAX);
+        self.record_pause(start);
+    }
 
-        self.mark_roots();
-        self.trace_references();
-        let bytes_freed = self.sweep();
+    fn collect_if_required(&mut self) {
+        if self.bytes_allocated >= self.collection_threshold {
+            self.collect();
+        }
+    }
 
-        let prev_bytes_allocated = self.bytes_allocated;
-        self.bytes_allocated -= bytes_freed;
-        self.collection_threshold = self.bytes_allocated * common::HEAP_GROWTH_FACTOR;
+    /// Advances an incremental collection by a bounded amount of work, starting a new one if the
+    /// heap has grown enough. When stressing the collector, collections are started as soon as
+    /// the previous one finishes.
+    fn step(&mut self, stress: bool) {
+        let is_due = stress || self.bytes_allocated >= self.collection_threshold;
+        if self.phase == Phase::Idle && !is_due {
+            return;
+        }
+        let start = Instant::now();
+        let objects_visited = self.objects_visited;
+        match self.phase {
+            Phase::Idle => {
+                if cfg!(feature = "debug_trace_gc") {
+                    println!("-- gc begin (incremental)")
+                }
+                self.begin_marking();
+            }
+            Phase::Marking => {
+                if self.trace_references(common::GC_STEP_OBJECTS) {
+                    self.finish_marking();
+                }
+            }
+            Phase::Sweeping => self.sweep(common::GC_STEP_OBJECTS),
+        }
+        self.record_pause(start);
+        self.stats.max_step_objects = self
+            .stats
+            .max_step_objects
+            .max(self.objects_visited - objects_visited);
+    }
+
+    fn record_pause(&mut self, start: Instant) {
+        let pause = start.elapsed();
+        self.stats.last_pause = pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+        self.stats.total_pause += pause;
+    }
+
+    fn stats(&self) -> GcStats {
+        let mut live_objects = HashMap::new();
+        // Marking has finished if there are objects waiting to be swept, so any that are still
+        // white are unreachable.
+        let marked = self
+            .unswept
+            .iter()
+            .filter(|obj| obj.colour.get() != Colour::White);
+        for obj in self.objects.iter().chain(marked) {
+            *live_objects
+                .entry(short_type_name(obj.data.type_name()))
+                .or_insert(0) += 1;
+        }
+        GcStats {
+            heap_bytes: self.bytes_allocated,
+            live_objects,
+            ..self.stats.clone()
+        }
+    }
 
-        if cfg!(feature = "debug_trace_gc") {
-            println!("-- gc end (freed {} bytes)", bytes_freed);
-            println!(
-                "   collected {} bytes (from {} to {}) next at {}",
-                bytes_freed, prev_bytes_allocated, self.bytes_allocated, self.collection_threshold,
-            )
+    /// Completes the incremental collection that's in progress.
+    fn finish_collection(&mut self) {
+        if self.phase == Phase::Marking {
+            self.finish_marking();
         }
+        self.sweep(usize::MAX);
     }
 
-    fn collect_if_required(&mut self) {
-        if self.bytes_allocated >= self.collection_threshold {
+    fn set_collector(&mut self, collector: Collector) {
+        if self.phase != Phase::Idle {
+            self.finish_collection();
+        }
+        self.collector = collector;
+    }
+
+    fn is_over_limit(&self, additional_bytes: usize) -> bool {
+        self.memory_limit
+            .is_some_and(|limit| self.bytes_allocated.saturating_add(additional_bytes) > limit)
+    }
+
+    fn take_limit_exceeded(&mut self) -> bool {
+        // The objects that took the heap over the limit may have become garbage since, in which
+        // case there's no need to report an error.
+        if !mem::take(&mut self.limit_exceeded) {
+            return false;
+        }
+        self.collect();
+        self.is_over_limit(0)
+    }
+
+    fn reserve(&mut self, bytes: usize) -> Result<(), Error> {
+        self.check_available(bytes)?;
+        self.bytes_allocated += bytes;
+        self.stats.bytes_allocated += bytes;
+        Ok(())
+    }
+
+    fn check_available(&mut self, bytes: usize) -> Result<(), Error> {
+        if self.is_over_limit(bytes) {
             self.collect();
+            if self.is_over_limit(bytes) {
+                return Err(error!(ErrorKind::MemoryError, "Memory limit exceeded."));
+            }
         }
+        Ok(())
+    }
+
+    fn begin_marking(&mut self) {
+        self.phase = Phase::Marking;
+        MARKING.with(|marking| marking.set(true));
+        self.mark_roots();
     }
 
     fn mark_roots(&mut self) {
-        self.objects.iter_mut().for_each(|obj| obj.unmark());
-        self.objects.iter_mut().for_each(|obj| {
-            if obj.num_roots.get() > 0 {
-                obj.mark();
+        ROOTS.with(|roots| {
+            let roots = roots.borrow();
+            for &root in roots.iter() {
+                let obj = unsafe { root.as_ref() };
+                if obj.colour.get() != Colour::Grey {
+                    obj.colour.set(Colour::Grey);
+                    push_grey(root);
+                }
             }
-        });
+            self.objects_visited += roots.len();
+        })
     }
 
-    fn trace_references(&mut self) {
-        let mut num_greys = self
-            .objects
-            .iter()
-            .filter(|obj| obj.colour.get() == Colour::Grey)
-            .count();
-        #[allow(clippy::suspicious_map)]
-        while num_greys > 0 {
-            num_greys = self
-                .objects
-                .iter_mut()
-                .filter(|obj| obj.colour.get() == Colour::Grey)
-                .map(|obj| obj.blacken())
-                .count();
-        }
-    }
-
-    fn sweep(&mut self) -> usize {
-        let bytes_marked: usize = self
-            .objects
-            .iter()
-            .filter(|obj| obj.colour.get() == Colour::White)
-            .map(|obj| {
+    /// Traces the references of up to `limit` grey objects, returning true if no grey objects
+    /// remain.
+    fn trace_references(&mut self, limit: usize) -> bool {
+        for _ in 0..limit {
+            match pop_grey() {
+                Some(obj) => unsafe { obj.as_ref() }.blacken(),
+                None => return true,
+            }
+            self.objects_visited += 1;
+        }
+        false
+    }
+
+    fn finish_marking(&mut self) {
+        // Rooted objects can be modified without a write barrier, such as the stack of the
+        // active fiber, so they're traced again before anything is freed.
+        self.mark_roots();
+        self.trace_references(usize::MAX);
+        self.end_marking();
+    }
+
+    fn end_marking(&mut self) {
+        MARKING.with(|marking| marking.set(false));
+        self.phase = Phase::Sweeping;
+        // Weak tables are pruned before anything is freed, so that an unreachable object can't be
+        // retrieved from one while it's waiting to be swept.
+        self.weak_tables.retain(|table| match table.upgrade() {
+            Some(table) => {
+                table.borrow_mut().remove_unmarked();
+                true
+            }
+            None => false,
+        });
+        // The vector of swept objects from the previous collection is empty, so it's reused to
+        // avoid reallocating.
+        mem::swap(&mut self.objects, &mut self.unswept);
+        self.bytes_allocated_before_sweep = self.bytes_allocated;
+        self.bytes_live = 0;
+        self.bytes_freed = 0;
+    }
+
+    /// Frees up to `limit` unreachable objects, resetting the colour of those that survive.
+    fn sweep(&mut self, limit: usize) {
+        for _ in 0..limit {
+            let obj = match self.unswept.pop() {
+                Some(obj) => obj,
+                None => break,
+            };
+            self.objects_visited += 1;
+            let size = mem::size_of_val(&obj.data) + obj.data.heap_size();
+            if obj.colour.get() == Colour::White {
                 if cfg!(feature = "debug_trace_gc") {
                     println!("{:?} free", obj.as_ref().get_ref() as *const _);
                 }
-                mem::size_of_val(&obj.data)
-            })
-            .sum();
+                self.bytes_freed += size;
+            } else {
+                obj.colour.set(Colour::White);
+                self.bytes_live += size;
+                self.objects.push(obj);
+            }
+        }
+        if self.unswept.is_empty() {
+            self.finish_sweeping();
+        }
+    }
+
+    fn finish_sweeping(&mut self) {
+        // Objects such as vectors may have grown since they were allocated, so the size of the
+        // live heap is recalculated rather than subtracting the bytes freed.
+        let prev_bytes_allocated = self.bytes_allocated;
+        self.bytes_allocated =
+            self.bytes_live + (self.bytes_allocated - self.bytes_allocated_before_sweep);
+        self.collection_threshold = (self.bytes_allocated as f64 * self.growth_factor) as usize;
+        self.phase = Phase::Idle;
+        self.stats.collections += 1;
+        self.stats.bytes_freed += self.bytes_freed;
+
+        if cfg!(feature = "debug_trace_gc") {
+            println!("-- gc end (freed {} bytes)", self.bytes_freed);
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                self.bytes_freed,
+                prev_bytes_allocated,
+                self.bytes_allocated,
+                self.collection_threshold,
+            )
+        }
+    }
+}
 
-        self.objects.retain(|obj| obj.colour.get() == Colour::Black);
+/// Removes the module paths from a type name, along with any `RefCell` wrapping the type, since
+/// it's an implementation detail. For example `core::cell::RefCell<yarel::object::ObjVec>`
+/// becomes `ObjVec`.
+fn short_type_name(name: &str) -> String {
+    let name = name
+        .strip_prefix("core::cell::RefCell<")
+        .and_then(|name| name.strip_suffix('>'))
+        .unwrap_or(name);
+    let mut short_name = String::with_capacity(name.len());
+    let mut segment_start = 0;
+    for (i, c) in name.char_indices() {
+        if c == ':' {
+            segment_start = i + 1;
+        } else if !(c.is_alphanumeric() || c == '_') {
+            short_name.push_str(&name[segment_start..=i]);
+            segment_start = i + 1;
+        }
+    }
+    short_name.push_str(&name[segment_start..]);
+    short_name
+}
 
-        bytes_marked
+impl Drop for Heap {
+    fn drop(&mut self) {
+        // Objects are about to be freed, which mustn't cause any to be marked.
+        MARKING.with(|marking| marking.set(false));
     }
 }
 
@@ -444,8 +911,8 @@ impl<T: GcManaged> GcManaged for RefCell<T> {
         self.borrow().mark();
     }
 
-    fn blacken(&self) {
-        self.borrow().blacken();
+    fn heap_size(&self) -> usize {
+        self.borrow().heap_size()
     }
 }
 
@@ -455,26 +922,15 @@ impl<T: GcManaged> GcManaged for Vec<T> {
             e.mark();
         }
     }
-
-    fn blacken(&self) {
-        for e in self {
-            e.blacken();
-        }
-    }
 }
 
-impl<K, V: GcManaged, S> GcManaged for HashMap<K, V, S> {
+impl<K: GcManaged, V: GcManaged, S> GcManaged for HashMap<K, V, S> {
     fn mark(&self) {
-        for v in self.values() {
+        for (k, v) in self {
+            k.mark();
             v.mark();
         }
     }
-
-    fn blacken(&self) {
-        for v in self.values() {
-            v.blacken();
-        }
-    }
 }
 
 impl<T: GcManaged> GcManaged for &[T] {
@@ -483,10 +939,4 @@ impl<T: GcManaged> GcManaged for &[T] {
             self[i].mark();
         }
     }
-
-    fn blacken(&self) {
-        for i in 0..self.len() {
-            self[i].blacken();
-        }
-    }
 }
@@ -15,9 +15,10 @@
 
 use std::cell::{Cell, RefCell};
 use std::cmp::{self, Eq};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::ops::Deref;
 
 use crate::chunk::Chunk;
@@ -25,6 +26,7 @@ use crate::common;
 use crate::error::{Error, ErrorKind};
 use crate::hash::{BuildPassThroughHasher, PassThroughHasher};
 use crate::memory::{self, Gc, GcManaged};
+use crate::packed_value::PackedValue;
 use crate::stack::Stack;
 use crate::value::Value;
 use crate::vm::Vm;
@@ -86,7 +88,9 @@ impl Deref for ObjString {
 impl memory::GcManaged for ObjString {
     fn mark(&self) {}
 
-    fn blacken(&self) {}
+    fn heap_size(&self) -> usize {
+        self.string.capacity()
+    }
 }
 
 pub type ObjStringValueMap = HashMap<Gc<ObjString>, Value, BuildPassThroughHasher>;
@@ -127,10 +131,6 @@ impl memory::GcManaged for ObjStringIter {
     fn mark(&self) {
         self.iterable.mark();
     }
-
-    fn blacken(&self) {
-        self.iterable.blacken();
-    }
 }
 
 impl fmt::Display for ObjStringIter {
@@ -141,7 +141,7 @@ impl fmt::Display for ObjStringIter {
 
 enum ObjUpvalueState {
     Closed(Value),
-    Open(*mut Value),
+    Open(*mut PackedValue),
 }
 
 pub struct ObjUpvalue {
@@ -150,7 +150,7 @@ pub struct ObjUpvalue {
 }
 
 impl ObjUpvalue {
-    pub(crate) fn new(address: *mut Value) -> Self {
+    pub(crate) fn new(address: *mut PackedValue) -> Self {
         ObjUpvalue {
             data: ObjUpvalueState::Open(address),
             next: None,
@@ -159,14 +159,18 @@ impl ObjUpvalue {
 
     pub(crate) fn get(&self) -> Value {
         match self.data {
-            ObjUpvalueState::Open(a) => unsafe { *a },
+            ObjUpvalueState::Open(a) => unsafe { (*a).unpack() },
             ObjUpvalueState::Closed(v) => v,
         }
     }
 
     pub(crate) fn set(&mut self, value: Value) {
         match self.data {
-            ObjUpvalueState::Open(a) => unsafe { *a = value },
+            ObjUpvalueState::Open(a) => {
+                // The variable may live on the stack of a fiber that isn't active.
+                memory::write_barrier(&value);
+                unsafe { *a = PackedValue::new(value) }
+            }
             ObjUpvalueState::Closed(ref mut v) => *v = value,
         }
     }
@@ -178,7 +182,7 @@ impl ObjUpvalue {
         }
     }
 
-    pub fn is_open_with_pred(&self, predicate: impl Fn(*const Value) -> bool) -> bool {
+    pub fn is_open_with_pred(&self, predicate: impl Fn(*const PackedValue) -> bool) -> bool {
         match self.data {
             ObjUpvalueState::Open(address) => predicate(address),
             ObjUpvalueState::Closed(_) => false,
@@ -201,21 +205,16 @@ impl memory::GcManaged for ObjUpvalue {
             u.mark();
         }
     }
-
-    fn blacken(&self) {
-        match self.data {
-            ObjUpvalueState::Closed(value) => value.blacken(),
-            ObjUpvalueState::Open(_) => {}
-        }
-        if let Some(u) = self.next.as_ref() {
-            u.blacken();
-        }
-    }
 }
 
 #[derive(Clone)]
 pub struct ObjFunction {
     pub arity: usize,
+    pub default_count: usize,
+    pub is_variadic: bool,
+    pub is_generator: bool,
+    pub is_async: bool,
+    pub parameters: Vec<Gc<ObjString>>,
     pub upvalue_count: usize,
     pub chunk: Gc<Chunk>,
     pub name: Gc<ObjString>,
@@ -233,6 +232,11 @@ impl ObjFunction {
         ObjFunction {
             name,
             arity,
+            default_count: 0,
+            is_variadic: false,
+            is_generator: false,
+            is_async: false,
+            parameters: Vec::new(),
             upvalue_count,
             chunk,
             module_path,
@@ -243,12 +247,9 @@ impl ObjFunction {
 impl memory::GcManaged for ObjFunction {
     fn mark(&self) {
         self.name.mark();
+        self.parameters.mark();
         self.chunk.mark();
-    }
-
-    fn blacken(&self) {
-        self.name.blacken();
-        self.chunk.blacken();
+        self.module_path.mark();
     }
 }
 
@@ -257,6 +258,11 @@ impl Default for ObjFunction {
         ObjFunction {
             name: Gc::dangling(),
             arity: 0,
+            default_count: 0,
+            is_variadic: false,
+            is_generator: false,
+            is_async: false,
+            parameters: Vec::new(),
             upvalue_count: 0,
             chunk: Gc::dangling(),
             module_path: Gc::dangling(),
@@ -287,9 +293,9 @@ impl ObjNative {
 }
 
 impl memory::GcManaged for ObjNative {
-    fn mark(&self) {}
-
-    fn blacken(&self) {}
+    fn mark(&self) {
+        self.name.mark();
+    }
 }
 
 impl fmt::Display for ObjNative {
@@ -301,6 +307,7 @@ impl fmt::Display for ObjNative {
 pub struct ObjClosure {
     pub function: memory::Gc<ObjFunction>,
     pub upvalues: RefCell<Vec<memory::Gc<RefCell<ObjUpvalue>>>>,
+    pub defaults: RefCell<Vec<Value>>,
     pub(crate) module: Gc<RefCell<ObjModule>>,
 }
 
@@ -313,6 +320,7 @@ impl ObjClosure {
         ObjClosure {
             function,
             upvalues: RefCell::new(upvalues),
+            defaults: RefCell::new(Vec::new()),
             module,
         }
     }
@@ -322,11 +330,7 @@ impl memory::GcManaged for ObjClosure {
     fn mark(&self) {
         self.function.mark();
         self.upvalues.mark();
-    }
-
-    fn blacken(&self) {
-        self.function.blacken();
-        self.upvalues.blacken();
+        self.defaults.mark();
     }
 }
 
@@ -369,14 +373,10 @@ impl ObjClass {
 
 impl memory::GcManaged for ObjClass {
     fn mark(&self) {
+        self.name.mark();
         self.metaclass.mark();
         self.methods.mark();
     }
-
-    fn blacken(&self) {
-        self.metaclass.blacken();
-        self.methods.blacken();
-    }
 }
 
 impl fmt::Display for ObjClass {
@@ -404,11 +404,6 @@ impl memory::GcManaged for ObjInstance {
         self.class.mark();
         self.fields.mark();
     }
-
-    fn blacken(&self) {
-        self.class.blacken();
-        self.fields.blacken();
-    }
 }
 
 impl fmt::Display for ObjInstance {
@@ -433,11 +428,6 @@ impl<T: 'static + memory::GcManaged> memory::GcManaged for ObjBoundMethod<T> {
         self.receiver.mark();
         self.method.mark();
     }
-
-    fn blacken(&self) {
-        self.receiver.mark();
-        self.method.blacken();
-    }
 }
 
 impl fmt::Display for ObjBoundMethod<ObjNative> {
@@ -462,7 +452,7 @@ impl fmt::Display for ObjBoundMethod<ObjClosure> {
 
 pub struct ObjVec {
     pub class: Gc<ObjClass>,
-    pub elements: Vec<Value>,
+    pub elements: Vec<PackedValue>,
     disp_lock: Cell<bool>,
 }
 
@@ -482,9 +472,8 @@ impl memory::GcManaged for ObjVec {
         self.elements.mark();
     }
 
-    fn blacken(&self) {
-        self.class.blacken();
-        self.elements.blacken();
+    fn heap_size(&self) -> usize {
+        self.elements.capacity() * mem::size_of::<PackedValue>()
     }
 }
 
@@ -533,7 +522,7 @@ impl ObjVecIter {
         if self.current >= borrowed_vec.elements.len() {
             return None;
         }
-        let ret = borrowed_vec.elements[self.current];
+        let ret = borrowed_vec.elements[self.current].unpack();
         self.current += 1;
         Some(ret)
     }
@@ -543,10 +532,6 @@ impl memory::GcManaged for ObjVecIter {
     fn mark(&self) {
         self.iterable.mark();
     }
-
-    fn blacken(&self) {
-        self.iterable.blacken();
-    }
 }
 
 impl fmt::Display for ObjVecIter {
@@ -604,10 +589,6 @@ impl memory::GcManaged for ObjRange {
     fn mark(&self) {
         self.class.mark();
     }
-
-    fn blacken(&self) {
-        self.class.blacken();
-    }
 }
 
 impl fmt::Display for ObjRange {
@@ -648,10 +629,6 @@ impl memory::GcManaged for ObjRangeIter {
     fn mark(&self) {
         self.iterable.mark();
     }
-
-    fn blacken(&self) {
-        self.iterable.blacken();
-    }
 }
 
 impl fmt::Display for ObjRangeIter {
@@ -660,9 +637,16 @@ impl fmt::Display for ObjRangeIter {
     }
 }
 
+#[derive(Clone, Copy)]
+pub(crate) struct HashMapKey {
+    pub(crate) value: Value,
+    pub(crate) instance_hash: Option<u64>,
+}
+
 pub struct ObjHashMap {
     pub class: Gc<ObjClass>,
     pub elements: HashMap<Value, Value, BuildPassThroughHasher>,
+    instance_keys: HashMap<u64, Vec<Value>, BuildPassThroughHasher>,
     disp_lock: Cell<bool>,
 }
 
@@ -671,9 +655,40 @@ impl ObjHashMap {
         ObjHashMap {
             class,
             elements: HashMap::with_hasher(BuildPassThroughHasher::default()),
+            instance_keys: HashMap::default(),
             disp_lock: Cell::new(false),
         }
     }
+
+    pub(crate) fn instance_keys(&self, hash: u64) -> Vec<Value> {
+        self.instance_keys.get(&hash).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn insert(&mut self, key: HashMapKey, value: Value) -> Option<Value> {
+        let prev_value = self.elements.insert(key.value, value);
+        if let (None, Some(hash)) = (prev_value, key.instance_hash) {
+            self.instance_keys.entry(hash).or_default().push(key.value);
+        }
+        prev_value
+    }
+
+    pub(crate) fn remove(&mut self, key: HashMapKey) -> Option<Value> {
+        let prev_value = self.elements.remove(&key.value);
+        if let (Some(_), Some(hash)) = (prev_value, key.instance_hash) {
+            if let Some(keys) = self.instance_keys.get_mut(&hash) {
+                keys.retain(|&k| k != key.value);
+                if keys.is_empty() {
+                    self.instance_keys.remove(&hash);
+                }
+            }
+        }
+        prev_value
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.elements.clear();
+        self.instance_keys.clear();
+    }
 }
 
 impl memory::GcManaged for ObjHashMap {
@@ -682,9 +697,8 @@ impl memory::GcManaged for ObjHashMap {
         self.elements.mark();
     }
 
-    fn blacken(&self) {
-        self.class.blacken();
-        self.elements.blacken();
+    fn heap_size(&self) -> usize {
+        self.elements.capacity() * mem::size_of::<(Value, Value)>()
     }
 }
 
@@ -755,9 +769,8 @@ impl memory::GcManaged for ObjTuple {
         self.elements.mark();
     }
 
-    fn blacken(&self) {
-        self.class.blacken();
-        self.elements.blacken();
+    fn heap_size(&self) -> usize {
+        self.elements.capacity() * mem::size_of::<Value>()
     }
 }
 
@@ -837,10 +850,6 @@ impl memory::GcManaged for ObjTupleIter {
     fn mark(&self) {
         self.iterable.mark();
     }
-
-    fn blacken(&self) {
-        self.iterable.blacken();
-    }
 }
 
 impl fmt::Display for ObjTupleIter {
@@ -869,12 +878,9 @@ impl ObjModule {
 
 impl memory::GcManaged for ObjModule {
     fn mark(&self) {
+        self.path.mark();
         self.attributes.mark();
     }
-
-    fn blacken(&self) {
-        self.attributes.blacken();
-    }
 }
 
 impl fmt::Display for ObjModule {
@@ -893,10 +899,6 @@ impl GcManaged for CallFrame {
     fn mark(&self) {
         self.closure.mark();
     }
-
-    fn blacken(&self) {
-        self.closure.blacken();
-    }
 }
 
 #[derive(Clone, Copy)]
@@ -916,7 +918,7 @@ impl ExcHandler {
 pub struct ObjFiber {
     pub(crate) class: Gc<ObjClass>,
     pub(crate) caller: Option<Gc<RefCell<ObjFiber>>>,
-    pub(crate) stack: Stack<Value, STACK_MAX>,
+    pub(crate) stack: Stack<PackedValue, STACK_MAX>,
     pub(crate) frames: Vec<CallFrame>,
     pub(crate) open_upvalues: Option<Gc<RefCell<ObjUpvalue>>>,
     pub(crate) call_arity: usize,
@@ -924,6 +926,12 @@ pub struct ObjFiber {
     pub(crate) exc_handlers: Vec<ExcHandler>,
     pub(crate) return_ip: Option<*const u8>,
     pub(crate) error_ip: Option<*const u8>,
+    pub(crate) call_barriers: Vec<usize>,
+    pub(crate) nested_exception: Option<Value>,
+    pub(crate) handling_exception: bool,
+    pub(crate) error: Option<Value>,
+    pub(crate) is_try: bool,
+    pub(crate) is_blocked: bool,
 }
 
 impl ObjFiber {
@@ -946,6 +954,12 @@ impl ObjFiber {
             exc_handlers: Vec::new(),
             return_ip: None,
             error_ip: None,
+            call_barriers: Vec::new(),
+            nested_exception: None,
+            handling_exception: false,
+            error: None,
+            is_try: false,
+            is_blocked: false,
         }
     }
 
@@ -992,14 +1006,20 @@ impl ObjFiber {
     }
 
     pub(crate) fn is_new(&self) -> bool {
-        self.frames.len() == 1
-            && self.frames[0].ip == self.frames[0].closure.function.chunk.code.as_ptr()
+        // Generator fibers are created with their arguments already on the stack, so they're
+        // resumed rather than started.
+        !self.has_started() && self.stack.len() == 0
     }
 
     pub(crate) fn has_finished(&self) -> bool {
         self.frames.is_empty()
     }
 
+    pub(crate) fn has_started(&self) -> bool {
+        !(self.frames.len() == 1
+            && self.frames[0].ip == self.frames[0].closure.function.chunk.code.as_ptr())
+    }
+
     pub(crate) fn push_exc_handler(&mut self, catch_ip: *const u8, finally_ip: *const u8) {
         self.exc_handlers.push(ExcHandler {
             catch_ip,
@@ -1023,6 +1043,10 @@ impl ObjFiber {
         }
     }
 
+    pub(crate) fn at_call_barrier(&self) -> bool {
+        self.call_barriers.last() == Some(&self.frames.len())
+    }
+
     pub(crate) fn store_error_ip_or(&mut self, alternative: *const u8) {
         self.current_frame_mut().expect("Expected CallFrame.").ip =
             self.error_ip.unwrap_or(alternative);
@@ -1040,18 +1064,12 @@ impl GcManaged for ObjFiber {
             caller.mark();
         }
         self.return_value.mark();
-    }
-
-    fn blacken(&self) {
-        self.stack.blacken();
-        self.frames.blacken();
-        if let Some(upvalues) = self.open_upvalues.as_ref() {
-            upvalues.blacken();
+        if let Some(exception) = self.nested_exception.as_ref() {
+            exception.mark();
         }
-        if let Some(&caller) = self.caller.as_ref() {
-            caller.blacken();
+        if let Some(error) = self.error.as_ref() {
+            error.mark();
         }
-        self.return_value.blacken();
     }
 }
 
@@ -1060,3 +1078,78 @@ impl fmt::Display for ObjFiber {
         write!(f, "fiber")
     }
 }
+
+/// A fiber blocked on a channel, along with the wait object it yielded to its caller. Senders
+/// also hold the value they're waiting to send.
+pub(crate) struct ChannelWaiter {
+    pub(crate) fiber: Gc<RefCell<ObjFiber>>,
+    pub(crate) wait: Value,
+    pub(crate) value: Value,
+}
+
+impl ChannelWaiter {
+    pub(crate) fn new(fiber: Gc<RefCell<ObjFiber>>, wait: Value, value: Value) -> Self {
+        ChannelWaiter { fiber, wait, value }
+    }
+}
+
+impl GcManaged for ChannelWaiter {
+    fn mark(&self) {
+        self.fiber.mark();
+        self.wait.mark();
+        self.value.mark();
+    }
+}
+
+pub struct ObjChannel {
+    pub(crate) class: Gc<ObjClass>,
+    pub(crate) buffer: VecDeque<Value>,
+    pub(crate) capacity: Option<usize>,
+    pub(crate) closed: bool,
+    pub(crate) receivers: VecDeque<ChannelWaiter>,
+    pub(crate) senders: VecDeque<ChannelWaiter>,
+}
+
+impl ObjChannel {
+    pub(crate) fn new(class: Gc<ObjClass>, capacity: Option<usize>) -> Self {
+        ObjChannel {
+            class,
+            buffer: VecDeque::new(),
+            capacity,
+            closed: false,
+            receivers: VecDeque::new(),
+            senders: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn has_space(&self) -> bool {
+        self.capacity.is_none_or(|c| self.buffer.len() < c)
+    }
+
+    pub(crate) fn pop_receiver(&mut self) -> Option<ChannelWaiter> {
+        self.receivers.pop_front()
+    }
+
+    pub(crate) fn pop_sender(&mut self) -> Option<ChannelWaiter> {
+        self.senders.pop_front()
+    }
+}
+
+impl GcManaged for ObjChannel {
+    fn mark(&self) {
+        self.class.mark();
+        self.buffer.iter().for_each(|v| v.mark());
+        self.receivers.iter().for_each(|w| w.mark());
+        self.senders.iter().for_each(|w| w.mark());
+    }
+
+    fn heap_size(&self) -> usize {
+        self.buffer.capacity() * mem::size_of::<Value>()
+    }
+}
+
+impl fmt::Display for ObjChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel")
+    }
+}
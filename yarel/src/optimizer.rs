@@ -0,0 +1,549 @@
+/* Copyright 2020-2021 Matt Spraggs
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::Root;
+use crate::object::ObjString;
+use crate::packed_value::PackedValue;
+use crate::utils;
+use crate::value::Value;
+use crate::vm::Vm;
+
+/// Determines which optimisations are applied to each chunk once it's been compiled.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum OptimizationLevel {
+    /// The compiled code is left as it is.
+    None,
+    /// Unreachable code is removed, jumps to jumps are threaded and instructions with no net
+    /// effect are removed.
+    Basic,
+    /// As `Basic`, but arithmetic, comparisons and string concatenation on constants are also
+    /// evaluated at compile time and common sequences of instructions are combined into
+    /// superinstructions.
+    Full,
+}
+
+// Bounds the number of times the passes are repeated, since each can create opportunities for
+// the others.
+const MAX_ITERATIONS: usize = 8;
+
+struct Instruction {
+    opcode: OpCode,
+    // The instruction's encoding and the line of each byte. Jump offsets in the encoding are
+    // rewritten from `targets` once the chunk has been optimised.
+    code: Vec<u8>,
+    lines: Vec<i32>,
+    // The indices of the instructions this one jumps to.
+    targets: Vec<usize>,
+    removed: bool,
+}
+
+impl Instruction {
+    fn new(opcode: OpCode, line: i32) -> Self {
+        Instruction {
+            opcode,
+            code: vec![opcode as u8],
+            lines: vec![line],
+            targets: Vec::new(),
+            removed: false,
+        }
+    }
+
+    fn is_terminator(&self) -> bool {
+        matches!(
+            self.opcode,
+            OpCode::Return | OpCode::Throw | OpCode::Jump | OpCode::Loop | OpCode::Rethrow
+        )
+    }
+
+    fn constant_index(&self) -> usize {
+        u16::from_le_bytes([self.code[1], self.code[2]]) as usize
+    }
+}
+
+/// Optimises the code in a chunk, leaving it unchanged if the code can't be decoded or the
+/// optimised code can't be encoded.
+/// Returns the strings created by folding constants, which must be kept alive until the chunk has
+/// been allocated.
+pub(crate) fn optimize(
+    vm: &mut Vm,
+    chunk: &mut Chunk,
+    level: OptimizationLevel,
+) -> Vec<Root<ObjString>> {
+    if level == OptimizationLevel::None || chunk.code.is_empty() {
+        return Vec::new();
+    }
+    let instructions = match decode(chunk) {
+        Some(instructions) => instructions,
+        None => return Vec::new(),
+    };
+    let mut optimizer = Optimizer {
+        vm,
+        instructions,
+        constants: chunk.constants.clone(),
+        is_target: Vec::new(),
+        strings: Vec::new(),
+    };
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = optimizer.thread_jumps();
+        changed |= optimizer.remove_unreachable();
+        changed |= optimizer.remove_no_ops();
+        if level >= OptimizationLevel::Full {
+            changed |= optimizer.fold_constants();
+        }
+        if !changed {
+            break;
+        }
+        optimizer.compact();
+    }
+    if level >= OptimizationLevel::Full {
+        optimizer.fuse_superinstructions();
+        optimizer.compact();
+    }
+
+    if let Some((code, lines)) = encode(&optimizer.instructions) {
+        chunk.code = code;
+        chunk.lines = lines;
+        chunk.constants = optimizer.constants;
+    }
+    optimizer.strings
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut indices = vec![0; chunk.code.len() + 1];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let opcode = OpCode::from_byte(chunk.code[offset])?;
+        let mut size = 1 + opcode.arg_sizes().iter().sum::<usize>();
+        if let OpCode::Closure = opcode {
+            let index = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+            if let Value::ObjFunction(function) = chunk.constants[index as usize].unpack() {
+                size += 2 * function.upvalue_count;
+            }
+        }
+        indices[offset] = instructions.len();
+        instructions.push(Instruction {
+            opcode,
+            code: chunk.code[offset..offset + size].to_vec(),
+            lines: chunk.lines[offset..offset + size].to_vec(),
+            targets: Vec::new(),
+            removed: false,
+        });
+        offset += size;
+    }
+    indices[offset] = instructions.len();
+
+    // Jump offsets are converted to instruction indices, so that they survive instructions being
+    // removed.
+    let mut offset = 0;
+    for instruction in &mut instructions {
+        let next = offset + instruction.code.len();
+        let read_short =
+            |i: usize| u16::from_le_bytes([instruction.code[i], instruction.code[i + 1]]) as usize;
+        instruction.targets = match instruction.opcode {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfStopIter | OpCode::Rethrow => {
+                vec![indices[next + read_short(1)]]
+            }
+            OpCode::Loop => vec![indices[next - read_short(1)]],
+            OpCode::PushExcHandler => {
+                let catch_offset = next + read_short(1);
+                vec![indices[catch_offset], indices[catch_offset + read_short(3)]]
+            }
+            _ => Vec::new(),
+        };
+        offset = next;
+    }
+    Some(instructions)
+}
+
+fn encode(instructions: &[Instruction]) -> Option<(Vec<u8>, Vec<i32>)> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += instruction.code.len();
+    }
+    offsets.push(offset);
+
+    let mut code = Vec::with_capacity(offset);
+    let mut lines = Vec::with_capacity(offset);
+    for (index, instruction) in instructions.iter().enumerate() {
+        let next = offsets[index + 1];
+        let mut bytes = instruction.code.clone();
+        let mut write_short = |i: usize, value: Option<usize>| -> Option<()> {
+            let value = u16::try_from(value?).ok()?;
+            bytes[i..i + 2].copy_from_slice(&value.to_le_bytes());
+            Some(())
+        };
+        match instruction.opcode {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfStopIter | OpCode::Rethrow => {
+                write_short(1, offsets[instruction.targets[0]].checked_sub(next))?;
+            }
+            OpCode::Loop => {
+                write_short(1, next.checked_sub(offsets[instruction.targets[0]]))?;
+            }
+            OpCode::PushExcHandler => {
+                let catch_offset = offsets[instruction.targets[0]];
+                let finally_offset = offsets[instruction.targets[1]];
+                write_short(1, catch_offset.checked_sub(next))?;
+                write_short(3, finally_offset.checked_sub(catch_offset))?;
+            }
+            _ => {}
+        }
+        code.extend_from_slice(&bytes);
+        lines.extend_from_slice(&instruction.lines);
+    }
+    Some((code, lines))
+}
+
+struct Optimizer<'a> {
+    vm: &'a mut Vm,
+    instructions: Vec<Instruction>,
+    constants: Vec<PackedValue>,
+    is_target: Vec<bool>,
+    strings: Vec<Root<ObjString>>,
+}
+
+impl<'a> Optimizer<'a> {
+    /// Points jumps that land on an unconditional jump at that jump's destination. Conditional
+    /// jumps that land on a jump with the same condition are threaded too, since the value being
+    /// tested is left on the stack.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for index in 0..self.instructions.len() {
+            let opcode = self.instructions[index].opcode;
+            if !matches!(
+                opcode,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfStopIter | OpCode::Loop
+            ) {
+                continue;
+            }
+            let mut target = self.instructions[index].targets[0];
+            for _ in 0..self.instructions.len() {
+                let next = &self.instructions[target];
+                let can_thread =
+                    matches!(next.opcode, OpCode::Jump | OpCode::Loop) || next.opcode == opcode;
+                if !can_thread {
+                    break;
+                }
+                // Loops are only threaded backwards and everything else forwards, so that every
+                // cycle still passes through a loop instruction.
+                let next_target = next.targets[0];
+                let keeps_direction = match opcode {
+                    OpCode::Loop => next_target <= index,
+                    _ => next_target > index,
+                };
+                if !keeps_direction || next_target == target {
+                    break;
+                }
+                target = next_target;
+            }
+            if target != self.instructions[index].targets[0] {
+                self.instructions[index].targets[0] = target;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn remove_unreachable(&mut self) -> bool {
+        let mut reachable = vec![false; self.instructions.len()];
+        let mut worklist = vec![0];
+        while let Some(index) = worklist.pop() {
+            if index >= self.instructions.len() || reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            let instruction = &self.instructions[index];
+            worklist.extend_from_slice(&instruction.targets);
+            if !instruction.is_terminator() {
+                worklist.push(index + 1);
+            }
+        }
+
+        let mut changed = false;
+        for (instruction, reachable) in self.instructions.iter_mut().zip(reachable) {
+            if !reachable {
+                instruction.removed = true;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Removes jumps to the next instruction and values that are pushed only to be popped.
+    fn remove_no_ops(&mut self) -> bool {
+        self.find_targets();
+        let mut changed = false;
+        for index in 0..self.instructions.len() {
+            let instruction = &self.instructions[index];
+            if instruction.removed {
+                continue;
+            }
+            if let OpCode::Jump = instruction.opcode {
+                if self.next_live(index) == Some(instruction.targets[0]) {
+                    self.instructions[index].removed = true;
+                    changed = true;
+                }
+                continue;
+            }
+            let is_pure_push = matches!(
+                instruction.opcode,
+                OpCode::Constant
+                    | OpCode::Nil
+                    | OpCode::True
+                    | OpCode::False
+                    | OpCode::CopyTop
+                    | OpCode::GetLocal
+                    | OpCode::GetUpvalue
+            );
+            if !is_pure_push {
+                continue;
+            }
+            match self.next_live(index) {
+                Some(next)
+                    if !self.is_target[next]
+                        && matches!(self.instructions[next].opcode, OpCode::Pop) =>
+                {
+                    self.instructions[index].removed = true;
+                    self.instructions[next].removed = true;
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        changed
+    }
+
+    /// Evaluates unary and binary operations whose operands are all constants.
+    fn fold_constants(&mut self) -> bool {
+        self.find_targets();
+        let mut changed = false;
+        for index in 0..self.instructions.len() {
+            if self.instructions[index].removed {
+                continue;
+            }
+            let operation = self.instructions[index].opcode;
+            let arity = match operation {
+                OpCode::Negate | OpCode::LogicalNot => 1,
+                OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::BitwiseAnd
+                | OpCode::BitwiseOr
+                | OpCode::BitwiseXor
+                | OpCode::Modulo
+                | OpCode::BitShiftLeft
+                | OpCode::BitShiftRight => 2,
+                _ => continue,
+            };
+
+            // Only the first operand may be jumped to, since the folded value replaces it.
+            let mut operands = Vec::new();
+            let mut position = index;
+            for _ in 0..arity {
+                match self.prev_live(position) {
+                    Some(prev) if !self.is_target[position] => {
+                        operands.push(prev);
+                        position = prev;
+                    }
+                    _ => break,
+                }
+            }
+            if operands.len() != arity {
+                continue;
+            }
+            operands.reverse();
+            let values: Option<Vec<_>> = operands.iter().map(|&i| self.constant_value(i)).collect();
+            let result = match values.and_then(|values| self.evaluate(operation, &values)) {
+                Some(result) => result,
+                None => continue,
+            };
+            let first = operands[0];
+            let line = self.instructions[first].lines[0];
+            let folded = match result {
+                Value::None => Instruction::new(OpCode::Nil, line),
+                Value::Boolean(true) => Instruction::new(OpCode::True, line),
+                Value::Boolean(false) => Instruction::new(OpCode::False, line),
+                value => {
+                    if self.constants.len() > u16::MAX as usize {
+                        continue;
+                    }
+                    self.constants.push(PackedValue::new(value));
+                    let index = (self.constants.len() - 1) as u16;
+                    let mut instruction = Instruction::new(OpCode::Constant, line);
+                    instruction.code.extend_from_slice(&index.to_le_bytes());
+                    instruction.lines.extend_from_slice(&[line, line]);
+                    instruction
+                }
+            };
+            self.instructions[first] = folded;
+            for &operand in &operands[1..] {
+                self.instructions[operand].removed = true;
+            }
+            self.instructions[index].removed = true;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Replaces local variable accesses that are followed by another local variable access, an
+    /// addition or a constant with a single instruction, saving the cost of dispatching each one.
+    /// This is done last, since the other passes don't recognise the combined instructions.
+    fn fuse_superinstructions(&mut self) {
+        self.find_targets();
+        for index in 0..self.instructions.len() {
+            if self.instructions[index].removed
+                || self.instructions[index].opcode != OpCode::GetLocal
+            {
+                continue;
+            }
+            // Instructions after the first can't be jumped to, since they no longer exist alone.
+            let following = |optimizer: &Self, index: usize| {
+                optimizer
+                    .next_live(index)
+                    .filter(|&next| !optimizer.is_target[next])
+            };
+            let second = match following(self, index) {
+                Some(second) => second,
+                None => continue,
+            };
+            let (opcode, fused) = match self.instructions[second].opcode {
+                OpCode::GetLocal => match following(self, second) {
+                    Some(third) if self.instructions[third].opcode == OpCode::Add => {
+                        (OpCode::AddLocals, vec![second, third])
+                    }
+                    _ => (OpCode::GetLocals, vec![second]),
+                },
+                OpCode::Constant => (OpCode::GetLocalConstant, vec![second]),
+                _ => continue,
+            };
+
+            // Errors are reported using the line of the last byte of an instruction that's been
+            // executed, so the final byte takes the line of the final instruction being fused.
+            let first = &self.instructions[index];
+            let mut instruction = Instruction::new(opcode, first.lines[0]);
+            instruction.code.push(first.code[1]);
+            instruction.lines.push(first.lines[1]);
+            let operands = &self.instructions[second];
+            instruction.code.extend_from_slice(&operands.code[1..]);
+            instruction.lines.extend_from_slice(&operands.lines[1..]);
+            let last_line = self.instructions[*fused.last().unwrap()].lines[0];
+            *instruction.lines.last_mut().unwrap() = last_line;
+
+            self.instructions[index] = instruction;
+            for removed in fused {
+                self.instructions[removed].removed = true;
+            }
+        }
+    }
+
+    fn constant_value(&self, index: usize) -> Option<Value> {
+        let instruction = &self.instructions[index];
+        match instruction.opcode {
+            OpCode::Nil => Some(Value::None),
+            OpCode::True => Some(Value::Boolean(true)),
+            OpCode::False => Some(Value::Boolean(false)),
+            OpCode::Constant => match self.constants[instruction.constant_index()].unpack() {
+                value @ (Value::Number(_) | Value::ObjString(_)) => Some(value),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn evaluate(&mut self, operation: OpCode, values: &[Value]) -> Option<Value> {
+        let result = match (operation, values) {
+            (OpCode::Negate, &[Value::Number(a)]) => utils::negate(a),
+            (OpCode::LogicalNot, &[value]) => Value::Boolean(!value.as_bool()),
+            (OpCode::Equal, &[a, b]) => Value::Boolean(a == b),
+            (OpCode::Add, &[Value::ObjString(a), Value::ObjString(b)]) => {
+                let string = self
+                    .vm
+                    .new_root_obj_string(format!("{}{}", *a, *b).as_str());
+                let value = Value::ObjString(string.as_gc());
+                self.strings.push(string);
+                value
+            }
+            (operation, &[Value::Number(a), Value::Number(b)]) => {
+                let op = match operation {
+                    OpCode::Greater => utils::greater,
+                    OpCode::Less => utils::less,
+                    OpCode::Add => utils::add,
+                    OpCode::Subtract => utils::subtract,
+                    OpCode::Multiply => utils::multiply,
+                    OpCode::Divide => utils::divide,
+                    OpCode::Modulo => utils::modulo,
+                    OpCode::BitwiseAnd => utils::bitwise_and,
+                    OpCode::BitwiseOr => utils::bitwise_or,
+                    OpCode::BitwiseXor => utils::bitwise_xor,
+                    OpCode::BitShiftLeft => utils::shift_left,
+                    OpCode::BitShiftRight => utils::shift_right,
+                    _ => return None,
+                };
+                op(a, b)
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn find_targets(&mut self) {
+        self.is_target = vec![false; self.instructions.len()];
+        for instruction in &self.instructions {
+            if !instruction.removed {
+                for &target in &instruction.targets {
+                    self.is_target[target] = true;
+                }
+            }
+        }
+    }
+
+    fn next_live(&self, index: usize) -> Option<usize> {
+        (index + 1..self.instructions.len()).find(|&i| !self.instructions[i].removed)
+    }
+
+    fn prev_live(&self, index: usize) -> Option<usize> {
+        (0..index).rev().find(|&i| !self.instructions[i].removed)
+    }
+
+    /// Drops removed instructions. Jumps to a removed instruction are redirected to the next one
+    /// that remains.
+    fn compact(&mut self) {
+        let mut indices = vec![0; self.instructions.len() + 1];
+        let mut count = self.instructions.iter().filter(|i| !i.removed).count();
+        indices[self.instructions.len()] = count;
+        for (index, instruction) in self.instructions.iter().enumerate().rev() {
+            if !instruction.removed {
+                count -= 1;
+            }
+            indices[index] = count;
+        }
+        self.instructions.retain(|i| !i.removed);
+        for instruction in &mut self.instructions {
+            for target in &mut instruction.targets {
+                *target = indices[*target];
+            }
+        }
+    }
+}
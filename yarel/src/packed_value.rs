@@ -0,0 +1,285 @@
+/* Copyright 2020-2021 Matt Spraggs
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::error::Error;
+use crate::memory::{self, Gc};
+use crate::object::{
+    ObjBoundMethod, ObjChannel, ObjClass, ObjClosure, ObjFiber, ObjFunction, ObjHashMap,
+    ObjInstance, ObjModule, ObjNative, ObjRange, ObjRangeIter, ObjString, ObjStringIter, ObjTuple,
+    ObjTupleIter, ObjVec, ObjVecIter,
+};
+use crate::value::Value;
+
+/// The form in which values are held on the stack, in a chunk's constants and in Vecs.
+///
+/// By default this is a plain `Value`. With the `nan_boxing` feature it's instead an eight byte
+/// NaN-boxed encoding, half the size of a `Value`: numbers are stored as they are, and every other
+/// value is stored in the bits of a NaN that's never used to store a number.
+#[derive(Clone, Copy)]
+pub struct PackedValue(Repr);
+
+#[cfg(not(feature = "nan_boxing"))]
+type Repr = Value;
+
+#[cfg(feature = "nan_boxing")]
+type Repr = u64;
+
+impl PackedValue {
+    #[cfg(not(feature = "nan_boxing"))]
+    #[inline(always)]
+    pub fn new(value: Value) -> Self {
+        PackedValue(value)
+    }
+
+    #[cfg(not(feature = "nan_boxing"))]
+    #[inline(always)]
+    pub fn unpack(self) -> Value {
+        self.0
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    #[inline(always)]
+    pub fn new(value: Value) -> Self {
+        PackedValue(nan_box::pack(value))
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    #[inline(always)]
+    pub fn unpack(self) -> Value {
+        unsafe { nan_box::unpack(self.0) }
+    }
+
+    /// Checks whether a reference to an object at the provided address can be packed. The heap
+    /// checks this once for each object it allocates, so that packing doesn't have to.
+    #[cfg(not(feature = "nan_boxing"))]
+    pub(crate) fn can_hold_address(_address: usize) -> bool {
+        true
+    }
+
+    #[cfg(feature = "nan_boxing")]
+    pub(crate) fn can_hold_address(address: usize) -> bool {
+        nan_box::can_hold_address(address as u64)
+    }
+
+    pub fn as_bool(&self) -> bool {
+        self.unpack().as_bool()
+    }
+
+    pub fn try_as_bool(&self) -> Option<bool> {
+        self.unpack().try_as_bool()
+    }
+
+    pub fn try_as_number(&self) -> Option<f64> {
+        self.unpack().try_as_number()
+    }
+
+    pub fn try_as_obj_string(&self) -> Option<Gc<ObjString>> {
+        self.unpack().try_as_obj_string()
+    }
+
+    pub fn try_as_obj_string_iter(&self) -> Option<Gc<RefCell<ObjStringIter>>> {
+        self.unpack().try_as_obj_string_iter()
+    }
+
+    pub fn try_as_obj_function(&self) -> Option<Gc<ObjFunction>> {
+        self.unpack().try_as_obj_function()
+    }
+
+    pub fn try_as_obj_native(&self) -> Option<Gc<ObjNative>> {
+        self.unpack().try_as_obj_native()
+    }
+
+    pub fn try_as_obj_closure(&self) -> Option<Gc<ObjClosure>> {
+        self.unpack().try_as_obj_closure()
+    }
+
+    pub fn try_as_obj_class(&self) -> Option<Gc<ObjClass>> {
+        self.unpack().try_as_obj_class()
+    }
+
+    pub fn try_as_obj_instance(&self) -> Option<Gc<RefCell<ObjInstance>>> {
+        self.unpack().try_as_obj_instance()
+    }
+
+    pub fn try_as_obj_bound_method(&self) -> Option<Gc<RefCell<ObjBoundMethod<ObjClosure>>>> {
+        self.unpack().try_as_obj_bound_method()
+    }
+
+    pub fn try_as_obj_bound_native(&self) -> Option<Gc<RefCell<ObjBoundMethod<ObjNative>>>> {
+        self.unpack().try_as_obj_bound_native()
+    }
+
+    pub fn try_as_obj_tuple(&self) -> Option<Gc<ObjTuple>> {
+        self.unpack().try_as_obj_tuple()
+    }
+
+    pub fn try_as_obj_tuple_iter(&self) -> Option<Gc<RefCell<ObjTupleIter>>> {
+        self.unpack().try_as_obj_tuple_iter()
+    }
+
+    pub fn try_as_obj_vec(&self) -> Option<Gc<RefCell<ObjVec>>> {
+        self.unpack().try_as_obj_vec()
+    }
+
+    pub fn try_as_obj_vec_iter(&self) -> Option<Gc<RefCell<ObjVecIter>>> {
+        self.unpack().try_as_obj_vec_iter()
+    }
+
+    pub fn try_as_obj_range(&self) -> Option<Gc<ObjRange>> {
+        self.unpack().try_as_obj_range()
+    }
+
+    pub fn try_as_obj_range_iter(&self) -> Option<Gc<RefCell<ObjRangeIter>>> {
+        self.unpack().try_as_obj_range_iter()
+    }
+
+    pub fn try_as_obj_hash_map(&self) -> Option<Gc<RefCell<ObjHashMap>>> {
+        self.unpack().try_as_obj_hash_map()
+    }
+
+    pub fn try_as_obj_module(&self) -> Option<Gc<RefCell<ObjModule>>> {
+        self.unpack().try_as_obj_module()
+    }
+
+    pub fn try_as_obj_fiber(&self) -> Option<Gc<RefCell<ObjFiber>>> {
+        self.unpack().try_as_obj_fiber()
+    }
+
+    pub fn try_as_obj_channel(&self) -> Option<Gc<RefCell<ObjChannel>>> {
+        self.unpack().try_as_obj_channel()
+    }
+
+    pub fn try_as_bounded_index(&self, bound: isize, msg: &str) -> Result<usize, Error> {
+        self.unpack().try_as_bounded_index(bound, msg)
+    }
+}
+
+impl Default for PackedValue {
+    fn default() -> Self {
+        PackedValue::new(Value::None)
+    }
+}
+
+impl From<Value> for PackedValue {
+    fn from(value: Value) -> Self {
+        PackedValue::new(value)
+    }
+}
+
+impl From<PackedValue> for Value {
+    fn from(value: PackedValue) -> Self {
+        value.unpack()
+    }
+}
+
+impl memory::GcManaged for PackedValue {
+    fn mark(&self) {
+        self.unpack().mark();
+    }
+}
+
+impl fmt::Display for PackedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.unpack())
+    }
+}
+
+impl PartialEq for PackedValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.unpack() == other.unpack()
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+mod nan_box {
+    use std::mem::{self, MaybeUninit};
+
+    use crate::value::Value;
+
+    // A value is boxed if its sign bit, exponent bits and quiet bit are all set. Every NaN is
+    // replaced by CANONICAL_NAN, which has its sign bit clear, when it's packed, so none of these
+    // bit patterns are used by numbers. That leaves the remaining 51 bits for a tag, identifying
+    // the type of value, and the address of the object being pointed to.
+    const BOXED: u64 = 0xfff8_0000_0000_0000;
+    const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+    const TAG_SHIFT: u32 = 45;
+    const TAG_MASK: u64 = 0x3f;
+    const ADDRESS_MASK: u64 = (1 << TAG_SHIFT) - 1;
+    // Objects are aligned to at least eight bytes, so the bottom three bits of their addresses
+    // don't need storing. This allows 48 bit addresses to fit in the 45 bits left after the tag.
+    const ADDRESS_SHIFT: u32 = 3;
+
+    // Objects are tagged with the discriminant of their variant of `Value`, so that they can be
+    // packed and unpacked without matching on the variant. `Value` has a primitive
+    // representation, so the discriminant is its first byte and the `Gc` pointer of each object
+    // variant immediately follows it, at the pointer's alignment.
+    const TAG_FALSE: u64 = 0x3d;
+    const TAG_TRUE: u64 = 0x3e;
+    const TAG_NONE: u64 = 0x3f;
+    const POINTER_OFFSET: usize = mem::align_of::<usize>();
+
+    #[inline(always)]
+    fn discriminant(value: &Value) -> u8 {
+        unsafe { *(value as *const Value as *const u8) }
+    }
+
+    pub(super) fn can_hold_address(address: u64) -> bool {
+        address.trailing_zeros() >= ADDRESS_SHIFT && address >> ADDRESS_SHIFT <= ADDRESS_MASK
+    }
+
+    #[inline(always)]
+    pub(super) fn pack(value: Value) -> u64 {
+        let (tag, address) = match value {
+            Value::Number(number) if number.is_nan() => return CANONICAL_NAN,
+            Value::Number(number) => return number.to_bits(),
+            Value::None => (TAG_NONE, 0),
+            Value::Boolean(false) => (TAG_FALSE, 0),
+            Value::Boolean(true) => (TAG_TRUE, 0),
+            _ => unsafe {
+                let address =
+                    *((&value as *const Value as *const u8).add(POINTER_OFFSET) as *const usize);
+                (discriminant(&value) as u64, address as u64)
+            },
+        };
+        debug_assert!(can_hold_address(address));
+        BOXED | tag << TAG_SHIFT | address >> ADDRESS_SHIFT
+    }
+
+    /// # Safety
+    /// The bits must have been produced by `pack`, and any object they refer to must still be
+    /// alive.
+    #[inline(always)]
+    pub(super) unsafe fn unpack(bits: u64) -> Value {
+        if bits & BOXED != BOXED {
+            return Value::Number(f64::from_bits(bits));
+        }
+        match (bits >> TAG_SHIFT) & TAG_MASK {
+            TAG_NONE => Value::None,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            tag => {
+                let address = ((bits & ADDRESS_MASK) << ADDRESS_SHIFT) as usize;
+                let mut value = MaybeUninit::<Value>::uninit();
+                let ptr = value.as_mut_ptr() as *mut u8;
+                *ptr = tag as u8;
+                *(ptr.add(POINTER_OFFSET) as *mut usize) = address;
+                value.assume_init()
+            }
+        }
+    }
+}
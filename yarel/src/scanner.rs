@@ -27,6 +27,7 @@ pub enum TokenKind {
     Comma,
     Dot,
     DotDot,
+    DotDotDot,
     Minus,
     MinusEqual,
     Plus,
@@ -41,6 +42,7 @@ pub enum TokenKind {
     BangEqual,
     Equal,
     EqualEqual,
+    EqualGreater,
     Greater,
     GreaterEqual,
     Less,
@@ -76,7 +78,10 @@ pub enum TokenKind {
     If,
     Import,
     As,
+    Async,
+    Await,
     In,
+    Match,
     Nil,
     Return,
     Self_,
@@ -88,6 +93,7 @@ pub enum TokenKind {
     Try,
     Var,
     While,
+    Yield,
     Error,
     Eof,
 }
@@ -201,19 +207,30 @@ impl Scanner {
             "," => self.make_token(TokenKind::Comma),
             "#" => self.make_token(TokenKind::Hash),
             "." => {
-                let match_char = self.match_char(".");
-                self.make_token(if match_char {
-                    TokenKind::DotDot
-                } else {
-                    TokenKind::Dot
-                })
+                let double_dot = self.match_char(".");
+                let triple_dot = double_dot && self.match_char(".");
+                let token_kind = match (double_dot, triple_dot) {
+                    (true, true) => TokenKind::DotDotDot,
+                    (true, false) => TokenKind::DotDot,
+                    _ => TokenKind::Dot,
+                };
+                self.make_token(token_kind)
             }
             "-" => self.binary_token(TokenKind::Minus, TokenKind::MinusEqual),
             "+" => self.binary_token(TokenKind::Plus, TokenKind::PlusEqual),
             "/" => self.binary_token(TokenKind::Slash, TokenKind::SlashEqual),
             "*" => self.binary_token(TokenKind::Star, TokenKind::StarEqual),
             "!" => self.binary_token(TokenKind::Bang, TokenKind::BangEqual),
-            "=" => self.binary_token(TokenKind::Equal, TokenKind::EqualEqual),
+            "=" => {
+                let token_kind = if self.match_char("=") {
+                    TokenKind::EqualEqual
+                } else if self.match_char(">") {
+                    TokenKind::EqualGreater
+                } else {
+                    TokenKind::Equal
+                };
+                self.make_token(token_kind)
+            }
             "<" => {
                 let double_less = self.match_char("<");
                 let equal = self.match_char("=");
@@ -375,7 +392,23 @@ impl Scanner {
     fn identifier_type(&self) -> TokenKind {
         let start = &self.source[self.start..self.start + 1];
         match start {
-            "a" => self.check_keyword(1, "s", TokenKind::As),
+            "a" => {
+                if self.current - self.start > 1 {
+                    let next = &self.source[self.start + 1..self.start + 2];
+                    return match next {
+                        "s" => {
+                            if self.current - self.start > 2 {
+                                self.check_keyword(2, "ync", TokenKind::Async)
+                            } else {
+                                TokenKind::As
+                            }
+                        }
+                        "w" => self.check_keyword(2, "ait", TokenKind::Await),
+                        _ => TokenKind::Identifier,
+                    };
+                }
+                TokenKind::Identifier
+            }
             "b" => self.check_keyword(1, "reak", TokenKind::Break),
             "c" => {
                 if self.current - self.start > 1 {
@@ -415,6 +448,7 @@ impl Scanner {
                 }
                 TokenKind::Identifier
             }
+            "m" => self.check_keyword(1, "atch", TokenKind::Match),
             "n" => self.check_keyword(1, "il", TokenKind::Nil),
             "r" => self.check_keyword(1, "eturn", TokenKind::Return),
             "S" => self.check_keyword(1, "elf", TokenKind::CapSelf),
@@ -453,6 +487,7 @@ impl Scanner {
             }
             "v" => self.check_keyword(1, "ar", TokenKind::Var),
             "w" => self.check_keyword(1, "hile", TokenKind::While),
+            "y" => self.check_keyword(1, "ield", TokenKind::Yield),
             _ => TokenKind::Identifier,
         }
     }
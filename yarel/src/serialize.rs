@@ -0,0 +1,321 @@
+/* Copyright 2020-2021 Matt Spraggs
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use crate::chunk::{self, Chunk, InlineCache};
+use crate::common;
+use crate::error::{Error, ErrorKind};
+use crate::memory::{Gc, Root};
+use crate::object::{ObjFunction, ObjString};
+use crate::value::Value;
+use crate::vm::Vm;
+
+const MAGIC: &[u8; 4] = b"YLC\0";
+
+/// The version of the bytecode format, which must be incremented whenever the encoding or the
+/// instruction set changes.
+pub const FORMAT_VERSION: u32 = 5;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+const FLAG_VARIADIC: u8 = 1;
+const FLAG_GENERATOR: u8 = 1 << 1;
+const FLAG_ASYNC: u8 = 1 << 2;
+
+/// Encodes a compiled function, along with the functions nested within it, as a `.ylc` file.
+pub fn serialize_function(function: &ObjFunction) -> Result<Vec<u8>, Error> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.write_u32(FORMAT_VERSION);
+    writer.write_function(function)?;
+    Ok(writer.bytes)
+}
+
+/// Checks whether the provided bytes start with a header that this version of the VM can load.
+pub fn is_compatible(bytes: &[u8]) -> bool {
+    bytes.len() >= 8
+        && &bytes[..4] == MAGIC
+        && u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) == FORMAT_VERSION
+}
+
+/// Decodes a function produced by `serialize_function` and verifies its bytecode. The module path
+/// plays the same role as the one passed to `compiler::compile`.
+pub fn deserialize_function(
+    vm: &mut Vm,
+    bytes: &[u8],
+    module_path: Option<&str>,
+) -> Result<Root<ObjFunction>, Error> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(invalid("missing header"));
+    }
+    if !is_compatible(bytes) {
+        return Err(error!(
+            ErrorKind::RuntimeError,
+            "Unsupported bytecode version (expected version {}).", FORMAT_VERSION
+        ));
+    }
+    let mut reader = Reader {
+        bytes,
+        offset: 8,
+        depth: 0,
+        strings: Vec::new(),
+        functions: Vec::new(),
+    };
+    let module_path = vm.new_root_obj_string(module_path.unwrap_or("main"));
+    let function = reader.read_function(vm, module_path.as_gc())?;
+    if reader.offset != bytes.len() {
+        return Err(invalid("unexpected data after function"));
+    }
+    chunk::verify(&function).map_err(|e| invalid(&e.to_string()))?;
+    Ok(function)
+}
+
+fn invalid(reason: &str) -> Error {
+    error!(ErrorKind::RuntimeError, "Invalid bytecode ({}).", reason)
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_len(&mut self, len: usize) -> Result<(), Error> {
+        let len = u32::try_from(len).map_err(|_| invalid("length too large"))?;
+        self.write_u32(len);
+        Ok(())
+    }
+
+    fn write_str(&mut self, string: &str) -> Result<(), Error> {
+        self.write_len(string.len())?;
+        self.bytes.extend_from_slice(string.as_bytes());
+        Ok(())
+    }
+
+    fn write_function(&mut self, function: &ObjFunction) -> Result<(), Error> {
+        self.write_str(function.name.as_str())?;
+        self.write_len(function.arity)?;
+        self.write_len(function.default_count)?;
+        self.write_len(function.upvalue_count)?;
+        let mut flags = 0;
+        if function.is_variadic {
+            flags |= FLAG_VARIADIC;
+        }
+        if function.is_generator {
+            flags |= FLAG_GENERATOR;
+        }
+        if function.is_async {
+            flags |= FLAG_ASYNC;
+        }
+        self.write_u8(flags);
+        self.write_len(function.parameters.len())?;
+        for parameter in &function.parameters {
+            self.write_str(parameter.as_str())?;
+        }
+        self.write_chunk(&function.chunk)
+    }
+
+    fn write_chunk(&mut self, chunk: &Chunk) -> Result<(), Error> {
+        self.write_len(chunk.code.len())?;
+        self.bytes.extend_from_slice(&chunk.code);
+        for &line in &chunk.lines {
+            self.bytes.extend_from_slice(&line.to_le_bytes());
+        }
+        self.write_len(chunk.constants.len())?;
+        for constant in &chunk.constants {
+            self.write_constant(constant.unpack())?;
+        }
+        // Inline caches start out empty, so only the number of them is needed.
+        self.write_len(chunk.inline_caches.len())
+    }
+
+    fn write_constant(&mut self, value: Value) -> Result<(), Error> {
+        match value {
+            Value::None => self.write_u8(TAG_NIL),
+            Value::Boolean(false) => self.write_u8(TAG_FALSE),
+            Value::Boolean(true) => self.write_u8(TAG_TRUE),
+            Value::Number(number) => {
+                self.write_u8(TAG_NUMBER);
+                self.bytes
+                    .extend_from_slice(&number.to_bits().to_le_bytes());
+            }
+            Value::ObjString(string) => {
+                self.write_u8(TAG_STRING);
+                self.write_str(string.as_str())?;
+            }
+            Value::ObjFunction(function) => {
+                self.write_u8(TAG_FUNCTION);
+                self.write_function(&function)?;
+            }
+            _ => {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Cannot serialize constant '{}'.", value
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    // The number of functions currently being read, which bounds the recursion into nested
+    // functions so that malformed input can't overflow the stack.
+    depth: usize,
+    // Everything allocated while reading is kept alive until the top-level function is returned.
+    strings: Vec<Root<ObjString>>,
+    functions: Vec<Root<ObjFunction>>,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_len(&mut self) -> Result<usize, Error> {
+        Ok(self.read_u32()? as usize)
+    }
+
+    fn read_string(&mut self, vm: &mut Vm) -> Result<Gc<ObjString>, Error> {
+        let len = self.read_len()?;
+        let bytes = self.read_bytes(len)?;
+        let string = std::str::from_utf8(bytes).map_err(|_| invalid("malformed string"))?;
+        let string = vm.new_root_obj_string(string);
+        let gc_string = string.as_gc();
+        self.strings.push(string);
+        Ok(gc_string)
+    }
+
+    fn read_function(
+        &mut self,
+        vm: &mut Vm,
+        module_path: Gc<ObjString>,
+    ) -> Result<Root<ObjFunction>, Error> {
+        if self.depth == common::FUNCTION_DEPTH_MAX {
+            return Err(invalid("functions nested too deeply"));
+        }
+        self.depth += 1;
+        let function = self.read_function_body(vm, module_path);
+        self.depth -= 1;
+        function
+    }
+
+    fn read_function_body(
+        &mut self,
+        vm: &mut Vm,
+        module_path: Gc<ObjString>,
+    ) -> Result<Root<ObjFunction>, Error> {
+        let name = self.read_string(vm)?;
+        let arity = self.read_len()?;
+        let default_count = self.read_len()?;
+        let upvalue_count = self.read_len()?;
+        let flags = self.read_u8()?;
+        let num_parameters = self.read_len()?;
+        let mut parameters = Vec::new();
+        for _ in 0..num_parameters {
+            parameters.push(self.read_string(vm)?);
+        }
+        let chunk = self.read_chunk(vm, module_path)?;
+        let chunk = vm.new_root_chunk(chunk);
+
+        let mut function = ObjFunction::new(name, arity, upvalue_count, chunk.as_gc(), module_path);
+        function.default_count = default_count;
+        function.is_variadic = flags & FLAG_VARIADIC != 0;
+        function.is_generator = flags & FLAG_GENERATOR != 0;
+        function.is_async = flags & FLAG_ASYNC != 0;
+        function.parameters = parameters;
+        let function = Root::new(function);
+        self.functions.push(function.clone());
+        Ok(function)
+    }
+
+    fn read_chunk(&mut self, vm: &mut Vm, module_path: Gc<ObjString>) -> Result<Chunk, Error> {
+        let mut chunk = Chunk::new();
+        let code_len = self.read_len()?;
+        if code_len == 0 {
+            return Err(invalid("empty chunk"));
+        }
+        chunk.code = self.read_bytes(code_len)?.to_vec();
+        let line_bytes = self.read_bytes(
+            code_len
+                .checked_mul(4)
+                .ok_or_else(|| invalid("chunk too large"))?,
+        )?;
+        chunk.lines = line_bytes
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let num_constants = self.read_len()?;
+        for _ in 0..num_constants {
+            let constant = self.read_constant(vm, module_path)?;
+            chunk.add_constant(constant);
+        }
+        let num_inline_caches = self.read_len()?;
+        if num_inline_caches > u16::MAX as usize + 1 {
+            return Err(invalid("too many inline caches"));
+        }
+        chunk.inline_caches = vec![InlineCache::default(); num_inline_caches];
+        Ok(chunk)
+    }
+
+    fn read_constant(&mut self, vm: &mut Vm, module_path: Gc<ObjString>) -> Result<Value, Error> {
+        let value = match self.read_u8()? {
+            TAG_NIL => Value::None,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NUMBER => {
+                let bytes = self.read_bytes(8)?;
+                let mut bits = [0; 8];
+                bits.copy_from_slice(bytes);
+                Value::Number(f64::from_bits(u64::from_le_bytes(bits)))
+            }
+            TAG_STRING => Value::ObjString(self.read_string(vm)?),
+            TAG_FUNCTION => Value::ObjFunction(self.read_function(vm, module_path)?.as_gc()),
+            _ => return Err(invalid("unknown constant type")),
+        };
+        Ok(value)
+    }
+}
@@ -96,12 +96,6 @@ where
             elem.mark();
         }
     }
-
-    fn blacken(&self) {
-        for elem in &self.stack[0..self.len()] {
-            elem.blacken();
-        }
-    }
 }
 
 impl<T, const N: usize> Default for Stack<T, N>
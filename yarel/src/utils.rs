@@ -44,3 +44,62 @@ pub(crate) fn hash_number(num: f64) -> u64 {
     hash = hash ^ hash.wrapping_shr(22);
     hash as u64
 }
+
+// The operations the VM applies to numbers. The constant folder uses the same functions, so folded
+// expressions produce the same values as they would at run time.
+
+pub(crate) fn greater(a: f64, b: f64) -> Value {
+    Value::Boolean(a > b)
+}
+
+pub(crate) fn less(a: f64, b: f64) -> Value {
+    Value::Boolean(a < b)
+}
+
+pub(crate) fn add(a: f64, b: f64) -> Value {
+    Value::Number(a + b)
+}
+
+pub(crate) fn subtract(a: f64, b: f64) -> Value {
+    Value::Number(a - b)
+}
+
+pub(crate) fn multiply(a: f64, b: f64) -> Value {
+    Value::Number(a * b)
+}
+
+pub(crate) fn divide(a: f64, b: f64) -> Value {
+    Value::Number(a / b)
+}
+
+pub(crate) fn modulo(a: f64, b: f64) -> Value {
+    Value::Number(a % b)
+}
+
+pub(crate) fn bitwise_and(a: f64, b: f64) -> Value {
+    Value::Number(((a as i64) & (b as i64)) as f64)
+}
+
+pub(crate) fn bitwise_or(a: f64, b: f64) -> Value {
+    Value::Number(((a as i64) | (b as i64)) as f64)
+}
+
+pub(crate) fn bitwise_xor(a: f64, b: f64) -> Value {
+    Value::Number(((a as i64) ^ (b as i64)) as f64)
+}
+
+pub(crate) fn shift_left(a: f64, b: f64) -> Value {
+    Value::Number((a as i64).checked_shl(b as u32).unwrap_or_default() as f64)
+}
+
+pub(crate) fn shift_right(a: f64, b: f64) -> Value {
+    Value::Number((a as i64).checked_shr(b as u32).unwrap_or_default() as f64)
+}
+
+pub(crate) fn negate(a: f64) -> Value {
+    Value::Number(-a)
+}
+
+pub(crate) fn bitwise_not(a: f64) -> Value {
+    Value::Number(!(a as i64) as f64)
+}
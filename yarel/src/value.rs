@@ -22,13 +22,16 @@ use crate::error::{Error, ErrorKind};
 use crate::hash::PassThroughHasher;
 use crate::memory::{self, Gc};
 use crate::object::{
-    ObjBoundMethod, ObjClass, ObjClosure, ObjFiber, ObjFunction, ObjHashMap, ObjInstance,
-    ObjModule, ObjNative, ObjRange, ObjRangeIter, ObjString, ObjStringIter, ObjTuple, ObjTupleIter,
-    ObjVec, ObjVecIter,
+    ObjBoundMethod, ObjChannel, ObjClass, ObjClosure, ObjFiber, ObjFunction, ObjHashMap,
+    ObjInstance, ObjModule, ObjNative, ObjRange, ObjRangeIter, ObjString, ObjStringIter, ObjTuple,
+    ObjTupleIter, ObjVec, ObjVecIter,
 };
 use crate::utils;
 
+// NaN-boxed values are tagged with the discriminant of the variant, which is only accessible with a
+// primitive representation.
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "nan_boxing", repr(u8))]
 pub enum Value {
     Boolean(bool),
     Number(f64),
@@ -50,6 +53,7 @@ pub enum Value {
     ObjHashMap(Gc<RefCell<ObjHashMap>>),
     ObjModule(Gc<RefCell<ObjModule>>),
     ObjFiber(Gc<RefCell<ObjFiber>>),
+    ObjChannel(Gc<RefCell<ObjChannel>>),
     None,
 }
 
@@ -200,6 +204,12 @@ impl Value {
             _ => None,
         }
     }
+    pub fn try_as_obj_channel(&self) -> Option<Gc<RefCell<ObjChannel>>> {
+        match self {
+            Value::ObjChannel(inner) => Some(*inner),
+            _ => None,
+        }
+    }
     pub fn try_as_bounded_index(&self, bound: isize, msg: &str) -> Result<usize, Error> {
         let mut index = utils::validate_integer(*self)?;
         if index < 0 {
@@ -240,30 +250,7 @@ impl memory::GcManaged for Value {
             Value::ObjHashMap(inner) => inner.mark(),
             Value::ObjModule(inner) => inner.mark(),
             Value::ObjFiber(inner) => inner.mark(),
-            _ => {}
-        }
-    }
-
-    fn blacken(&self) {
-        match self {
-            Value::ObjString(inner) => inner.blacken(),
-            Value::ObjStringIter(inner) => inner.blacken(),
-            Value::ObjFunction(inner) => inner.blacken(),
-            Value::ObjNative(inner) => inner.blacken(),
-            Value::ObjClosure(inner) => inner.blacken(),
-            Value::ObjClass(inner) => inner.blacken(),
-            Value::ObjInstance(inner) => inner.blacken(),
-            Value::ObjBoundMethod(inner) => inner.blacken(),
-            Value::ObjBoundNative(inner) => inner.blacken(),
-            Value::ObjTuple(inner) => inner.blacken(),
-            Value::ObjTupleIter(inner) => inner.blacken(),
-            Value::ObjVec(inner) => inner.blacken(),
-            Value::ObjVecIter(inner) => inner.blacken(),
-            Value::ObjRange(inner) => inner.blacken(),
-            Value::ObjRangeIter(inner) => inner.blacken(),
-            Value::ObjHashMap(inner) => inner.blacken(),
-            Value::ObjModule(inner) => inner.blacken(),
-            Value::ObjFiber(inner) => inner.blacken(),
+            Value::ObjChannel(inner) => inner.mark(),
             _ => {}
         }
     }
@@ -321,6 +308,9 @@ impl fmt::Display for Value {
             Value::ObjFiber(underlying) => {
                 write!(f, "<{} @ {:p}>", *underlying.borrow(), underlying.as_ptr())
             }
+            Value::ObjChannel(underlying) => {
+                write!(f, "<{} @ {:p}>", *underlying.borrow(), underlying.as_ptr())
+            }
             Value::None => write!(f, "nil"),
         }
     }
@@ -352,6 +342,7 @@ impl cmp::PartialEq for Value {
             }
             (Value::ObjModule(first), Value::ObjModule(second)) => *first == *second,
             (Value::ObjFiber(first), Value::ObjFiber(second)) => *first == *second,
+            (Value::ObjChannel(first), Value::ObjChannel(second)) => *first == *second,
             (Value::None, Value::None) => true,
             _ => false,
         }
@@ -379,6 +370,9 @@ impl Hash for Value {
             Value::ObjRange(r) => {
                 utils::hash_number(r.begin as f64) ^ utils::hash_number(r.end as f64)
             }
+            // Instances used as HashMap keys are resolved by the VM using their __hash__ and
+            // __eq__ methods, so within the map itself they're identified by address.
+            Value::ObjInstance(i) => utils::hash_number(f64::from_bits(i.as_ptr() as u64)),
             Value::None => 2_u64,
             _ => {
                 panic!("Unhashable value type: {}", self);
@@ -21,11 +21,15 @@ use std::fs;
 use std::hash::{Hash, Hasher};
 use std::hint;
 use std::io;
+use std::mem;
 use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time;
 
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, SequenceKind, UnpackRest};
 use crate::class_store::CoreClassStore;
 use crate::common;
 use crate::compiler;
@@ -33,18 +37,27 @@ use crate::core;
 use crate::debug;
 use crate::error::{Error, ErrorKind};
 use crate::hash::{BuildPassThroughHasher, FnvHasher};
-use crate::memory::{self, Gc, Root, UniqueRoot};
+use crate::memory::{self, Collector, Gc, GcStats, Root, UniqueRoot, WeakTable};
 use crate::object::{
-    self, NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjFiber, ObjFunction, ObjHashMap,
-    ObjInstance, ObjModule, ObjNative, ObjRange, ObjRangeIter, ObjString, ObjStringIter,
-    ObjStringValueMap, ObjTuple, ObjTupleIter, ObjUpvalue, ObjVec, ObjVecIter,
+    self, HashMapKey, NativeFn, ObjBoundMethod, ObjChannel, ObjClass, ObjClosure, ObjFiber,
+    ObjFunction, ObjHashMap, ObjInstance, ObjModule, ObjNative, ObjRange, ObjRangeIter, ObjString,
+    ObjStringIter, ObjStringValueMap, ObjTuple, ObjTupleIter, ObjUpvalue, ObjVec, ObjVecIter,
 };
+use crate::optimizer::OptimizationLevel;
+use crate::packed_value::PackedValue;
+use crate::serialize;
 use crate::utils;
 use crate::value::Value;
 
 const RANGE_CACHE_SIZE: usize = 8;
 
-type LoadModuleFn = fn(&str) -> Result<String, Error>;
+/// The contents of a module, as returned by a module loader.
+pub enum ModuleSource {
+    Source(String),
+    Bytecode(Vec<u8>),
+}
+
+type LoadModuleFn = fn(&str) -> Result<ModuleSource, Error>;
 
 pub fn interpret(vm: &mut Vm, source: String, module_path: Option<&str>) -> Result<Value, Error> {
     let compile_result = compiler::compile(vm, source, module_path);
@@ -54,6 +67,15 @@ pub fn interpret(vm: &mut Vm, source: String, module_path: Option<&str>) -> Resu
     }
 }
 
+pub fn interpret_bytecode(
+    vm: &mut Vm,
+    bytecode: &[u8],
+    module_path: Option<&str>,
+) -> Result<Value, Error> {
+    let function = serialize::deserialize_function(vm, bytecode, module_path)?;
+    vm.execute(function, &[])
+}
+
 struct ClassDef {
     class: UniqueRoot<ObjClass>,
     metaclass: UniqueRoot<ObjClass>,
@@ -65,8 +87,23 @@ impl ClassDef {
     }
 }
 
-fn default_read_module_source(path: &str) -> Result<String, Error> {
-    let path = Path::new(path).with_extension("yl");
+fn default_read_module_source(path: &str) -> Result<ModuleSource, Error> {
+    // Compiled bytecode is used in place of the source if it's at least as new as the source.
+    let bytecode_path = Path::new(path).with_extension("ylc");
+    let source_path = Path::new(path).with_extension("yl");
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    if let Some(bytecode_modified) = modified(&bytecode_path) {
+        let is_fresh = modified(&source_path).is_none_or(|m| m <= bytecode_modified);
+        if is_fresh {
+            if let Ok(bytecode) = fs::read(&bytecode_path) {
+                if serialize::is_compatible(&bytecode) {
+                    return Ok(ModuleSource::Bytecode(bytecode));
+                }
+            }
+        }
+    }
+
+    let path = source_path;
     let filename = match path.as_path().to_str() {
         Some(p) => p,
         None => {
@@ -108,27 +145,45 @@ fn default_read_module_source(path: &str) -> Result<String, Error> {
         }
     };
 
-    Ok(source)
+    Ok(ModuleSource::Source(source))
+}
+
+/// A handle that can be used to interrupt a running `Vm` from another thread. The interrupt is
+/// raised as an `InterruptError` the next time the VM loops or calls a function.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
 }
 
+/// Compiles and runs yarel code. Objects are allocated on the current thread's heap, which is
+/// shared with any other VMs on the same thread, so garbage collection settings and the memory
+/// limit apply to all of them.
 pub struct Vm {
     ip: *const u8,
     active_module: Gc<RefCell<ObjModule>>,
     active_chunk: Gc<Chunk>,
     fiber: Option<Root<RefCell<ObjFiber>>>,
     unsafe_fiber: *mut ObjFiber,
-    next_string: Gc<ObjString>,
-    class_store: CoreClassStore,
-    chunks: Vec<Root<Chunk>>,
+    next_string: Option<Root<ObjString>>,
+    pub(crate) class_store: CoreClassStore,
     modules: HashMap<Gc<ObjString>, Root<RefCell<ObjModule>>, BuildPassThroughHasher>,
-    core_chunks: Vec<Root<Chunk>>,
     string_class: Option<Root<ObjClass>>,
-    string_store: string_store::ObjStringStore,
+    string_store: Rc<RefCell<string_store::ObjStringStore>>,
     range_cache: Vec<(Root<ObjRange>, time::Instant)>,
     working_class_def: Option<ClassDef>,
     module_loader: LoadModuleFn,
     printer: NativeFn,
-    handling_exception: bool,
+    instruction_budget: Option<usize>,
+    fuel: usize,
+    interrupt_requested: Arc<AtomicBool>,
+    catchable_interrupts: bool,
+    optimization_level: OptimizationLevel,
 }
 
 impl Vm {
@@ -142,19 +197,25 @@ impl Vm {
             active_chunk: Gc::dangling(),
             fiber: None,
             unsafe_fiber: ptr::null_mut(),
-            next_string: Gc::dangling(),
+            next_string: None,
             class_store: CoreClassStore::new_empty(),
-            chunks: Vec::new(),
             modules: HashMap::with_hasher(BuildPassThroughHasher::default()),
-            core_chunks: Vec::new(),
             string_class: None,
-            string_store: string_store::ObjStringStore::new(),
+            string_store: Rc::new(RefCell::new(string_store::ObjStringStore::new())),
             range_cache: Vec::with_capacity(RANGE_CACHE_SIZE),
             module_loader: default_read_module_source,
             printer: core::print,
             working_class_def: None,
-            handling_exception: false,
+            instruction_budget: None,
+            fuel: 0,
+            interrupt_requested: Arc::new(AtomicBool::new(false)),
+            catchable_interrupts: false,
+            optimization_level: OptimizationLevel::Full,
         };
+        // Interned strings are held weakly, so the collector removes them from the store once
+        // they're unreachable.
+        let string_store: Rc<RefCell<dyn WeakTable>> = vm.string_store.clone();
+        memory::register_weak_table(Rc::downgrade(&string_store));
         vm.init_heap_allocated_data();
         vm
     }
@@ -165,17 +226,80 @@ impl Vm {
         vm
     }
 
+    /// As `with_built_ins`, but garbage on the current thread is collected using the provided
+    /// strategy.
+    pub fn with_thread_collector(collector: Collector) -> Self {
+        memory::set_collector(collector);
+        Self::with_built_ins()
+    }
+
     pub fn set_printer(&mut self, printer: NativeFn) {
         self.printer = printer;
         self.define_native("main", "print", self.printer);
     }
 
-    pub fn set_module_loader(&mut self, loader: fn(&str) -> Result<String, Error>) {
+    pub fn set_module_loader(&mut self, loader: fn(&str) -> Result<ModuleSource, Error>) {
         self.module_loader = loader;
     }
 
+    /// Limits the number of loop iterations and function calls that each call to `execute` may
+    /// perform. Exceeding the budget raises an `InterruptError`.
+    pub fn set_instruction_budget(&mut self, budget: Option<usize>) {
+        self.instruction_budget = budget;
+        self.fuel = budget.unwrap_or(0);
+    }
+
+    /// Limits the size of the live heap to the provided number of bytes. Allocations that leave
+    /// the heap over the limit after a collection raise a `MemoryError`.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        memory::set_memory_limit(limit);
+    }
+
+    /// Returns a summary of the collector's activity.
+    pub fn gc_stats(&self) -> GcStats {
+        memory::stats()
+    }
+
+    /// Performs a complete garbage collection.
+    pub fn collect_garbage(&mut self) {
+        memory::collect_garbage();
+    }
+
+    /// Sets the number of bytes the heap may grow to before the next collection starts.
+    pub fn set_collection_threshold(&mut self, bytes: usize) {
+        memory::set_collection_threshold(bytes);
+    }
+
+    /// Sets how far the heap may grow after a collection before the next one starts, as a
+    /// multiple of the size of the live heap. The factor must be at least one.
+    pub fn set_heap_growth_factor(&mut self, factor: f64) -> Result<(), Error> {
+        memory::set_heap_growth_factor(factor)
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            requested: self.interrupt_requested.clone(),
+        }
+    }
+
+    /// Determines whether an `InterruptError` can be caught by the script. When it can't, the
+    /// error skips any exception handlers and is returned straight to the host.
+    pub fn set_interrupts_catchable(&mut self, catchable: bool) {
+        self.catchable_interrupts = catchable;
+    }
+
+    /// Determines which optimisations the compiler applies to subsequently compiled code.
+    pub fn set_optimization_level(&mut self, level: OptimizationLevel) {
+        self.optimization_level = level;
+    }
+
+    pub(crate) fn optimization_level(&self) -> OptimizationLevel {
+        self.optimization_level
+    }
+
     pub fn execute(&mut self, function: Root<ObjFunction>, args: &[Value]) -> Result<Value, Error> {
         self.ip = ptr::null();
+        self.fuel = self.instruction_budget.unwrap_or(0);
         self.fiber = None;
         let module = self.module(&function.module_path);
         let closure = self.new_root_obj_closure(function.as_gc(), module);
@@ -189,10 +313,11 @@ impl Vm {
                 args.len()
             ));
         }
-        self.load_fiber(fiber.as_gc(), None)?;
+        self.load_fiber(fiber.as_gc(), None, false)?;
         for &arg in args {
             self.push(arg);
         }
+        debug_assert!(self.modules.len() == 1);
         match self.run() {
             Ok(value) => Ok(value),
             Err(mut error) => Err(self.runtime_error(&mut error)),
@@ -200,29 +325,25 @@ impl Vm {
     }
 
     pub fn global(&mut self, module_name: &str, var_name: &str) -> Option<Value> {
+        let module = self.module(module_name);
         let var_name = self.new_gc_obj_string(var_name);
-        self.module(module_name)
-            .borrow()
-            .attributes
-            .get(&var_name)
-            .copied()
+        let value = module.borrow().attributes.get(&var_name).copied();
+        value
     }
 
     pub fn set_global(&mut self, module_name: &str, var_name: &str, value: Value) {
+        let module = self.module(module_name);
         let var_name = self.new_gc_obj_string(var_name);
-        self.module(module_name)
-            .borrow_mut()
-            .attributes
-            .insert(var_name, value);
+        module.borrow_mut().attributes.insert(var_name, value);
     }
 
     pub fn define_native(&mut self, module_name: &str, var_name: &str, function: NativeFn) {
-        let var_name = self.new_gc_obj_string(var_name);
-        let native = self.new_root_obj_native(var_name, function);
+        let var_name = self.new_root_obj_string(var_name);
+        let native = self.new_root_obj_native(var_name.as_gc(), function);
         self.module(module_name)
             .borrow_mut()
             .attributes
-            .insert(var_name, Value::ObjNative(native.as_gc()));
+            .insert(var_name.as_gc(), Value::ObjNative(native.as_gc()));
     }
 
     pub fn get_class(&self, value: Value) -> Gc<ObjClass> {
@@ -253,10 +374,23 @@ impl Vm {
             Value::ObjHashMap(hash_map) => hash_map.borrow().class,
             Value::ObjModule(module) => module.borrow().class,
             Value::ObjFiber(fiber) => fiber.borrow().class,
+            Value::ObjChannel(channel) => channel.borrow().class,
             Value::None => self.class_store.nil_class(),
         }
     }
 
+    /// Checks whether the class of the provided value is the provided class or derives from it.
+    pub fn value_derives(&self, value: Value, class: Gc<ObjClass>) -> bool {
+        let mut current = Some(self.get_class(value));
+        while let Some(c) = current {
+            if c == class {
+                return true;
+            }
+            current = c.superclass;
+        }
+        false
+    }
+
     pub fn new_gc_obj_string(&mut self, data: &str) -> Gc<ObjString> {
         let hash = {
             let mut hasher = FnvHasher::new();
@@ -264,20 +398,29 @@ impl Vm {
             hasher.finish()
         };
         let key = (hash, data);
-        if let Some(string) = self.string_store.get(key) {
-            return string.as_gc();
+        if let Some(string) = self.string_store.borrow().get(key) {
+            return string;
         }
-        let string = Root::new(ObjString::new(
-            self.string_class.as_ref().expect("Expected Root.").as_gc(),
-            data,
-            hash,
-        ));
+        // The names of the core classes are created before the string class, so they're given
+        // their class once it exists.
+        let class = self
+            .string_class
+            .as_ref()
+            .map_or(Gc::dangling(), |class| class.as_gc());
+        let string = Root::new(ObjString::new(class, data, hash));
         let ret = string.as_gc();
-        self.string_store.insert(string);
+        self.string_store.borrow_mut().insert(ret);
         ret
     }
 
-    pub fn new_root_obj_upvalue(&mut self, value: &mut Value) -> Root<RefCell<ObjUpvalue>> {
+    /// As `new_gc_obj_string`, but the string is rooted. Interned strings aren't kept alive by
+    /// the VM, so this should be used whenever a string has to survive a further allocation
+    /// before it's stored somewhere the collector can see.
+    pub fn new_root_obj_string(&mut self, data: &str) -> Root<ObjString> {
+        self.new_gc_obj_string(data).as_root()
+    }
+
+    pub fn new_root_obj_upvalue(&mut self, value: &mut PackedValue) -> Root<RefCell<ObjUpvalue>> {
         Root::new(RefCell::new(ObjUpvalue::new(value)))
     }
 
@@ -408,9 +551,22 @@ impl Vm {
         Root::new(RefCell::new(ObjFiber::new(class, closure)))
     }
 
+    pub(crate) fn new_root_obj_channel(
+        &mut self,
+        capacity: Option<usize>,
+    ) -> Root<RefCell<ObjChannel>> {
+        let class = self.class_store.channel_class();
+        Root::new(RefCell::new(ObjChannel::new(class, capacity)))
+    }
+
+    pub(crate) fn new_channel_wait(&mut self) -> Result<Value, Error> {
+        let class = self.class_store.channel_wait_class();
+        let new_string = self.class_store.new_name();
+        self.invoke_method(Value::ObjClass(class), new_string, &[])
+    }
+
     pub fn reset(&mut self) {
         self.reset_stack();
-        self.chunks = self.core_chunks.clone();
         self.modules.retain(|&k, _| k.as_str() == "main");
         self.active_module = self.module("main");
         self.active_module.borrow_mut().attributes = object::new_obj_string_value_map();
@@ -418,25 +574,25 @@ impl Vm {
     }
 
     pub(crate) fn module(&mut self, path: &str) -> Gc<RefCell<ObjModule>> {
-        let path = self.new_gc_obj_string(path);
-        if let Some(module) = self.modules.get(&path) {
+        let path = self.new_root_obj_string(path);
+        if let Some(module) = self.modules.get(&path.as_gc()) {
             return module.as_gc();
         }
         let module = Root::new(RefCell::new(ObjModule::new(
             self.class_store.module_class(),
-            path,
+            path.as_gc(),
         )));
         let gc_module = module.as_gc();
-        self.modules.insert(path, module);
+        self.modules.insert(path.as_gc(), module);
         gc_module
     }
 
     pub fn peek(&self, depth: usize) -> Value {
-        *self.active_fiber().stack.peek(depth)
+        self.active_fiber().stack.peek(depth).unpack()
     }
 
     pub(crate) fn push(&mut self, value: Value) {
-        self.active_fiber_mut().stack.push(value)
+        self.active_fiber_mut().stack.push(PackedValue::new(value))
     }
 
     pub(crate) fn pop(&mut self) -> Value {
@@ -444,22 +600,27 @@ impl Vm {
             .stack
             .pop()
             .expect("Expected Value.")
+            .unpack()
     }
 
-    pub(crate) fn add_chunk(&mut self, chunk: Chunk) -> Gc<Chunk> {
-        let root = Root::new(chunk);
-        let ret = root.as_gc();
-        self.chunks.push(root);
-        ret
+    pub(crate) fn new_root_chunk(&mut self, chunk: Chunk) -> Root<Chunk> {
+        Root::new(chunk)
     }
 
     pub(crate) fn load_fiber(
         &mut self,
         fiber: Gc<RefCell<ObjFiber>>,
         arg: Option<Value>,
+        is_try: bool,
     ) -> Result<(), Error> {
         {
             let borrowed_fiber = fiber.borrow();
+            if borrowed_fiber.error.is_some() {
+                return Err(error!(
+                    ErrorKind::RuntimeError,
+                    "Cannot call a fiber that has failed."
+                ));
+            }
             if borrowed_fiber.has_finished() {
                 return Err(error!(
                     ErrorKind::RuntimeError,
@@ -472,6 +633,12 @@ impl Vm {
                     "Cannot call a fiber that has already been called.",
                 ));
             }
+            if borrowed_fiber.is_blocked {
+                return Err(error!(
+                    ErrorKind::RuntimeError,
+                    "Cannot call a fiber that is blocked on a channel."
+                ));
+            }
         }
         if self.fiber.is_some() {
             self.active_fiber_mut().current_frame_mut().unwrap().ip = self.ip;
@@ -479,17 +646,21 @@ impl Vm {
 
         self.unsafe_fiber = (*fiber).as_ptr();
         let caller = self.fiber.replace(fiber.as_root());
-        self.active_fiber_mut().caller = caller.map(|p| p.as_gc());
+        {
+            let mut borrowed_fiber = fiber.borrow_mut();
+            borrowed_fiber.caller = caller.map(|p| p.as_gc());
+            borrowed_fiber.is_try = is_try;
+        }
 
         if self.active_fiber().is_new() {
             let closure = self.active_fiber().frames[0].closure;
             self.push(Value::ObjClosure(closure));
             if let Some(arg) = arg {
-                self.push(Value::None);
                 self.push(arg);
             }
         } else if let Some(arg) = arg {
-            self.push(Value::None);
+            // A suspended fiber has the slot for the result of the call that suspended it on top
+            // of its stack.
             self.poke(0, arg);
         }
 
@@ -497,7 +668,13 @@ impl Vm {
         Ok(())
     }
 
-    pub(crate) fn unload_fiber(&mut self, arg: Option<Value>) -> Result<(), Error> {
+    pub(crate) fn unload_fiber(&mut self, value: Value) -> Result<(), Error> {
+        if !self.active_fiber().call_barriers.is_empty() {
+            return Err(error!(
+                ErrorKind::RuntimeError,
+                "Cannot yield from a fiber during a call from a native function."
+            ));
+        }
         if !self.active_fiber().has_finished() {
             self.active_fiber_mut().current_frame_mut().unwrap().ip = self.ip;
         }
@@ -505,145 +682,411 @@ impl Vm {
         if let Some(caller) = caller {
             let mut current = self.fiber.replace(caller.as_root());
             self.unsafe_fiber = (*caller).as_ptr();
-            current.as_mut().unwrap().borrow_mut().caller = None;
+            let mut borrowed_current = current.as_mut().unwrap().borrow_mut();
+            borrowed_current.caller = None;
+            borrowed_current.is_try = false;
         } else {
             return Err(error!(
                 ErrorKind::RuntimeError,
                 "Cannot yield from module-level code."
             ));
         }
-        if let Some(arg) = arg {
-            self.poke(0, arg);
-        } else {
-            self.pop();
-            self.poke(0, Value::None);
-        }
+        self.poke(0, value);
         self.load_frame();
         Ok(())
     }
 
-    fn run(&mut self) -> Result<Value, Error> {
-        debug_assert!(self.modules.len() == 1);
+    /// Invokes the named method on the provided receiver, running the VM until the method
+    /// returns. This allows native functions to call back into yarel code. Any error returned must
+    /// be propagated back to the VM so that the original exception object can be rethrown.
+    pub(crate) fn invoke_method(
+        &mut self,
+        receiver: Value,
+        name: Gc<ObjString>,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        self.push(receiver);
+        for &arg in args {
+            self.push(arg);
+        }
+        self.run_nested(args.len(), |vm| vm.invoke(name, args.len(), None))
+    }
+
+    /// Resolves the provided value to a key for the provided HashMap. Instances are hashed
+    /// using their `__hash__` method and compared to existing keys with the same hash using
+    /// their `__eq__` method, so an equal key that's already in the map is returned in place of
+    /// the provided value.
+    pub(crate) fn hash_map_key(
+        &mut self,
+        hash_map: Gc<RefCell<ObjHashMap>>,
+        key: Value,
+    ) -> Result<HashMapKey, Error> {
+        if key.has_hash() {
+            return Ok(HashMapKey {
+                value: key,
+                instance_hash: None,
+            });
+        }
+        let hash_name = self.class_store.hash_name();
+        if self.operator_class(key, hash_name).is_none() {
+            return Err(error!(
+                ErrorKind::ValueError,
+                "Cannot use unhashable value '{}' as HashMap key.", key
+            ));
+        }
+
+        let hash = match self.invoke_method(key, hash_name, &[])? {
+            Value::Number(n) => utils::hash_number(n),
+            value => {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Expected '__hash__' to return a number but found '{}'.", value
+                ));
+            }
+        };
+        let eq_name = self.class_store.eq_name();
+        let has_eq = self.operator_class(key, eq_name).is_some();
+        let candidates = hash_map.borrow().instance_keys(hash);
+        for candidate in candidates {
+            if candidate == key
+                || (has_eq && self.invoke_method(key, eq_name, &[candidate])?.as_bool())
+            {
+                return Ok(HashMapKey {
+                    value: candidate,
+                    instance_hash: Some(hash),
+                });
+            }
+        }
+        Ok(HashMapKey {
+            value: key,
+            instance_hash: Some(hash),
+        })
+    }
+
+    /// Converts the provided value to a string, deferring to the `__str__` method of instances
+    /// that define one. Instances nested within a `Vec`, `Tuple` or `HashMap` are converted using
+    /// `__repr__`. These methods are run by the VM, so this should only be called from within a
+    /// native function.
+    pub fn format_value(&mut self, value: Value) -> Result<Gc<ObjString>, Error> {
+        if let Some(string) = value.try_as_obj_string() {
+            return Ok(string);
+        }
+        let mut buffer = String::new();
+        self.write_value(&mut buffer, value, false, &mut Vec::new())?;
+        Ok(self.new_gc_obj_string(&buffer))
+    }
+
+    fn run_nested(
+        &mut self,
+        arg_count: usize,
+        call: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<Value, Error> {
+        // The frame count at the point of the call acts as a barrier. Returning to it stops the
+        // nested run loop, and exceptions that aren't handled above it are passed back to the
+        // caller as errors.
+        let fiber = self.fiber.as_ref().unwrap().as_gc();
+        let stack_size = self.stack_size() - arg_count - 1;
+        let frame_count = {
+            let mut active_fiber = self.active_fiber_mut();
+            let frame_count = active_fiber.frames.len();
+            active_fiber.call_barriers.push(frame_count);
+            active_fiber.nested_exception = None;
+            frame_count
+        };
+        self.active_fiber_mut().current_frame_mut().unwrap().ip = self.ip;
+
+        let result = call(self).and_then(|_| {
+            if self.unsafe_fiber == (*fiber).as_ptr() && self.active_fiber().at_call_barrier() {
+                Ok(self.pop())
+            } else {
+                self.run()
+            }
+        });
+
+        if self.unsafe_fiber != (*fiber).as_ptr() {
+            self.unsafe_fiber = (*fiber).as_ptr();
+            self.fiber = Some(fiber.as_root());
+        }
+        self.active_fiber_mut().call_barriers.pop();
+        if result.is_err() {
+            {
+                let mut active_fiber = self.active_fiber_mut();
+                active_fiber.close_upvalues(stack_size);
+                active_fiber.stack.truncate(stack_size);
+                active_fiber.frames.truncate(frame_count);
+                active_fiber
+                    .exc_handlers
+                    .retain(|handler| handler.frame_count <= frame_count);
+                active_fiber.error_ip = None;
+            }
+            self.load_frame();
+        }
+        result
+    }
+
+    fn write_value(
+        &mut self,
+        buffer: &mut String,
+        value: Value,
+        is_element: bool,
+        containers: &mut Vec<*const ()>,
+    ) -> Result<(), Error> {
+        // Containers are tracked by address so that self-referential values are rendered in the
+        // same way as the Display implementations for Vec, Tuple and HashMap.
+        match value {
+            Value::ObjInstance(_) => {
+                let method_names = [self.class_store.str_name(), self.class_store.repr_name()];
+                let method_names = if is_element {
+                    &method_names[1..]
+                } else {
+                    &method_names[..]
+                };
+                for &name in method_names {
+                    if self.operator_class(value, name).is_none() {
+                        continue;
+                    }
+                    let result = self.invoke_method(value, name, &[])?;
+                    return match result.try_as_obj_string() {
+                        Some(string) => {
+                            buffer.push_str(string.as_str());
+                            Ok(())
+                        }
+                        None => Err(error!(
+                            ErrorKind::TypeError,
+                            "Expected '{}' to return a string but found '{}'.", *name, result
+                        )),
+                    };
+                }
+                write!(buffer, "{}", value).expect("Unable to write value to buffer.");
+            }
+            Value::ObjVec(vec) => {
+                let address = vec.as_ptr() as *const ();
+                if containers.contains(&address) {
+                    buffer.push_str("[...]");
+                    return Ok(());
+                }
+                containers.push(address);
+                buffer.push('[');
+                let mut i = 0;
+                while let Some(element) = vec.borrow().elements.get(i).map(|e| e.unpack()) {
+                    if i > 0 {
+                        buffer.push_str(", ");
+                    }
+                    self.write_value(buffer, element, true, containers)?;
+                    i += 1;
+                }
+                buffer.push(']');
+                containers.pop();
+            }
+            Value::ObjTuple(tuple) => {
+                let address = tuple.as_ptr() as *const ();
+                if containers.contains(&address) {
+                    buffer.push_str("(...)");
+                    return Ok(());
+                }
+                containers.push(address);
+                buffer.push('(');
+                for (i, &element) in tuple.elements.iter().enumerate() {
+                    if i > 0 {
+                        buffer.push_str(", ");
+                    }
+                    self.write_value(buffer, element, true, containers)?;
+                }
+                if tuple.elements.len() == 1 {
+                    buffer.push(',');
+                }
+                buffer.push(')');
+                containers.pop();
+            }
+            Value::ObjHashMap(map) => {
+                let address = map.as_ptr() as *const ();
+                if containers.contains(&address) {
+                    buffer.push_str("{...}");
+                    return Ok(());
+                }
+                containers.push(address);
+                buffer.push('{');
+                let elements = map
+                    .borrow()
+                    .elements
+                    .iter()
+                    .map(|(&k, &v)| (k, v))
+                    .collect::<Vec<_>>();
+                for (i, (key, value)) in elements.into_iter().enumerate() {
+                    if i > 0 {
+                        buffer.push_str(", ");
+                    }
+                    self.write_value(buffer, key, true, containers)?;
+                    buffer.push_str(": ");
+                    self.write_value(buffer, value, true, containers)?;
+                }
+                buffer.push('}');
+                containers.pop();
+            }
+            _ => write!(buffer, "{}", value).expect("Unable to write value to buffer."),
+        }
+        Ok(())
+    }
 
+    fn run(&mut self) -> Result<Value, Error> {
         loop {
             if cfg!(feature = "debug_trace") {
                 println!("          {}", self.active_fiber().stack);
                 let offset = self.active_chunk.code_offset(self.ip);
                 debug::disassemble_instruction(&self.active_chunk, offset);
             }
-            let byte = self.read_byte();
+            let opcode = self.read_opcode();
 
-            match byte {
-                byte if byte == OpCode::Constant as u8 => {
+            match opcode {
+                OpCode::Constant => {
                     let constant = self.read_constant();
                     self.push(constant);
                 }
-                byte if byte == OpCode::Nil as u8 => self.push(Value::None),
-                byte if byte == OpCode::True as u8 => self.push(Value::Boolean(true)),
-                byte if byte == OpCode::False as u8 => self.push(Value::Boolean(false)),
-                byte if byte == OpCode::Pop as u8 => {
+                OpCode::Nil => self.push(Value::None),
+                OpCode::True => self.push(Value::Boolean(true)),
+                OpCode::False => self.push(Value::Boolean(false)),
+                OpCode::Pop => {
                     self.pop();
                 }
-                byte if byte == OpCode::CopyTop as u8 => {
+                OpCode::CopyTop => {
                     let top = self.peek(0);
                     self.push(top);
                 }
-                byte if byte == OpCode::GetLocal as u8 => self.get_local_impl(),
-                byte if byte == OpCode::SetLocal as u8 => self.set_local_impl(),
-                byte if byte == OpCode::GetGlobal as u8 => self.get_global_impl()?,
-                byte if byte == OpCode::DefineGlobal as u8 => self.define_global_impl(),
-                byte if byte == OpCode::SetGlobal as u8 => self.set_global_impl()?,
-                byte if byte == OpCode::GetUpvalue as u8 => self.get_upvalue_impl(),
-                byte if byte == OpCode::SetUpvalue as u8 => self.set_upvalue_impl(),
-                byte if byte == OpCode::GetProperty as u8 => self.get_property_impl()?,
-                byte if byte == OpCode::SetProperty as u8 => self.set_property_impl()?,
-                byte if byte == OpCode::GetClass as u8 => self.get_class_impl(),
-                byte if byte == OpCode::GetSuper as u8 => self.get_super_impl()?,
-                byte if byte == OpCode::Equal as u8 => self.equal_impl(),
-                byte if byte == OpCode::Greater as u8 => {
-                    self.binary_op_impl(|a, b| Value::Boolean(a > b))?;
-                }
-                byte if byte == OpCode::Less as u8 => {
-                    self.binary_op_impl(|a, b| Value::Boolean(a < b))?;
-                }
-                byte if byte == OpCode::Add as u8 => self.add_impl()?,
-                byte if byte == OpCode::Subtract as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(a - b))?
-                }
-                byte if byte == OpCode::Multiply as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(a * b))?
-                }
-                byte if byte == OpCode::Divide as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(a / b))?
-                }
-                byte if byte == OpCode::BitwiseAnd as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(((a as i64) & (b as i64)) as f64))?;
-                }
-                byte if byte == OpCode::BitwiseOr as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(((a as i64) | (b as i64)) as f64))?;
-                }
-                byte if byte == OpCode::BitwiseXor as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(((a as i64) ^ (b as i64)) as f64))?;
-                }
-                byte if byte == OpCode::Modulo as u8 => {
-                    self.binary_op_impl(|a, b| Value::Number(a % b))?;
-                }
-                byte if byte == OpCode::LogicalNot as u8 => self.logical_not_impl(),
-                byte if byte == OpCode::BitwiseNot as u8 => self.bitwise_not_impl()?,
-                byte if byte == OpCode::BitShiftLeft as u8 => {
-                    self.binary_op_impl(|a, b| {
-                        Value::Number((a as i64).checked_shl(b as u32).unwrap_or_default() as f64)
-                    })?;
-                }
-                byte if byte == OpCode::BitShiftRight as u8 => {
-                    self.binary_op_impl(|a, b| {
-                        Value::Number((a as i64).checked_shr(b as u32).unwrap_or_default() as f64)
-                    })?;
-                }
-                byte if byte == OpCode::Negate as u8 => self.negate_impl()?,
-                byte if byte == OpCode::FormatString as u8 => self.format_string_impl(),
-                byte if byte == OpCode::BuildHashMap as u8 => self.build_hash_map_impl()?,
-                byte if byte == OpCode::BuildRange as u8 => self.build_range_impl()?,
-                byte if byte == OpCode::BuildString as u8 => self.build_string_impl(),
-                byte if byte == OpCode::BuildTuple as u8 => self.build_tuple_impl(),
-                byte if byte == OpCode::BuildVec as u8 => self.build_vec_impl(),
-                byte if byte == OpCode::IterNext as u8 => self.iter_next_impl()?,
-                byte if byte == OpCode::Jump as u8 => self.jump_impl(),
-                byte if byte == OpCode::JumpIfFalse as u8 => self.jump_if_false_impl(),
-                byte if byte == OpCode::JumpIfStopIter as u8 => self.jump_if_stop_iter(),
-                byte if byte == OpCode::Loop as u8 => self.loop_impl(),
-                byte if byte == OpCode::JumpFinally as u8 => self.jump_finally_impl(),
-                byte if byte == OpCode::EndFinally as u8 => self.end_finally_impl()?,
-                byte if byte == OpCode::PushExcHandler as u8 => self.push_exc_handler_impl(),
-                byte if byte == OpCode::PopExcHandler as u8 => self.pop_exc_handler_impl(),
-                byte if byte == OpCode::Throw as u8 => self.throw_impl()?,
-                byte if byte == OpCode::Call as u8 => self.call_impl()?,
-                byte if byte == OpCode::Construct as u8 => self.construct_impl(),
-                byte if byte == OpCode::Invoke as u8 => self.invoke_impl()?,
-                byte if byte == OpCode::SuperInvoke as u8 => self.super_invoke_impl()?,
-                byte if byte == OpCode::Closure as u8 => self.closure_impl(),
-                byte if byte == OpCode::CloseUpvalue as u8 => self.close_upvalue_impl(),
-                byte if byte == OpCode::Return as u8 => {
+                OpCode::GetLocal => self.get_local_impl(),
+                OpCode::SetLocal => self.set_local_impl(),
+                OpCode::GetGlobal => self.get_global_impl()?,
+                OpCode::DefineGlobal => self.define_global_impl(),
+                OpCode::SetGlobal => self.set_global_impl()?,
+                OpCode::GetUpvalue => self.get_upvalue_impl(),
+                OpCode::SetUpvalue => self.set_upvalue_impl(),
+                OpCode::GetProperty => self.get_property_impl()?,
+                OpCode::SetProperty => self.set_property_impl()?,
+                OpCode::GetClass => self.get_class_impl(),
+                OpCode::GetSuper => self.get_super_impl()?,
+                OpCode::Equal => self.equal_impl()?,
+                OpCode::Greater => self.binary_op_impl(
+                    utils::greater,
+                    (self.class_store.gt_name(), self.class_store.lt_name()),
+                )?,
+                OpCode::Less => self.binary_op_impl(
+                    utils::less,
+                    (self.class_store.lt_name(), self.class_store.gt_name()),
+                )?,
+                OpCode::Add => self.add_impl()?,
+                OpCode::Subtract => self.binary_op_impl(
+                    utils::subtract,
+                    (self.class_store.sub_name(), self.class_store.rsub_name()),
+                )?,
+                OpCode::Multiply => self.binary_op_impl(
+                    utils::multiply,
+                    (self.class_store.mul_name(), self.class_store.rmul_name()),
+                )?,
+                OpCode::Divide => self.binary_op_impl(
+                    utils::divide,
+                    (self.class_store.div_name(), self.class_store.rdiv_name()),
+                )?,
+                OpCode::BitwiseAnd => self.binary_op_impl(
+                    utils::bitwise_and,
+                    (self.class_store.and_name(), self.class_store.rand_name()),
+                )?,
+                OpCode::BitwiseOr => self.binary_op_impl(
+                    utils::bitwise_or,
+                    (self.class_store.or_name(), self.class_store.ror_name()),
+                )?,
+                OpCode::BitwiseXor => self.binary_op_impl(
+                    utils::bitwise_xor,
+                    (self.class_store.xor_name(), self.class_store.rxor_name()),
+                )?,
+                OpCode::Modulo => self.binary_op_impl(
+                    utils::modulo,
+                    (self.class_store.mod_name(), self.class_store.rmod_name()),
+                )?,
+                OpCode::LogicalNot => self.logical_not_impl(),
+                OpCode::BitwiseNot => self.bitwise_not_impl()?,
+                OpCode::BitShiftLeft => self.binary_op_impl(
+                    utils::shift_left,
+                    (
+                        self.class_store.lshift_name(),
+                        self.class_store.rlshift_name(),
+                    ),
+                )?,
+                OpCode::BitShiftRight => self.binary_op_impl(
+                    utils::shift_right,
+                    (
+                        self.class_store.rshift_name(),
+                        self.class_store.rrshift_name(),
+                    ),
+                )?,
+                OpCode::Negate => self.negate_impl()?,
+                OpCode::FormatString => self.format_string_impl()?,
+                OpCode::BuildHashMap => self.build_hash_map_impl()?,
+                OpCode::BuildRange => self.build_range_impl()?,
+                OpCode::BuildString => self.build_string_impl(),
+                OpCode::BuildTuple => self.build_tuple_impl(),
+                OpCode::BuildVec => self.build_vec_impl(),
+                OpCode::Extend => self.extend_impl()?,
+                OpCode::Unpack => self.unpack_impl()?,
+                OpCode::MatchSequence => self.match_sequence_impl(),
+                OpCode::MatchRange => self.match_range_impl(),
+                OpCode::MatchField => self.match_field_impl(),
+                OpCode::IterNext => self.iter_next_impl()?,
+                OpCode::Jump => self.jump_impl(),
+                OpCode::JumpIfFalse => self.jump_if_false_impl(),
+                OpCode::JumpIfStopIter => self.jump_if_stop_iter(),
+                OpCode::Loop => self.loop_impl()?,
+                OpCode::JumpFinally => self.jump_finally_impl(),
+                OpCode::EndFinally => self.end_finally_impl()?,
+                OpCode::PushExcHandler => self.push_exc_handler_impl(),
+                OpCode::PopExcHandler => self.pop_exc_handler_impl(),
+                OpCode::Throw => self.throw_impl()?,
+                OpCode::Rethrow => self.rethrow_impl(),
+                OpCode::Call => self.call_impl()?,
+                OpCode::CallKw => self.call_kw_impl()?,
+                OpCode::CallSpread => self.call_spread_impl()?,
+                OpCode::Construct => self.construct_impl(),
+                OpCode::Invoke => self.invoke_impl()?,
+                OpCode::InvokeKw => self.invoke_kw_impl()?,
+                OpCode::InvokeSpread => self.invoke_spread_impl()?,
+                OpCode::SuperInvoke => self.super_invoke_impl()?,
+                OpCode::SuperInvokeKw => self.super_invoke_kw_impl()?,
+                OpCode::SuperInvokeSpread => self.super_invoke_spread_impl()?,
+                OpCode::Closure => self.closure_impl(),
+                OpCode::CloseUpvalue => self.close_upvalue_impl(),
+                OpCode::Return => {
                     if let Some(value) = self.return_impl()? {
                         return Ok(value);
                     }
-                }
-                byte if byte == OpCode::DeclareClass as u8 => self.declare_class_impl(),
-                byte if byte == OpCode::DefineClass as u8 => self.define_class_impl(),
-                byte if byte == OpCode::Inherit as u8 => self.inherit_impl()?,
-                byte if byte == OpCode::Method as u8 => self.method_impl()?,
-                byte if byte == OpCode::StaticMethod as u8 => self.static_method_impl()?,
-                byte if byte == OpCode::StartImport as u8 => self.start_import_impl()?,
-                byte if byte == OpCode::FinishImport as u8 => self.finish_import_impl(),
-                _ => {
-                    if cfg!(any(debug_assertions, feature = "safe_vm_opcodes")) {
-                        panic!("Unknown opcode {}", byte);
-                    } else {
-                        unsafe {
-                            hint::unreachable_unchecked();
-                        }
+                    if self.active_fiber().at_call_barrier() {
+                        return Ok(self.pop());
                     }
                 }
+                OpCode::Yield => self.yield_impl()?,
+                OpCode::Await => self.await_impl()?,
+                OpCode::DeclareClass => self.declare_class_impl(),
+                OpCode::DefineClass => self.define_class_impl()?,
+                OpCode::Inherit => self.inherit_impl()?,
+                OpCode::Method => self.method_impl()?,
+                OpCode::StaticMethod => self.static_method_impl()?,
+                OpCode::StartImport => self.start_import_impl()?,
+                OpCode::FinishImport => self.finish_import_impl(),
+                OpCode::GetLocals => self.get_locals_impl(),
+                OpCode::GetLocalConstant => self.get_local_constant_impl(),
+                OpCode::AddLocals => self.add_locals_impl()?,
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn read_opcode(&mut self) -> OpCode {
+        let byte = self.read_byte();
+        match OpCode::from_byte(byte) {
+            Some(opcode) => opcode,
+            None => {
+                if cfg!(any(debug_assertions, feature = "safe_vm_opcodes")) {
+                    panic!("Unknown opcode {}", byte);
+                } else {
+                    unsafe { hint::unreachable_unchecked() }
+                }
             }
         }
     }
@@ -658,7 +1101,7 @@ impl Vm {
 
     fn read_short(&mut self) -> u16 {
         unsafe {
-            let ret = u16::from_ne_bytes([*self.ip, *self.ip.offset(1)]);
+            let ret = u16::from_le_bytes([*self.ip, *self.ip.offset(1)]);
             self.ip = self.ip.offset(2);
             ret
         }
@@ -666,7 +1109,7 @@ impl Vm {
 
     fn read_constant(&mut self) -> Value {
         let index = self.read_short() as usize;
-        self.active_chunk.constants[index]
+        self.active_chunk.constants[index].unpack()
     }
 
     fn read_string(&mut self) -> Gc<ObjString> {
@@ -679,13 +1122,30 @@ impl Vm {
         let slot = self.read_byte() as usize;
         let slot_base = self.active_fiber().current_frame().unwrap().slot_base;
         let value = self.active_fiber().stack[slot_base + slot];
-        self.push(value);
+        self.active_fiber_mut().stack.push(value);
+    }
+
+    fn get_locals_impl(&mut self) {
+        self.get_local_impl();
+        self.get_local_impl();
+    }
+
+    fn get_local_constant_impl(&mut self) {
+        self.get_local_impl();
+        let constant = self.read_constant();
+        self.push(constant);
+    }
+
+    fn add_locals_impl(&mut self) -> Result<(), Error> {
+        self.get_locals_impl();
+        self.add_impl()
     }
 
     fn set_local_impl(&mut self) {
         let slot = self.read_byte() as usize;
         let slot_base = self.active_fiber().current_frame().unwrap().slot_base;
-        self.active_fiber_mut().stack[slot_base + slot] = self.peek(0);
+        let value = *self.active_fiber().stack.peek(0);
+        self.active_fiber_mut().stack[slot_base + slot] = value;
     }
 
     fn get_global_impl(&mut self) -> Result<(), Error> {
@@ -758,6 +1218,7 @@ impl Vm {
 
     fn get_property_impl(&mut self) -> Result<(), Error> {
         let name = self.read_string();
+        let cache = self.read_short() as usize;
 
         if let Some(instance) = self.peek(0).try_as_obj_instance() {
             let borrowed_instance = instance.borrow();
@@ -774,9 +1235,16 @@ impl Vm {
                 return Ok(());
             }
         }
+        if let Some(fiber) = self.peek(0).try_as_obj_fiber() {
+            if let Some(property) = self.fiber_attribute(fiber, name) {
+                self.poke(0, property);
+                return Ok(());
+            }
+        }
 
         let class = self.get_class(self.peek(0));
-        self.bind_method(class, name)
+        let method = self.find_method(class, name, Some(cache));
+        self.bind_method(method, name)
     }
 
     fn set_property_impl(&mut self) -> Result<(), Error> {
@@ -821,23 +1289,37 @@ impl Vm {
 
     fn get_super_impl(&mut self) -> Result<(), Error> {
         let name = self.read_string();
-        let superclass = self.pop().try_as_obj_class().expect("Expected ObjClass.");
+        let superclass = match self.pop_superclass() {
+            Ok(superclass) => superclass,
+            Err(error) => return self.try_handle_error(error),
+        };
+        let method = superclass.methods.get(&name).copied();
 
-        self.bind_method(superclass, name)
+        self.bind_method(method, name)
     }
 
-    fn equal_impl(&mut self) {
+    fn equal_impl(&mut self) -> Result<(), Error> {
+        if self.try_invoke_binary_op((self.class_store.eq_name(), self.class_store.eq_name()))? {
+            return Ok(());
+        }
         let b = self.pop();
         let a = self.pop();
         self.push(Value::Boolean(a == b));
+        Ok(())
     }
 
-    fn binary_op_impl(&mut self, op: fn(f64, f64) -> Value) -> Result<(), Error> {
-        let second_value = self.pop();
-        let first_value = self.pop();
-        let (first, second) = match (first_value, second_value) {
+    fn binary_op_impl(
+        &mut self,
+        op: fn(f64, f64) -> Value,
+        method_names: (Gc<ObjString>, Gc<ObjString>),
+    ) -> Result<(), Error> {
+        let (first, second) = match (self.peek(1), self.peek(0)) {
             (Value::Number(first), Value::Number(second)) => (first, second),
             _ => {
+                if self.try_invoke_binary_op(method_names)? {
+                    return Ok(());
+                }
+                self.discard(2);
                 let err = error!(
                     ErrorKind::TypeError,
                     "Binary operands must both be numbers."
@@ -845,25 +1327,37 @@ impl Vm {
                 return self.try_handle_error(err);
             }
         };
+        self.discard(2);
         self.push(op(first, second));
         Ok(())
     }
 
     fn add_impl(&mut self) -> Result<(), Error> {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
+        match (self.peek(1), self.peek(0)) {
             (Value::ObjString(a), Value::ObjString(b)) => {
+                if let Err(error) = memory::check_available(a.len() + b.len()) {
+                    self.discard(2);
+                    return self.try_handle_error(error);
+                }
                 let value =
                     Value::ObjString(self.new_gc_obj_string(format!("{}{}", *a, *b).as_str()));
+                self.discard(2);
                 self.push(value)
             }
 
             (Value::Number(a), Value::Number(b)) => {
-                self.push(Value::Number(a + b));
+                self.discard(2);
+                self.push(utils::add(a, b));
             }
 
             _ => {
+                if self.try_invoke_binary_op((
+                    self.class_store.add_name(),
+                    self.class_store.radd_name(),
+                ))? {
+                    return Ok(());
+                }
+                self.discard(2);
                 let err = error!(
                     ErrorKind::TypeError,
                     "Binary operands must be two numbers or two strings.",
@@ -880,10 +1374,10 @@ impl Vm {
     }
 
     fn bitwise_not_impl(&mut self) -> Result<(), Error> {
-        let value = self.pop();
-        if let Some(num) = value.try_as_number() {
-            self.push(Value::Number(!(num as i64) as f64));
-        } else {
+        if let Some(num) = self.peek(0).try_as_number() {
+            self.poke(0, utils::bitwise_not(num));
+        } else if !self.try_invoke_unary_op(self.class_store.invert_name())? {
+            self.pop();
             let err = error!(ErrorKind::TypeError, "Unary operand must be a number.");
             self.try_handle_error(err)?;
         }
@@ -891,23 +1385,29 @@ impl Vm {
     }
 
     fn negate_impl(&mut self) -> Result<(), Error> {
-        let value = self.pop();
-        if let Some(num) = value.try_as_number() {
-            self.push(Value::Number(-num));
-        } else {
+        if let Some(num) = self.peek(0).try_as_number() {
+            self.poke(0, utils::negate(num));
+        } else if !self.try_invoke_unary_op(self.class_store.neg_name())? {
+            self.pop();
             let err = error!(ErrorKind::TypeError, "Unary operand must be a number.");
             self.try_handle_error(err)?;
         }
         Ok(())
     }
 
-    fn format_string_impl(&mut self) {
+    fn format_string_impl(&mut self) -> Result<(), Error> {
         let value = self.peek(0);
         if value.try_as_obj_string().is_some() {
-            return;
+            return Ok(());
+        }
+        match self.format_value(value) {
+            Ok(string) => self.poke(0, Value::ObjString(string)),
+            Err(error) => {
+                self.pop();
+                self.try_handle_error(error)?;
+            }
         }
-        let obj = Value::ObjString(self.new_gc_obj_string(format!("{}", value).as_str()));
-        self.poke(0, obj);
+        Ok(())
     }
 
     fn build_hash_map_impl(&mut self) -> Result<(), Error> {
@@ -961,7 +1461,7 @@ impl Vm {
         let end = self.stack_size();
         let elements = self.active_fiber().stack[begin..end]
             .iter()
-            .copied()
+            .map(|value| value.unpack())
             .collect();
         let tuple = self.new_root_obj_tuple(elements);
         self.discard(num_operands);
@@ -981,10 +1481,110 @@ impl Vm {
         self.push(Value::ObjVec(vec.as_gc()));
     }
 
+    fn extend_impl(&mut self) -> Result<(), Error> {
+        let elements = match self.sequence_elements(self.peek(0)) {
+            Ok(elements) => elements,
+            Err(error) => {
+                self.pop();
+                return self.try_handle_error(error);
+            }
+        };
+        match self.peek(1) {
+            Value::ObjVec(vec) => {
+                if let Err(error) = memory::reserve(elements.len() * mem::size_of::<PackedValue>())
+                {
+                    self.pop();
+                    return self.try_handle_error(error);
+                }
+                vec.borrow_mut()
+                    .elements
+                    .extend(elements.into_iter().map(PackedValue::new));
+            }
+            Value::ObjTuple(tuple) => {
+                let mut combined = tuple.elements.clone();
+                combined.extend(elements);
+                let tuple = self.new_root_obj_tuple(combined);
+                self.poke(1, Value::ObjTuple(tuple.as_gc()));
+            }
+            target => {
+                let error = error!(
+                    ErrorKind::TypeError,
+                    "Expected a Tuple or Vec instance but found '{}'.", target
+                );
+                self.pop();
+                return self.try_handle_error(error);
+            }
+        }
+        self.pop();
+        Ok(())
+    }
+
+    fn unpack_impl(&mut self) -> Result<(), Error> {
+        let num_elements = self.read_byte() as usize;
+        let rest = UnpackRest::from(self.read_byte());
+        match self.unpack(num_elements, rest) {
+            Ok(elements) => {
+                self.pop();
+                for element in elements {
+                    self.push(element);
+                }
+            }
+            Err(error) => {
+                self.pop();
+                self.try_handle_error(error)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn match_sequence_impl(&mut self) {
+        let num_elements = self.read_byte() as usize;
+        let kind = SequenceKind::from(self.read_byte());
+        let has_rest = self.read_byte() != 0;
+        let len = match (kind, self.peek(0)) {
+            (SequenceKind::Tuple, Value::ObjTuple(tuple)) => Some(tuple.elements.len()),
+            (SequenceKind::Vec, Value::ObjVec(vec)) => Some(vec.borrow().elements.len()),
+            _ => None,
+        };
+        let matched = len.is_some_and(|len| {
+            if has_rest {
+                len >= num_elements
+            } else {
+                len == num_elements
+            }
+        });
+        self.poke(0, Value::Boolean(matched));
+    }
+
+    fn match_range_impl(&mut self) {
+        // Range patterns contain the same values that iterating over the equivalent range would
+        // produce, so descending ranges exclude their end rather than their beginning.
+        let end = self.pop();
+        let begin = self.pop();
+        let matched = match (self.peek(0), begin, end) {
+            (Value::Number(n), Value::Number(begin), Value::Number(end)) => {
+                if begin <= end {
+                    begin <= n && n < end
+                } else {
+                    end < n && n <= begin
+                }
+            }
+            _ => false,
+        };
+        self.poke(0, Value::Boolean(matched));
+    }
+
+    fn match_field_impl(&mut self) {
+        let name = self.read_string();
+        let matched = self.resolve_method(self.peek(0), name).is_some();
+        self.poke(0, Value::Boolean(matched));
+    }
+
     fn iter_next_impl(&mut self) -> Result<(), Error> {
         let iter = self.peek(0);
         self.push(iter);
-        self.invoke(self.next_string, 0)
+        let next_string = self.next_string.as_ref().expect("Expected Root.").as_gc();
+        self.invoke(next_string, 0, None)
     }
 
     fn jump_impl(&mut self) {
@@ -1009,9 +1609,13 @@ impl Vm {
         }
     }
 
-    fn loop_impl(&mut self) {
+    fn loop_impl(&mut self) -> Result<(), Error> {
         let offset = self.read_short();
+        if let Some(error) = self.check_limits() {
+            return self.raise_limit_error(error);
+        }
         self.ip = unsafe { self.ip.offset(-(offset as isize)) };
+        Ok(())
     }
 
     fn jump_finally_impl(&mut self) {
@@ -1031,7 +1635,7 @@ impl Vm {
     }
 
     fn end_finally_impl(&mut self) -> Result<(), Error> {
-        if self.handling_exception {
+        if self.active_fiber().handling_exception {
             self.unwind_stack()?;
         }
         let return_data = self.active_fiber_mut().take_return_data();
@@ -1058,20 +1662,55 @@ impl Vm {
     }
 
     fn throw_impl(&mut self) -> Result<(), Error> {
-        self.handling_exception = true;
+        self.record_traceback(self.peek(0));
+        self.active_fiber_mut().handling_exception = true;
         self.active_fiber_mut().error_ip = Some(self.ip);
         self.unwind_stack()
     }
 
+    fn rethrow_impl(&mut self) {
+        // None of the catch clauses matched the exception, so the finally block is run before
+        // the exception continues to unwind the stack.
+        self.active_fiber_mut().handling_exception = true;
+        self.jump_impl();
+    }
+
     fn call_impl(&mut self) -> Result<(), Error> {
         let arg_count = self.read_byte() as usize;
         self.call_value(self.peek(arg_count), arg_count)
     }
 
-    fn construct_impl(&mut self) {
-        let arg_count = self.read_byte() as usize;
-        let value = self.peek(arg_count);
-        if let Some(class) = value.try_as_obj_class() {
+    fn call_kw_impl(&mut self) -> Result<(), Error> {
+        let positional = self.read_byte() as usize;
+        let keywords = self.read_byte() as usize;
+        self.call_kw(positional, keywords)
+    }
+
+    fn call_spread_impl(&mut self) -> Result<(), Error> {
+        let keywords = self.read_byte() as usize;
+        let positional = match self.spread_args(keywords) {
+            Ok(positional) => positional,
+            Err(error) => return self.try_handle_error(error),
+        };
+        if keywords == 0 {
+            return self.call_value(self.peek(positional), positional);
+        }
+        self.call_kw(positional, keywords)
+    }
+
+    fn call_kw(&mut self, positional: usize, keywords: usize) -> Result<(), Error> {
+        let callee = self.peek(positional + 2 * keywords);
+        let arg_count = match self.bind_keyword_args(callee, positional, keywords) {
+            Ok(arg_count) => arg_count,
+            Err(error) => return self.try_handle_error(error),
+        };
+        self.call_value(callee, arg_count)
+    }
+
+    fn construct_impl(&mut self) {
+        let arg_count = self.read_byte() as usize;
+        let value = self.peek(arg_count);
+        if let Some(class) = value.try_as_obj_class() {
             let instance = self.new_root_obj_instance(class);
             self.poke(arg_count, Value::ObjInstance(instance.as_gc()));
         }
@@ -1079,20 +1718,115 @@ impl Vm {
 
     fn invoke_impl(&mut self) -> Result<(), Error> {
         let method = self.read_string();
+        let cache = self.read_short() as usize;
         let arg_count = self.read_byte() as usize;
-        self.invoke(method, arg_count)
+        self.invoke(method, arg_count, Some(cache))
     }
 
-    fn super_invoke_impl(&mut self) -> Result<(), Error> {
+    fn invoke_kw_impl(&mut self) -> Result<(), Error> {
         let method = self.read_string();
-        let arg_count = self.read_byte() as usize;
-        let superclass = match self.pop() {
-            Value::ObjClass(ptr) => ptr,
-            _ => unreachable!(),
+        let positional = self.read_byte() as usize;
+        let keywords = self.read_byte() as usize;
+        self.invoke_kw(method, positional, keywords)
+    }
+
+    fn invoke_spread_impl(&mut self) -> Result<(), Error> {
+        let method = self.read_string();
+        let keywords = self.read_byte() as usize;
+        let positional = match self.spread_args(keywords) {
+            Ok(positional) => positional,
+            Err(error) => return self.try_handle_error(error),
+        };
+        if keywords == 0 {
+            return self.invoke(method, positional, None);
+        }
+        self.invoke_kw(method, positional, keywords)
+    }
+
+    fn invoke_kw(
+        &mut self,
+        method: Gc<ObjString>,
+        positional: usize,
+        keywords: usize,
+    ) -> Result<(), Error> {
+        let receiver = self.peek(positional + 2 * keywords);
+        let arg_count = match self.resolve_method(receiver, method) {
+            Some(callee) => match self.bind_keyword_args(callee, positional, keywords) {
+                Ok(arg_count) => arg_count,
+                Err(error) => return self.try_handle_error(error),
+            },
+            None => positional + 2 * keywords,
+        };
+        self.invoke(method, arg_count, None)
+    }
+
+    fn super_invoke_kw_impl(&mut self) -> Result<(), Error> {
+        let method = self.read_string();
+        let positional = self.read_byte() as usize;
+        let keywords = self.read_byte() as usize;
+        let superclass = match self.pop_superclass() {
+            Ok(superclass) => superclass,
+            Err(error) => return self.try_handle_error(error),
+        };
+        self.super_invoke_kw(superclass, method, positional, keywords)
+    }
+
+    fn super_invoke_spread_impl(&mut self) -> Result<(), Error> {
+        let method = self.read_string();
+        let keywords = self.read_byte() as usize;
+        let superclass = match self.pop_superclass() {
+            Ok(superclass) => superclass,
+            Err(error) => return self.try_handle_error(error),
+        };
+        let positional = match self.spread_args(keywords) {
+            Ok(positional) => positional,
+            Err(error) => return self.try_handle_error(error),
+        };
+        if keywords == 0 {
+            return self.invoke_from_class(superclass, method, positional);
+        }
+        self.super_invoke_kw(superclass, method, positional, keywords)
+    }
+
+    fn super_invoke_kw(
+        &mut self,
+        superclass: Gc<ObjClass>,
+        method: Gc<ObjString>,
+        positional: usize,
+        keywords: usize,
+    ) -> Result<(), Error> {
+        let arg_count = match superclass.methods.get(&method).copied() {
+            Some(callee) => match self.bind_keyword_args(callee, positional, keywords) {
+                Ok(arg_count) => arg_count,
+                Err(error) => return self.try_handle_error(error),
+            },
+            None => positional + 2 * keywords,
         };
         self.invoke_from_class(superclass, method, arg_count)
     }
 
+    fn super_invoke_impl(&mut self) -> Result<(), Error> {
+        let name = self.read_string();
+        let cache = self.read_short() as usize;
+        let arg_count = self.read_byte() as usize;
+        let superclass = match self.pop_superclass() {
+            Ok(superclass) => superclass,
+            Err(error) => return self.try_handle_error(error),
+        };
+        let method = self.find_method(superclass, name, Some(cache));
+        self.call_method(method, name, arg_count)
+    }
+
+    fn pop_superclass(&mut self) -> Result<Gc<ObjClass>, Error> {
+        match self.pop() {
+            Value::ObjClass(ptr) => Ok(ptr),
+            value => Err(error!(
+                ErrorKind::TypeError,
+                "Expected a superclass but found '{}'.", value
+            )),
+        }
+    }
+
     fn closure_impl(&mut self) {
         let function = match self.read_constant() {
             Value::ObjFunction(underlying) => underlying,
@@ -1100,8 +1834,14 @@ impl Vm {
         };
 
         let upvalue_count = function.upvalue_count;
+        let default_count = function.default_count;
 
         let closure = self.new_root_obj_closure(function, self.active_module);
+        *closure.defaults.borrow_mut() = (0..default_count)
+            .rev()
+            .map(|depth| self.peek(depth))
+            .collect();
+        self.discard(default_count);
         self.push(Value::ObjClosure(closure.as_gc()));
 
         for i in 0..upvalue_count {
@@ -1135,8 +1875,7 @@ impl Vm {
         self.active_fiber_mut().frames.pop();
         if self.active_fiber().has_finished() {
             if self.active_fiber().caller.is_some() {
-                self.unload_fiber(None)?;
-                self.poke(0, result);
+                self.unload_fiber(result)?;
                 return Ok(None);
             }
             return Ok(Some(self.pop()));
@@ -1147,11 +1886,33 @@ impl Vm {
         Ok(None)
     }
 
+    fn yield_impl(&mut self) -> Result<(), Error> {
+        let value = self.peek(0);
+        if let Err(error) = self.unload_fiber(value) {
+            return self.try_handle_error(error);
+        }
+        Ok(())
+    }
+
+    fn await_impl(&mut self) -> Result<(), Error> {
+        // Awaiting suspends the task's fiber and hands the awaitable to the event loop, which
+        // resumes the fiber with the result once it's available.
+        let awaitable_class = self.class_store.awaitable_class();
+        if !self.value_derives(self.peek(0), awaitable_class) {
+            return self.try_handle_error(error!(
+                ErrorKind::TypeError,
+                "Expected an awaitable but found '{}'.",
+                self.peek(0)
+            ));
+        }
+        self.yield_impl()
+    }
+
     fn declare_class_impl(&mut self) {
         let name = self.read_string();
-        let metaclass_name = self.new_gc_obj_string(format!("{}Class", *name).as_str());
+        let metaclass_name = self.new_root_obj_string(format!("{}Class", *name).as_str());
         let metaclass = UniqueRoot::new(ObjClass::new(
-            metaclass_name,
+            metaclass_name.as_gc(),
             self.class_store.base_metaclass(),
             Some(self.class_store.object_class()),
             object::new_obj_string_value_map(),
@@ -1166,14 +1927,21 @@ impl Vm {
         self.push(Value::None);
     }
 
-    fn define_class_impl(&mut self) {
-        let mut class_def = self.working_class_def.take().expect("Expected ClassDef.");
+    fn define_class_impl(&mut self) -> Result<(), Error> {
+        let mut class_def = match self.working_class_def.take() {
+            Some(class_def) => class_def,
+            None => {
+                let err = error!(ErrorKind::RuntimeError, "No class is being defined.");
+                return self.try_handle_error(err);
+            }
+        };
 
         let defined_metaclass: Root<ObjClass> = class_def.metaclass.into();
         class_def.class.metaclass = defined_metaclass.as_gc();
         let defined_class: Root<ObjClass> = class_def.class.into();
 
         self.poke(0, Value::ObjClass(defined_class.as_gc()));
+        Ok(())
     }
 
     fn inherit_impl(&mut self) -> Result<(), Error> {
@@ -1183,14 +1951,16 @@ impl Vm {
             let err = error!(ErrorKind::RuntimeError, "Superclass must be a class.");
             return self.try_handle_error(err);
         };
-        self.working_class_def.as_mut().unwrap().class.superclass = Some(superclass);
+        let class_def = match self.working_class_def.as_mut() {
+            Some(class_def) => class_def,
+            None => {
+                let err = error!(ErrorKind::RuntimeError, "No class is being defined.");
+                return self.try_handle_error(err);
+            }
+        };
+        class_def.class.superclass = Some(superclass);
         for (name, method) in &superclass.methods {
-            self.working_class_def
-                .as_mut()
-                .unwrap()
-                .class
-                .methods
-                .insert(*name, *method);
+            class_def.class.methods.insert(*name, *method);
         }
         self.pop();
         Ok(())
@@ -1224,6 +1994,12 @@ impl Vm {
             return Ok(());
         }
 
+        if let Some(module) = self.built_in_module(path.as_str()) {
+            self.push(Value::ObjModule(module));
+            self.push(Value::None);
+            return Ok(());
+        }
+
         let source = match (self.module_loader)(&path) {
             Ok(s) => s,
             Err(e) => {
@@ -1231,10 +2007,18 @@ impl Vm {
             }
         };
 
-        let function = match compiler::compile(self, source, Some(&path)) {
+        let result = match source {
+            ModuleSource::Source(source) => compiler::compile(self, source, Some(&path))
+                .map_err(|e| (e, "Error compiling module:")),
+            ModuleSource::Bytecode(bytecode) => {
+                serialize::deserialize_function(self, &bytecode, Some(&path))
+                    .map_err(|e| (e, "Error loading module bytecode:"))
+            }
+        };
+        let function = match result {
             Ok(f) => f,
-            Err(e) => {
-                let mut error = error!(ErrorKind::ImportError, "Error compiling module:");
+            Err((e, context)) => {
+                let mut error = error!(ErrorKind::ImportError, "{}", context);
                 for msg in e.messages() {
                     error.add_message(&format!("    {}", msg));
                 }
@@ -1254,6 +2038,23 @@ impl Vm {
         Ok(())
     }
 
+    /// Creates the module provided by the VM with the given path, if there is one. These take
+    /// precedence over modules provided by the module loader.
+    fn built_in_module(&mut self, path: &str) -> Option<Gc<RefCell<ObjModule>>> {
+        match path {
+            "gc" => {
+                self.define_native(path, "collect", core::gc_collect);
+                self.define_native(path, "stats", core::gc_stats);
+                self.define_native(path, "set_threshold", core::gc_set_threshold);
+                self.define_native(path, "set_growth_factor", core::gc_set_growth_factor);
+            }
+            _ => return None,
+        }
+        let module = self.module(path);
+        module.borrow_mut().imported = true;
+        Some(module)
+    }
+
     fn finish_import_impl(&mut self) {
         self.pop();
         let module = self
@@ -1294,19 +2095,178 @@ impl Vm {
         name: Gc<ObjString>,
         arg_count: usize,
     ) -> Result<(), Error> {
-        if let Some(value) = class.methods.get(&name) {
-            return match value {
-                Value::ObjClosure(closure) => self.call_closure(*closure, arg_count),
-                Value::ObjNative(native) => self.call_native(*native, arg_count),
-                _ => unreachable!(),
+        let method = class.methods.get(&name).copied();
+        self.call_method(method, name, arg_count)
+    }
+
+    #[inline(always)]
+    fn call_method(
+        &mut self,
+        method: Option<Value>,
+        name: Gc<ObjString>,
+        arg_count: usize,
+    ) -> Result<(), Error> {
+        match method {
+            Some(Value::ObjClosure(closure)) => self.call_closure(closure, arg_count),
+            Some(Value::ObjNative(native)) => self.call_native(native, arg_count),
+            Some(_) => unreachable!(),
+            None => {
+                let err = error!(ErrorKind::AttributeError, "Undefined property '{}'.", *name);
+                self.try_handle_error(err)
+            }
+        }
+    }
+
+    /// Looks up a method on a class, consulting and updating the inline cache with the provided
+    /// index in the active chunk if there is one.
+    #[inline(always)]
+    fn find_method(
+        &self,
+        class: Gc<ObjClass>,
+        name: Gc<ObjString>,
+        cache: Option<usize>,
+    ) -> Option<Value> {
+        let cache = match cache {
+            Some(index) => &self.active_chunk.inline_caches[index],
+            None => return class.methods.get(&name).copied(),
+        };
+        if let Some(method) = cache.get(class) {
+            return Some(method);
+        }
+        let method = class.methods.get(&name).copied()?;
+        cache.set(class, method);
+        Some(method)
+    }
+
+    fn resolve_method(&mut self, receiver: Value, name: Gc<ObjString>) -> Option<Value> {
+        let class = match receiver {
+            Value::ObjInstance(instance) => {
+                if let Some(value) = instance.borrow().fields.get(&name) {
+                    return Some(*value);
+                }
+                instance.borrow().class
+            }
+            Value::ObjModule(module) => {
+                if let Some(value) = module.borrow().attributes.get(&name) {
+                    return Some(*value);
+                }
+                module.borrow().class
+            }
+            Value::ObjFiber(fiber) => {
+                if let Some(value) = self.fiber_attribute(fiber, name) {
+                    return Some(value);
+                }
+                fiber.borrow().class
+            }
+            _ => self.get_class(receiver),
+        };
+        class.methods.get(&name).copied()
+    }
+
+    fn bind_keyword_args(
+        &mut self,
+        callee: Value,
+        positional: usize,
+        keywords: usize,
+    ) -> Result<usize, Error> {
+        // Keyword arguments sit on the stack as (name, value) pairs after the positional
+        // arguments. These are rearranged into parameter order, with any gaps filled from the
+        // closure's default values, so that the call can proceed as a plain positional one.
+        let closure = match callee {
+            Value::ObjClosure(closure) => closure,
+            Value::ObjBoundMethod(bound) => bound.borrow().method,
+            Value::ObjNative(_) | Value::ObjBoundNative(_) => {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Built-in functions do not accept keyword arguments."
+                ));
+            }
+            _ => {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Can only call functions and methods."
+                ));
+            }
+        };
+
+        let function = closure.function;
+        let num_params = function.parameters.len();
+        if positional > num_params && !function.is_variadic {
+            return Err(error!(
+                ErrorKind::TypeError,
+                "Expected at most {} positional arguments but found {}.", num_params, positional
+            ));
+        }
+
+        let mut args = vec![None; num_params];
+        let first_arg = positional + 2 * keywords;
+        for (i, arg) in args.iter_mut().enumerate().take(positional) {
+            *arg = Some(self.peek(first_arg - 1 - i));
+        }
+        let extra_args: Vec<Value> = (num_params..positional)
+            .map(|i| self.peek(first_arg - 1 - i))
+            .collect();
+        for i in 0..keywords {
+            let depth = 2 * (keywords - i) - 1;
+            let name = self.peek(depth).try_as_obj_string().ok_or_else(|| {
+                error!(
+                    ErrorKind::TypeError,
+                    "Expected a keyword argument name but found '{}'.",
+                    self.peek(depth)
+                )
+            })?;
+            let index = match function.parameters.iter().position(|p| *p == name) {
+                Some(index) => index,
+                None => {
+                    return Err(error!(
+                        ErrorKind::TypeError,
+                        "Unexpected keyword argument '{}'.", *name
+                    ));
+                }
             };
+            if args[index].is_some() {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Received multiple values for argument '{}'.", *name
+                ));
+            }
+            args[index] = Some(self.peek(depth - 1));
         }
-        let err = error!(ErrorKind::AttributeError, "Undefined property '{}'.", *name);
-        self.try_handle_error(err)
+
+        let first_default = num_params
+            .checked_sub(function.default_count)
+            .ok_or_else(|| error!(ErrorKind::RuntimeError, "Invalid function signature."))?;
+        for (i, arg) in args.iter_mut().enumerate() {
+            if arg.is_some() {
+                continue;
+            }
+            if i < first_default {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Missing argument '{}'.", *function.parameters[i]
+                ));
+            }
+            *arg = Some(closure.defaults.borrow()[i - first_default]);
+        }
+
+        self.discard(first_arg);
+        let num_args = num_params + extra_args.len();
+        for arg in args {
+            self.push(arg.unwrap());
+        }
+        for arg in extra_args {
+            self.push(arg);
+        }
+        Ok(num_args)
     }
 
     #[inline(always)]
-    fn invoke(&mut self, name: Gc<ObjString>, arg_count: usize) -> Result<(), Error> {
+    fn invoke(
+        &mut self,
+        name: Gc<ObjString>,
+        arg_count: usize,
+        cache: Option<usize>,
+    ) -> Result<(), Error> {
         let receiver = self.peek(arg_count);
         let class = match receiver {
             Value::ObjInstance(instance) => {
@@ -1324,19 +2284,85 @@ impl Vm {
                 }
                 module.borrow().class
             }
+            Value::ObjFiber(fiber) => {
+                if let Some(value) = self.fiber_attribute(fiber, name) {
+                    self.poke(arg_count, value);
+                    return self.call_value(value, arg_count);
+                }
+                fiber.borrow().class
+            }
             _ => self.get_class(receiver),
         };
-        self.invoke_from_class(class, name, arg_count)
+        let method = self.find_method(class, name, cache);
+        self.call_method(method, name, arg_count)
+    }
+
+    fn try_invoke_binary_op(
+        &mut self,
+        method_names: (Gc<ObjString>, Gc<ObjString>),
+    ) -> Result<bool, Error> {
+        // Operators on instances are delegated to the left operand's class. Failing that, the
+        // operands are swapped and the reflected method is looked up on the right operand's class.
+        let (name, reflected_name) = method_names;
+        if let Some(class) = self.operator_class(self.peek(1), name) {
+            self.invoke_from_class(class, name, 1)?;
+            return Ok(true);
+        }
+        if let Some(class) = self.operator_class(self.peek(0), reflected_name) {
+            let (first, second) = (self.peek(1), self.peek(0));
+            self.poke(1, second);
+            self.poke(0, first);
+            self.invoke_from_class(class, reflected_name, 1)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn try_invoke_unary_op(&mut self, name: Gc<ObjString>) -> Result<bool, Error> {
+        if let Some(class) = self.operator_class(self.peek(0), name) {
+            self.invoke_from_class(class, name, 0)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn operator_class(&self, value: Value, name: Gc<ObjString>) -> Option<Gc<ObjClass>> {
+        let class = value.try_as_obj_instance()?.borrow().class;
+        if class.methods.contains_key(&name) {
+            Some(class)
+        } else {
+            None
+        }
     }
 
     #[inline(always)]
     pub fn call_closure(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> Result<(), Error> {
-        let arity = closure.function.arity - 1;
-        let err = if arg_count != arity {
-            Some(error!(
-                ErrorKind::TypeError,
-                "Expected {} arguments but found {}.", arity, arg_count
-            ))
+        if let Some(error) = self.check_limits() {
+            return self.raise_limit_error(error);
+        }
+        let is_variadic = closure.function.is_variadic;
+        let arity = closure.function.arity - 1 - is_variadic as usize;
+        let min_arity = arity - closure.function.default_count;
+        let err = if arg_count < min_arity || (arg_count > arity && !is_variadic) {
+            if is_variadic {
+                Some(error!(
+                    ErrorKind::TypeError,
+                    "Expected at least {} arguments but found {}.", min_arity, arg_count
+                ))
+            } else if min_arity == arity {
+                Some(error!(
+                    ErrorKind::TypeError,
+                    "Expected {} arguments but found {}.", arity, arg_count
+                ))
+            } else {
+                Some(error!(
+                    ErrorKind::TypeError,
+                    "Expected between {} and {} arguments but found {}.",
+                    min_arity,
+                    arity,
+                    arg_count
+                ))
+            }
         } else if self.active_fiber().frames.len() == common::FRAMES_MAX {
             Some(error!(ErrorKind::IndexError, "Stack overflow."))
         } else {
@@ -1347,24 +2373,87 @@ impl Vm {
             return self.try_handle_error(err);
         }
 
+        if arg_count < arity {
+            let defaults = closure.defaults.borrow();
+            for value in &defaults[arg_count - min_arity..] {
+                self.push(*value);
+            }
+        }
+        if is_variadic {
+            // Any arguments beyond the fixed parameters are collected into a tuple, which
+            // occupies the slot of the variadic parameter.
+            let num_extra = arg_count.saturating_sub(arity);
+            let end = self.stack_size();
+            let extra_args = self.active_fiber().stack[end - num_extra..end]
+                .iter()
+                .map(|value| value.unpack())
+                .collect();
+            let tuple = self.new_root_obj_tuple(extra_args);
+            self.discard(num_extra);
+            self.push(Value::ObjTuple(tuple.as_gc()));
+        }
+
+        if closure.function.is_generator {
+            return self.wrap_call_in_fiber(closure, self.class_store.generator_class());
+        }
+        if closure.function.is_async {
+            return self.wrap_call_in_fiber(closure, self.class_store.task_class());
+        }
+
         self.active_fiber_mut().current_frame_mut().unwrap().ip = self.ip;
         self.active_fiber_mut().push_call_frame(closure);
         self.load_frame();
         Ok(())
     }
 
+    fn wrap_call_in_fiber(
+        &mut self,
+        closure: Gc<ObjClosure>,
+        class: Gc<ObjClass>,
+    ) -> Result<(), Error> {
+        // Generators and async functions run on their own fiber, which starts out with the callee
+        // and arguments on its stack, as if the call had just been made. The fiber is then passed
+        // to the constructor of the provided class, which is responsible for resuming it.
+        let arity = closure.function.arity;
+        let end = self.stack_size();
+        let fiber = self.new_root_obj_fiber(closure);
+        {
+            let mut borrowed_fiber = fiber.borrow_mut();
+            for &value in &self.active_fiber().stack[end - arity..end] {
+                borrowed_fiber.stack.push(value);
+            }
+        }
+        self.discard(arity - 1);
+        self.poke(0, Value::ObjClass(class));
+        self.push(Value::ObjFiber(fiber.as_gc()));
+        self.invoke(self.class_store.new_name(), 1, None)
+    }
+
     #[inline(always)]
     fn call_native(&mut self, native: Gc<ObjNative>, arg_count: usize) -> Result<(), Error> {
         let function = native.function;
+        let fiber = self.unsafe_fiber;
         let result = function(self, arg_count);
+        if self.unsafe_fiber != fiber && result.is_ok() {
+            // Natives such as `Fiber.call` suspend the fiber that called them. Its arguments are
+            // discarded, leaving the slot that receives the result when the fiber is resumed.
+            // # Safety
+            // Nothing has been allocated since the fiber was suspended, so it can't have been
+            // collected.
+            unsafe { (*fiber).stack.truncate((*fiber).stack.len() - arg_count) };
+            return Ok(());
+        }
         self.discard(arg_count);
         match result {
             Ok(value) => {
                 self.poke(0, value);
             }
             Err(error) => {
-                let exc_object = self.new_root_obj_err_from_error(error);
-                self.poke(0, Value::ObjInstance(exc_object.as_gc()));
+                if self.is_uncatchable(&error) {
+                    return Err(error);
+                }
+                let exc_object = self.new_exception_from_error(error);
+                self.poke(0, exc_object);
                 self.unwind_stack()?;
             }
         }
@@ -1374,10 +2463,29 @@ impl Vm {
     fn unwind_stack(&mut self) -> Result<(), Error> {
         let exc_object = self.peek(0);
 
-        let exc_handler = self.active_fiber_mut().pop_exc_handler();
+        let exc_handler = {
+            let mut active_fiber = self.active_fiber_mut();
+            let barrier = active_fiber.call_barriers.last().copied();
+            match active_fiber.exc_handlers.last() {
+                Some(h) if barrier.is_none_or(|b| h.frame_count > b) => {
+                    active_fiber.pop_exc_handler()
+                }
+                _ => {
+                    if barrier.is_some() {
+                        active_fiber.nested_exception = Some(exc_object);
+                    }
+                    None
+                }
+            }
+        };
         let handler = if let Some(h) = exc_handler {
             h
         } else {
+            let can_fail_fiber =
+                self.active_fiber().call_barriers.is_empty() && self.is_caught_by_callers();
+            if can_fail_fiber {
+                return self.fail_fiber(exc_object);
+            }
             return Err(self.new_error_from_value(exc_object));
         };
 
@@ -1386,13 +2494,124 @@ impl Vm {
             .truncate(handler.init_stack_size);
         self.push(exc_object);
         self.active_fiber_mut().frames.truncate(handler.frame_count);
-        self.handling_exception = handler.has_catch_block();
+        self.active_fiber_mut().handling_exception = handler.has_catch_block();
         self.active_fiber_mut().current_frame_mut().unwrap().ip = handler.catch_ip;
         self.load_frame();
 
         Ok(())
     }
 
+    fn is_caught_by_callers(&self) -> bool {
+        // Exceptions that would go unhandled anyway are left in the fiber that raised them, so
+        // that the reported stack trace points to where they were thrown.
+        let mut fiber = self.fiber.as_ref().unwrap().as_gc();
+        loop {
+            let caller = {
+                let borrowed_fiber = fiber.borrow();
+                if borrowed_fiber.is_try {
+                    return true;
+                }
+                match borrowed_fiber.caller {
+                    Some(caller) => caller,
+                    None => return false,
+                }
+            };
+            {
+                let borrowed_caller = caller.borrow();
+                if !borrowed_caller.exc_handlers.is_empty()
+                    || !borrowed_caller.call_barriers.is_empty()
+                {
+                    return true;
+                }
+            }
+            fiber = caller;
+        }
+    }
+
+    fn fail_fiber(&mut self, exc_object: Value) -> Result<(), Error> {
+        // An exception that isn't handled within a fiber finishes it. The exception is then either
+        // returned to the caller, if the fiber was run using `try`, or rethrown in the caller.
+        let is_try = {
+            let fiber = self.fiber.as_ref().unwrap().as_gc();
+            let mut active_fiber = fiber.borrow_mut();
+            active_fiber.close_upvalues(0);
+            active_fiber.stack.clear();
+            active_fiber.frames.clear();
+            active_fiber.exc_handlers.clear();
+            active_fiber.handling_exception = false;
+            active_fiber.error_ip = None;
+            active_fiber.error = Some(exc_object);
+            active_fiber.is_try
+        };
+        self.unload_fiber(exc_object)?;
+        if is_try {
+            return Ok(());
+        }
+        self.unwind_stack()
+    }
+
+    pub(crate) fn abort_fiber(&mut self, exc_object: Value) -> Error {
+        // Discarding the fiber's exception handlers means the exception can't be caught within
+        // the fiber, so it finishes the fiber when it's rethrown by `call_native`.
+        self.record_traceback(exc_object);
+        let fiber = self.fiber.as_ref().unwrap().as_gc();
+        let mut active_fiber = fiber.borrow_mut();
+        active_fiber.exc_handlers.clear();
+        active_fiber.nested_exception = Some(exc_object);
+        error!(ErrorKind::RuntimeError, "Fiber aborted.")
+    }
+
+    pub(crate) fn is_active_fiber(&self, fiber: Gc<RefCell<ObjFiber>>) -> bool {
+        self.unsafe_fiber == (*fiber).as_ptr()
+    }
+
+    /// Looks up one of the read-only attributes that describe a fiber's progress: `error`, which
+    /// holds the exception that failed the fiber, if any, `is_done` and `state`.
+    fn fiber_attribute(
+        &mut self,
+        fiber: Gc<RefCell<ObjFiber>>,
+        name: Gc<ObjString>,
+    ) -> Option<Value> {
+        if name == self.class_store.error_name() {
+            Some(fiber.borrow().error.unwrap_or(Value::None))
+        } else if name == self.class_store.is_done_name() {
+            Some(Value::Boolean(fiber.borrow().has_finished()))
+        } else if name == self.class_store.state_name() {
+            let state = {
+                let borrowed_fiber = fiber.borrow();
+                if borrowed_fiber.error.is_some() {
+                    "failed"
+                } else if borrowed_fiber.has_finished() {
+                    "done"
+                } else if borrowed_fiber.caller.is_some() || self.is_active_fiber(fiber) {
+                    "running"
+                } else if borrowed_fiber.has_started() {
+                    "suspended"
+                } else {
+                    "new"
+                }
+            };
+            Some(Value::ObjString(self.new_gc_obj_string(state)))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the active fiber if it can be suspended by a native function, i.e. if it has a
+    /// caller to return to and isn't running a nested call on behalf of another native.
+    pub(crate) fn suspendable_fiber(&self) -> Option<Gc<RefCell<ObjFiber>>> {
+        let fiber = self.fiber.as_ref()?.as_gc();
+        let can_suspend = {
+            let active_fiber = self.active_fiber();
+            active_fiber.caller.is_some() && active_fiber.call_barriers.is_empty()
+        };
+        if can_suspend {
+            Some(fiber)
+        } else {
+            None
+        }
+    }
+
     fn reset_stack(&mut self) {
         if let Some(fiber) = self.fiber.as_ref() {
             let mut borrowed_fiber = fiber.borrow_mut();
@@ -1432,7 +2651,20 @@ impl Vm {
 
     fn define_method(&mut self, name: Gc<ObjString>, is_static: bool) -> Result<(), Error> {
         let method = self.peek(0);
-        let class_def = self.working_class_def.as_mut().unwrap();
+        if !matches!(method, Value::ObjClosure(_) | Value::ObjNative(_)) {
+            let err = error!(
+                ErrorKind::TypeError,
+                "Expected a method but found '{}'.", method
+            );
+            return self.try_handle_error(err);
+        }
+        let class_def = match self.working_class_def.as_mut() {
+            Some(class_def) => class_def,
+            None => {
+                let err = error!(ErrorKind::RuntimeError, "No class is being defined.");
+                return self.try_handle_error(err);
+            }
+        };
         class_def.class.methods.insert(name, method);
         if is_static {
             class_def.metaclass.methods.insert(name, method);
@@ -1444,14 +2676,14 @@ impl Vm {
         Ok(())
     }
 
-    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> Result<(), Error> {
+    fn bind_method(&mut self, method: Option<Value>, name: Gc<ObjString>) -> Result<(), Error> {
         let instance = self.peek(0);
-        let bound = match class.methods.get(&name) {
+        let bound = match method {
             Some(Value::ObjClosure(ptr)) => {
-                Value::ObjBoundMethod(self.new_root_obj_bound_method(instance, *ptr).as_gc())
+                Value::ObjBoundMethod(self.new_root_obj_bound_method(instance, ptr).as_gc())
             }
             Some(Value::ObjNative(ptr)) => {
-                Value::ObjBoundNative(self.new_root_obj_bound_method(instance, *ptr).as_gc())
+                Value::ObjBoundNative(self.new_root_obj_bound_method(instance, ptr).as_gc())
             }
             None => {
                 let err = error!(ErrorKind::AttributeError, "Undefined property '{}'.", *name);
@@ -1533,32 +2765,47 @@ impl Vm {
         class: Gc<ObjClass>,
         context: Value,
     ) -> Root<RefCell<ObjInstance>> {
-        let context_string = self.new_gc_obj_string("context");
+        let context_string = self.new_root_obj_string("context");
+        let cause_string = self.new_root_obj_string("cause");
         let instance = self.new_root_obj_instance(class);
-        instance.borrow_mut().fields.insert(context_string, context);
+        instance
+            .borrow_mut()
+            .fields
+            .insert(context_string.as_gc(), context);
+        instance
+            .borrow_mut()
+            .fields
+            .insert(cause_string.as_gc(), Value::None);
+        let traceback_string = self.new_gc_obj_string("traceback");
+        instance
+            .borrow_mut()
+            .fields
+            .insert(traceback_string, Value::None);
         instance
     }
 
     fn new_root_obj_err_from_error(&mut self, error: Error) -> Root<RefCell<ObjInstance>> {
-        let msg = self.new_gc_obj_string(&error.messages().join("\n"));
+        let msg = self.new_root_obj_string(&error.messages().join("\n"));
         let class = match error.kind() {
             ErrorKind::AttributeError => self.class_store.attribute_error_class(),
             ErrorKind::CompileError => self.class_store.runtime_error_class(),
             ErrorKind::ImportError => self.class_store.import_error_class(),
             ErrorKind::IndexError => self.class_store.index_error_class(),
+            ErrorKind::InterruptError => self.class_store.interrupt_error_class(),
+            ErrorKind::MemoryError => self.class_store.memory_error_class(),
             ErrorKind::NameError => self.class_store.name_error_class(),
             ErrorKind::RuntimeError => self.class_store.runtime_error_class(),
             ErrorKind::TypeError => self.class_store.type_error_class(),
             ErrorKind::ValueError => self.class_store.value_error_class(),
         };
 
-        self.new_root_obj_err_with_class(class, Value::ObjString(msg))
+        self.new_root_obj_err_with_class(class, Value::ObjString(msg.as_gc()))
     }
 
     fn new_error_from_value(&mut self, value: Value) -> Error {
-        let (kind, exc_description, context) = if let Some(instance) = value.try_as_obj_instance() {
+        let kind = if let Some(instance) = value.try_as_obj_instance() {
             let class = instance.borrow().class;
-            let kind = if class == self.class_store.attribute_error_class() {
+            if class == self.class_store.attribute_error_class() {
                 ErrorKind::AttributeError
             } else if class == self.class_store.runtime_error_class() {
                 ErrorKind::CompileError
@@ -1566,6 +2813,10 @@ impl Vm {
                 ErrorKind::ImportError
             } else if class == self.class_store.index_error_class() {
                 ErrorKind::IndexError
+            } else if class == self.class_store.interrupt_error_class() {
+                ErrorKind::InterruptError
+            } else if class == self.class_store.memory_error_class() {
+                ErrorKind::MemoryError
             } else if class == self.class_store.name_error_class() {
                 ErrorKind::NameError
             } else if class == self.class_store.runtime_error_class() {
@@ -1576,7 +2827,41 @@ impl Vm {
                 ErrorKind::ValueError
             } else {
                 ErrorKind::RuntimeError
-            };
+            }
+        } else {
+            ErrorKind::RuntimeError
+        };
+
+        let (exc_description, context) = self.describe_exception(value);
+        let mut msg = format!("Unhandled {}: {}", exc_description, context);
+
+        // Follow the chain of exceptions that caused this one, stopping if the chain loops back
+        // on itself.
+        let cause_string = self.new_root_obj_string("cause");
+        let mut seen = vec![value];
+        let mut current = value;
+        while let Some(cause) = current
+            .try_as_obj_instance()
+            .and_then(|instance| instance.borrow().fields.get(&cause_string.as_gc()).copied())
+        {
+            if matches!(cause, Value::None) || seen.contains(&cause) {
+                break;
+            }
+            let (exc_description, context) = self.describe_exception(cause);
+            write!(msg, "\nCaused by {}: {}", exc_description, context)
+                .expect("Unable to write error cause.");
+            seen.push(cause);
+            current = cause;
+        }
+
+        let lines = msg.lines().collect::<Vec<_>>();
+
+        Error::with_messages(kind, &lines)
+    }
+
+    fn describe_exception(&mut self, value: Value) -> (String, Value) {
+        if let Some(instance) = value.try_as_obj_instance() {
+            let class = instance.borrow().class;
             let context_string = self.new_gc_obj_string("context");
             let borrowed_instance = instance.borrow();
             let context = borrowed_instance
@@ -1584,67 +2869,259 @@ impl Vm {
                 .get(&context_string)
                 .map(|&v| v)
                 .unwrap_or(value);
-            (kind, class.name.as_str().to_owned(), context)
+            (class.name.as_str().to_owned(), context)
         } else {
-            (ErrorKind::RuntimeError, "exception".to_owned(), value)
-        };
+            ("exception".to_owned(), value)
+        }
+    }
 
-        let msg = format!("Unhandled {}: {}", exc_description, context);
-        let lines = msg.lines().collect::<Vec<_>>();
+    fn new_exception_from_error(&mut self, error: Error) -> Value {
+        // Errors that originate from an exception thrown during a nested call rethrow the
+        // original exception object.
+        if let Some(exc_object) = self.active_fiber_mut().nested_exception.take() {
+            return exc_object;
+        }
+        let exc_object = self.new_root_obj_err_from_error(error);
+        self.record_traceback(Value::ObjInstance(exc_object.as_gc()));
+        Value::ObjInstance(exc_object.as_gc())
+    }
 
-        Error::with_messages(kind, &lines)
+    fn record_traceback(&mut self, exc_object: Value) {
+        // Errors keep the traceback from the point where they were first thrown, so rethrowing a
+        // caught error doesn't replace it.
+        let instance = match exc_object.try_as_obj_instance() {
+            Some(instance) => instance,
+            None => return,
+        };
+        if !self.value_derives(exc_object, self.class_store.error_class()) {
+            return;
+        }
+        let traceback_string = self.new_root_obj_string("traceback");
+        let existing = instance
+            .borrow()
+            .fields
+            .get(&traceback_string.as_gc())
+            .copied();
+        if existing.is_some_and(|v| !matches!(v, Value::None)) {
+            return;
+        }
+
+        let module_string = self.new_root_obj_string("module");
+        let function_string = self.new_root_obj_string("function");
+        let line_string = self.new_root_obj_string("line");
+        let frame_class = self.class_store.trace_frame_class();
+        let traceback = self.new_root_obj_vec();
+        let num_frames = self.active_fiber().frames.len();
+        for i in (0..num_frames).rev() {
+            let (closure, ip) = {
+                let frame = &self.active_fiber().frames[i];
+                let ip = if i == num_frames - 1 {
+                    self.ip
+                } else {
+                    frame.ip
+                };
+                (frame.closure, ip)
+            };
+            let chunk = closure.function.chunk;
+            let line = chunk.lines[chunk.code_offset(ip) - 1];
+            let function = if closure.function.name.is_empty() {
+                Value::None
+            } else {
+                Value::ObjString(closure.function.name)
+            };
+            let module = Value::ObjString(closure.module.borrow().path);
+
+            let frame = self.new_root_obj_instance(frame_class);
+            {
+                let mut borrowed_frame = frame.borrow_mut();
+                borrowed_frame.fields.insert(module_string.as_gc(), module);
+                borrowed_frame
+                    .fields
+                    .insert(function_string.as_gc(), function);
+                borrowed_frame
+                    .fields
+                    .insert(line_string.as_gc(), Value::Number(line as f64));
+            }
+            traceback
+                .borrow_mut()
+                .elements
+                .push(PackedValue::new(Value::ObjInstance(frame.as_gc())));
+        }
+        instance
+            .borrow_mut()
+            .fields
+            .insert(traceback_string.as_gc(), Value::ObjVec(traceback.as_gc()));
     }
 
     fn try_handle_error(&mut self, error: Error) -> Result<(), Error> {
-        let obj_err = self.new_root_obj_err_from_error(error);
-        self.push(Value::ObjInstance(obj_err.as_gc()));
+        if self.is_uncatchable(&error) {
+            return Err(error);
+        }
+        let exc_object = self.new_exception_from_error(error);
+        self.push(exc_object);
         self.unwind_stack()
     }
 
+    fn check_limits(&mut self) -> Option<Error> {
+        // Limits are checked when looping and calling functions, since any script that runs
+        // indefinitely or allocates without bound has to do one or the other.
+        if self.interrupt_requested.load(Ordering::Relaxed) {
+            self.interrupt_requested.store(false, Ordering::Relaxed);
+            return Some(error!(ErrorKind::InterruptError, "Script interrupted."));
+        }
+        if self.instruction_budget.is_some() {
+            // The budget isn't refilled once exhausted, so a script that catches the error can't
+            // use it to keep running.
+            if self.fuel == 0 {
+                return Some(error!(
+                    ErrorKind::InterruptError,
+                    "Instruction budget exhausted."
+                ));
+            }
+            self.fuel -= 1;
+        }
+        if memory::take_limit_exceeded() {
+            return Some(error!(ErrorKind::MemoryError, "Memory limit exceeded."));
+        }
+        None
+    }
+
+    fn raise_limit_error(&mut self, error: Error) -> Result<(), Error> {
+        if !self.is_uncatchable(&error) {
+            return self.try_handle_error(error);
+        }
+        let exc_object = self.new_exception_from_error(error);
+        Err(self.new_error_from_value(exc_object))
+    }
+
+    fn is_uncatchable(&self, error: &Error) -> bool {
+        error.kind() == ErrorKind::InterruptError && !self.catchable_interrupts
+    }
+
+    fn sequence_elements(&self, value: Value) -> Result<Vec<Value>, Error> {
+        if let Some(tuple) = value.try_as_obj_tuple() {
+            Ok(tuple.elements.clone())
+        } else if let Some(vec) = value.try_as_obj_vec() {
+            Ok(vec.borrow().elements.iter().map(|e| e.unpack()).collect())
+        } else {
+            Err(error!(
+                ErrorKind::TypeError,
+                "Expected a Tuple or Vec instance but found '{}'.", value
+            ))
+        }
+    }
+
+    fn spread_args(&mut self, keywords: usize) -> Result<usize, Error> {
+        // The positional arguments of a call containing spread arguments are gathered into a
+        // single Vec, which is replaced by its elements here. Any keyword arguments remain above
+        // the unpacked positional arguments.
+        let args = self.peek(2 * keywords);
+        let elements = match args.try_as_obj_vec() {
+            Some(vec) => vec.borrow().elements.clone(),
+            None => {
+                return Err(error!(
+                    ErrorKind::TypeError,
+                    "Expected spread arguments in a Vec but found '{}'.", args
+                ))
+            }
+        };
+        if elements.len() > 255 {
+            return Err(error!(
+                ErrorKind::ValueError,
+                "Cannot pass more than 255 arguments."
+            ));
+        }
+        let kwargs: Vec<_> = (0..2 * keywords)
+            .rev()
+            .map(|d| *self.active_fiber().stack.peek(d))
+            .collect();
+        self.discard(2 * keywords + 1);
+        let num_elements = elements.len();
+        for value in elements.into_iter().chain(kwargs) {
+            self.active_fiber_mut().stack.push(value);
+        }
+        Ok(num_elements)
+    }
+
+    fn unpack(&mut self, num_elements: usize, rest: UnpackRest) -> Result<Vec<Value>, Error> {
+        let mut elements = self.sequence_elements(self.peek(0))?;
+
+        if rest == UnpackRest::None && elements.len() != num_elements {
+            return Err(error!(
+                ErrorKind::ValueError,
+                "Expected {} elements to unpack but found {}.",
+                num_elements,
+                elements.len()
+            ));
+        }
+        if elements.len() < num_elements {
+            return Err(error!(
+                ErrorKind::ValueError,
+                "Expected at least {} elements to unpack but found {}.",
+                num_elements,
+                elements.len()
+            ));
+        }
+
+        // The unpacked value remains on the stack while any rest object is allocated, so its
+        // elements can't be collected in the meantime.
+        let remaining = elements.split_off(num_elements);
+        match rest {
+            UnpackRest::None => {}
+            UnpackRest::Tuple => {
+                let tuple = self.new_root_obj_tuple(remaining);
+                elements.push(Value::ObjTuple(tuple.as_gc()));
+            }
+            UnpackRest::Vec => {
+                let vec = self.new_root_obj_vec();
+                vec.borrow_mut().elements = remaining.into_iter().map(PackedValue::new).collect();
+                elements.push(Value::ObjVec(vec.as_gc()));
+            }
+        }
+        Ok(elements)
+    }
+
     fn build_hash_map(&mut self, num_elements: usize) -> Result<Root<RefCell<ObjHashMap>>, Error> {
         let map = self.new_root_obj_hash_map();
         let begin = self.stack_size() - num_elements * 2;
         for i in 0..num_elements {
-            let key = self.active_fiber().stack[begin + 2 * i];
-            if !key.has_hash() {
-                return Err(error!(
-                    ErrorKind::ValueError,
-                    "Cannot use unhashable value '{}' as HashMap key.", key
-                ));
-            }
-            let value = self.active_fiber().stack[begin + 2 * i + 1];
-            map.borrow_mut().elements.insert(key, value);
+            let key = self.active_fiber().stack[begin + 2 * i].unpack();
+            let key = self.hash_map_key(map.as_gc(), key)?;
+            let value = self.active_fiber().stack[begin + 2 * i + 1].unpack();
+            map.borrow_mut().insert(key, value);
         }
         self.discard(num_elements * 2);
         Ok(map)
     }
 
     fn init_heap_allocated_data(&mut self) {
-        let mut root_base_metaclass = unsafe { core::new_base_metaclass() };
+        // Classes keep their names alive, so the names have to exist before the classes do.
+        let mut object_class_name = self.new_root_obj_string("Object");
+        let mut base_metaclass_name = self.new_root_obj_string("Type");
+        let mut string_metaclass_name = self.new_root_obj_string("StringClass");
+        let mut string_class_name = self.new_root_obj_string("String");
+        let mut root_base_metaclass =
+            unsafe { core::new_base_metaclass(base_metaclass_name.as_gc()) };
         let mut root_object_class = Root::new(ObjClass {
-            name: Gc::dangling(),
+            name: object_class_name.as_gc(),
             metaclass: root_base_metaclass.as_gc(),
             superclass: None,
             methods: object::new_obj_string_value_map(),
         });
         let mut root_string_metaclass = Root::new(ObjClass::new(
-            Gc::dangling(),
+            string_metaclass_name.as_gc(),
             root_base_metaclass.as_gc(),
             Some(root_object_class.as_gc()),
             object::new_obj_string_value_map(),
         ));
         let mut string_class = Root::new(ObjClass::new(
-            Gc::dangling(),
+            string_class_name.as_gc(),
             root_string_metaclass.as_gc(),
             Some(root_object_class.as_gc()),
             object::new_obj_string_value_map(),
         ));
 
         self.string_class = Some(string_class.clone());
-        let object_class_name = self.new_gc_obj_string("Object");
-        let base_metaclass_name = self.new_gc_obj_string("Type");
-        let string_metaclass_name = self.new_gc_obj_string("StringClass");
-        let string_class_name = self.new_gc_obj_string("String");
         // # Safety
         // We're modifying data for which there are immutable references held by other data
         // structures (namely metaclass and class objects). Because the code is single-threaded and
@@ -1652,31 +3129,35 @@ impl Vm {
         // (class names are only used by the Display trait, and superclass and methods are only
         // accessed once code is run), mutating the data here should be safe.
         unsafe {
-            root_object_class.as_mut().name = object_class_name;
-            root_base_metaclass.as_mut().name = base_metaclass_name;
+            for name in [
+                &mut object_class_name,
+                &mut base_metaclass_name,
+                &mut string_metaclass_name,
+                &mut string_class_name,
+            ] {
+                name.as_mut().class = string_class.as_gc();
+            }
             root_base_metaclass.as_mut().superclass = Some(root_object_class.as_gc());
-            root_string_metaclass.as_mut().name = string_metaclass_name;
-            string_class.as_mut().name = string_class_name;
             core::bind_object_class(self, &mut root_object_class);
             core::bind_type_class(self, &mut root_base_metaclass);
             core::bind_gc_obj_string_class(self, &mut string_class, &mut root_string_metaclass);
         }
 
         let empty_chunk = Root::new(Chunk::new()).as_gc();
-        let next_string = self.new_gc_obj_string("next");
+        let next_string = self.new_root_obj_string("next");
         self.active_chunk = empty_chunk;
-        self.next_string = next_string;
+        self.next_string = Some(next_string);
         let class_store =
             CoreClassStore::new(self, root_base_metaclass.clone(), root_object_class.clone());
         self.class_store = class_store;
         let class_store =
             CoreClassStore::new_with_built_ins(self, root_base_metaclass, root_object_class);
-        self.core_chunks = self.chunks.clone();
         self.class_store = class_store;
     }
 
     fn init_built_in_globals(&mut self, module_path: &str) {
         self.define_native(module_path, "clock", core::clock);
+        self.define_native(module_path, "sleep_until", core::sleep_until);
         self.define_native(module_path, "type", core::type_);
         self.define_native(module_path, "print", self.printer);
         let base_metaclass = self.class_store.base_metaclass();
@@ -1727,6 +3208,8 @@ impl Vm {
         self.set_global(module_path, "HashMap", Value::ObjClass(obj_hash_map_class));
         let obj_fiber_class = self.class_store.fiber_class();
         self.set_global(module_path, "Fiber", Value::ObjClass(obj_fiber_class));
+        let obj_channel_class = self.class_store.channel_class();
+        self.set_global(module_path, "Channel", Value::ObjClass(obj_channel_class));
     }
 
     fn load_frame(&mut self) {
@@ -1768,8 +3251,8 @@ impl Vm {
         self.active_fiber().stack.len()
     }
 
-    fn poke(&mut self, depth: usize, value: Value) {
-        *self.active_fiber_mut().stack.peek_mut(depth) = value;
+    pub(crate) fn poke(&mut self, depth: usize, value: Value) {
+        *self.active_fiber_mut().stack.peek_mut(depth) = PackedValue::new(value);
     }
 
     fn discard(&mut self, num: usize) {
@@ -1781,7 +3264,7 @@ impl Vm {
 mod string_store {
     use std::mem;
 
-    use crate::memory::Root;
+    use crate::memory::{Gc, WeakTable};
     use crate::object::ObjString;
 
     const INIT_CAPACITY: usize = 4;
@@ -1792,8 +3275,11 @@ mod string_store {
     // using a custom hash algorithm along with caching of hash on the stored ObjString, meaning the
     // &str objects we use for look-up and the ObjString objects we store have different
     // implementations of Hash.
+    //
+    // The store doesn't keep strings alive. Those that become unreachable are removed by the
+    // collector before they're freed.
     pub(super) struct ObjStringStore {
-        entries: Vec<Option<Root<ObjString>>>,
+        entries: Vec<Option<Gc<ObjString>>>,
         size: usize,
         mask: usize,
     }
@@ -1803,16 +3289,11 @@ mod string_store {
             Default::default()
         }
 
-        pub(super) fn get(&self, key: (u64, &str)) -> Option<&Root<ObjString>> {
-            let entry = &self.entries[find_index(&self.entries, key, self.mask)];
-            if entry.is_some() {
-                entry.as_ref()
-            } else {
-                None
-            }
+        pub(super) fn get(&self, key: (u64, &str)) -> Option<Gc<ObjString>> {
+            self.entries[find_index(&self.entries, key, self.mask)]
         }
 
-        pub(super) fn insert(&mut self, value: Root<ObjString>) -> Option<Root<ObjString>> {
+        pub(super) fn insert(&mut self, value: Gc<ObjString>) -> Option<Gc<ObjString>> {
             if self.size + 1 > (self.entries.len() as f64 * MAX_LOAD) as usize {
                 self.adjust_capacity(self.entries.len() * 2);
             }
@@ -1829,29 +3310,33 @@ mod string_store {
         }
 
         fn adjust_capacity(&mut self, new_capacity: usize) {
-            let mut new_entries: Vec<Option<Root<ObjString>>> = vec![None; new_capacity];
-            let mask = new_capacity - 1;
-
-            for entry in self.entries.iter_mut() {
-                if entry.is_none() {
-                    continue;
-                }
+            let entries = mem::replace(&mut self.entries, vec![None; new_capacity]);
+            self.mask = new_capacity - 1;
+            self.size = 0;
+            self.reinsert(entries.into_iter().flatten());
+        }
 
-                let key = {
-                    let entry = entry.as_ref().unwrap();
-                    (entry.hash, entry.as_str())
-                };
-                let index = find_index(&new_entries, key, mask);
-                let dest = &mut new_entries[index];
-                *dest = mem::take(entry);
+        fn reinsert(&mut self, strings: impl Iterator<Item = Gc<ObjString>>) {
+            for string in strings {
+                let index = find_index(&self.entries, (string.hash, string.as_str()), self.mask);
+                self.entries[index] = Some(string);
+                self.size += 1;
             }
+        }
+    }
 
-            self.entries = new_entries;
-            self.mask = mask;
+    impl WeakTable for ObjStringStore {
+        fn remove_unmarked(&mut self) {
+            // Removing entries in place would break the probe sequences that pass through them,
+            // so the surviving strings are reinserted into an empty table instead.
+            let capacity = self.entries.len();
+            let entries = mem::replace(&mut self.entries, vec![None; capacity]);
+            self.size = 0;
+            self.reinsert(entries.into_iter().flatten().filter(|s| s.is_marked()));
         }
     }
 
-    fn find_index(entries: &Vec<Option<Root<ObjString>>>, key: (u64, &str), mask: usize) -> usize {
+    fn find_index(entries: &[Option<Gc<ObjString>>], key: (u64, &str), mask: usize) -> usize {
         let (hash, string) = key;
         let mut index = (hash as usize) & mask;
 
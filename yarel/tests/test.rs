@@ -16,11 +16,22 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
 use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
+use yarel::chunk::{self, Chunk, OpCode, VerifyError, VerifyErrorKind};
+use yarel::compiler;
 use yarel::error::{Error, ErrorKind};
+use yarel::memory::{Collector, Root};
+use yarel::object::ObjFunction;
+use yarel::optimizer::OptimizationLevel;
+use yarel::packed_value::PackedValue;
+use yarel::serialize;
 use yarel::value::Value;
-use yarel::vm::{self, Vm};
+use yarel::vm::{self, ModuleSource, Vm};
 
 type Matcher = fn(&str) -> Option<usize>;
 
@@ -127,7 +138,7 @@ fn local_print(vm: &mut Vm, num_args: usize) -> Result<Value, Error> {
             "Expected one argument to 'print'.",
         ));
     }
-    let lines = format!("{}", vm.peek(0));
+    let lines = vm.format_value(vm.peek(0))?;
     for line in lines.as_str().lines() {
         OUTPUT.with(|output| output.borrow_mut().push(line.to_string()));
     }
@@ -175,6 +186,1108 @@ fn run_test(source: &str) -> Outcome {
     }
 }
 
+fn run_with_vm(vm: &mut Vm, source: &str) -> (Vec<String>, Option<Error>) {
+    vm.set_printer(local_print);
+    let result = vm::interpret(vm, source.to_string(), None);
+    let output = OUTPUT.with(|output| mem::take(&mut *output.borrow_mut()));
+    (output, result.err())
+}
+
+#[test]
+fn instruction_budget_stops_infinite_loop() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_instruction_budget(Some(1000));
+    let (_, error) = run_with_vm(&mut vm, "while true {}");
+    let error = error.expect("Expected an error.");
+    assert_eq!(error.kind(), ErrorKind::InterruptError);
+    assert_eq!(
+        error.messages()[0],
+        "Unhandled InterruptError: Instruction budget exhausted."
+    );
+}
+
+#[test]
+fn instruction_budget_stops_infinite_recursion() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_instruction_budget(Some(50));
+    let (_, error) = run_with_vm(&mut vm, "fn f(n) { if n > 0 { f(n - 1); } f(60); } f(60);");
+    let error = error.expect("Expected an error.");
+    assert_eq!(error.kind(), ErrorKind::InterruptError);
+}
+
+#[test]
+fn instruction_budget_refills_per_execution() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_instruction_budget(Some(20));
+    let source = "for i in 0..10 {}";
+    assert!(run_with_vm(&mut vm, source).1.is_none());
+    assert!(run_with_vm(&mut vm, source).1.is_none());
+}
+
+#[test]
+fn uncatchable_interrupt_skips_handlers() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_instruction_budget(Some(1000));
+    let source = "
+try {
+    while true {}
+}
+catch err {
+    print(\"caught\");
+}
+finally {
+    print(\"finally\");
+}";
+    let (output, error) = run_with_vm(&mut vm, source);
+    assert!(output.is_empty());
+    assert_eq!(error.map(|e| e.kind()), Some(ErrorKind::InterruptError));
+}
+
+#[test]
+fn uncatchable_interrupt_skips_handlers_in_native_calls() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_instruction_budget(Some(1000));
+    let source = "
+var fiber = Fiber.new(|| {
+    [1, 2, 3].iter().map(|x| {
+        while true {}
+    }).collect();
+});
+print(fiber.try());";
+    let (output, error) = run_with_vm(&mut vm, source);
+    assert!(output.is_empty());
+    assert_eq!(error.map(|e| e.kind()), Some(ErrorKind::InterruptError));
+}
+
+#[test]
+fn catchable_interrupt() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_instruction_budget(Some(1000));
+    vm.set_interrupts_catchable(true);
+    let source = "
+try {
+    while true {}
+}
+catch err: InterruptError {
+    print(err.context);
+    print(err.derives(RuntimeError));
+}";
+    let (output, error) = run_with_vm(&mut vm, source);
+    assert!(error.is_none());
+    assert_eq!(output, vec!["Instruction budget exhausted.", "true"]);
+}
+
+#[test]
+fn interrupt_from_another_thread() {
+    let mut vm = Vm::with_built_ins();
+    let handle = vm.interrupt_handle();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let (_, error) = run_with_vm(&mut vm, "while true {}");
+    interrupter.join().unwrap();
+    let error = error.expect("Expected an error.");
+    assert_eq!(error.kind(), ErrorKind::InterruptError);
+    assert_eq!(
+        error.messages()[0],
+        "Unhandled InterruptError: Script interrupted."
+    );
+
+    let (output, error) = run_with_vm(&mut vm, "print(\"resumed\");");
+    assert!(error.is_none());
+    assert_eq!(output, vec!["resumed"]);
+}
+
+#[test]
+fn memory_limit_stops_vec_growth() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_memory_limit(Some(1 << 20));
+    let (_, error) = run_with_vm(&mut vm, "var v = []; while true { v.push(1); }");
+    vm.set_memory_limit(None);
+    let error = error.expect("Expected an error.");
+    assert_eq!(error.kind(), ErrorKind::MemoryError);
+    assert_eq!(
+        error.messages()[0],
+        "Unhandled MemoryError: Memory limit exceeded."
+    );
+}
+
+#[test]
+fn memory_limit_stops_string_growth() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_memory_limit(Some(1 << 20));
+    let (_, error) = run_with_vm(&mut vm, "var s = \"a\"; while true { s = s + s; }");
+    vm.set_memory_limit(None);
+    assert_eq!(error.map(|e| e.kind()), Some(ErrorKind::MemoryError));
+}
+
+#[test]
+fn memory_limit_stops_string_replace() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_memory_limit(Some(1 << 20));
+    let source = "
+var s = \"aaaaaaaaaa\";
+var t = \"bbbbbbbbbb\";
+for i in 0..3 {
+    s = s + s + s + s + s + s + s + s + s + s;
+}
+for i in 0..2 {
+    t = t + t + t + t + t + t + t + t + t + t;
+}
+s.replace(\"a\", t);";
+    let (_, error) = run_with_vm(&mut vm, source);
+    vm.set_memory_limit(None);
+    assert_eq!(error.map(|e| e.kind()), Some(ErrorKind::MemoryError));
+}
+
+#[test]
+fn memory_limit_stops_vec_spread() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_memory_limit(Some(1 << 20));
+    let (_, error) = run_with_vm(&mut vm, "var v = [1]; while true { v = [...v, ...v]; }");
+    vm.set_memory_limit(None);
+    assert_eq!(error.map(|e| e.kind()), Some(ErrorKind::MemoryError));
+}
+
+#[test]
+fn string_concatenation_counts_bytes_once() {
+    let mut vm = Vm::with_built_ins();
+    let (_, error) = run_with_vm(&mut vm, "var s = \"a\"; for i in 0..16 { s = s + s; }");
+    assert!(error.is_none());
+    let before = vm.gc_stats().bytes_allocated;
+    let (_, error) = run_with_vm(&mut vm, "var t = s + s;");
+    assert!(error.is_none());
+    let allocated = vm.gc_stats().bytes_allocated - before;
+    assert!(allocated >= 1 << 17);
+    assert!(allocated < 1 << 18);
+}
+
+#[test]
+fn memory_error_is_catchable() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_memory_limit(Some(1 << 20));
+    let source = "
+fn fill() {
+    var v = [];
+    while true {
+        v.push(1);
+    }
+}
+try {
+    fill();
+}
+catch err: MemoryError {
+    print(err.context);
+}
+var v = [];
+for i in 0..1000 {
+    v.push(i);
+}
+print(v.len());";
+    let (output, error) = run_with_vm(&mut vm, source);
+    vm.set_memory_limit(None);
+    assert!(error.is_none());
+    assert_eq!(output, vec!["Memory limit exceeded.", "1000"]);
+}
+
+#[test]
+fn incremental_collector_keeps_live_objects() {
+    let mut vm = Vm::with_thread_collector(Collector::Incremental);
+    // Objects are moved between the stack, instances, vecs and hash maps, and a generator assigns
+    // to a variable on the stack of the inactive fiber that created it.
+    let source = "
+class Node {
+    #[constructor]
+    fn new(self, value, next) {
+        self.value = value;
+        self.next = next;
+    }
+}
+var list = nil;
+for i in 0..200 {
+    list = Node.new(\"node ${i}\", list);
+}
+var moved = [];
+var map = {};
+for i in 0..100 {
+    moved.push(list.value);
+    map.insert(i % 10, list.next);
+    list = list.next;
+}
+fn outer() {
+    var latest = nil;
+    fn gen() {
+        for i in 0..50 {
+            latest = \"value ${i}\";
+            yield i;
+        }
+    }
+    for _ in gen() {}
+    return latest;
+}
+print(moved.len());
+print(moved[99]);
+print(map.get(5).value);
+print(list.value);
+print(outer());";
+    let (output, error) = run_with_vm(&mut vm, source);
+    assert!(error.is_none(), "{:?}", error.map(|e| e.to_string()));
+    assert_eq!(
+        output,
+        vec!["100", "node 100", "node 103", "node 99", "value 49"]
+    );
+}
+
+#[test]
+fn incremental_collector_enforces_memory_limit() {
+    let mut vm = Vm::with_thread_collector(Collector::Incremental);
+    vm.set_memory_limit(Some(1 << 20));
+    let source = "
+for i in 0..10000 {
+    var garbage = [i, i, i, i, i, i, i, i];
+}
+var v = [];
+while true {
+    v.push(1);
+}";
+    let (_, error) = run_with_vm(&mut vm, source);
+    vm.set_memory_limit(None);
+    assert_eq!(error.map(|e| e.kind()), Some(ErrorKind::MemoryError));
+}
+
+#[test]
+fn gc_stats_report_collections() {
+    let mut vm = Vm::with_built_ins();
+    let source = "
+var v = [];
+for i in 0..1000 {
+    v.push([i]);
+}
+v = nil;";
+    let (_, error) = run_with_vm(&mut vm, source);
+    assert!(error.is_none());
+    let before = vm.gc_stats();
+    vm.collect_garbage();
+    let after = vm.gc_stats();
+    assert!(after.collections > before.collections);
+    assert!(after.bytes_freed > before.bytes_freed);
+    assert!(after.bytes_allocated >= after.heap_bytes);
+    assert!(after.total_pause >= after.max_pause);
+    assert!(after.live_objects.get("ObjVec").is_none_or(|&n| n < 1000));
+    assert!(after.live_objects["ObjClass"] > 0);
+}
+
+// Debug builds collect on every allocation, whatever the threshold.
+#[cfg(not(debug_assertions))]
+#[test]
+fn collection_threshold_delays_collections() {
+    let source = "
+var v = [];
+for i in 0..1000 {
+    v.push([i]);
+}";
+    let collections = |threshold: usize| {
+        let mut vm = Vm::with_built_ins();
+        vm.set_collection_threshold(threshold);
+        let before = vm.gc_stats().collections;
+        let (_, error) = run_with_vm(&mut vm, source);
+        assert!(error.is_none());
+        vm.gc_stats().collections - before
+    };
+    assert_eq!(collections(1 << 30), 0);
+    assert!(collections(0) > 0);
+}
+
+#[test]
+fn transient_strings_are_collected() {
+    let mut vm = Vm::with_built_ins();
+    let source = "
+for i in 0..5000 {
+    var s = \"string ${i}\".replace(\"string\", \"str\");
+}";
+    let (_, error) = run_with_vm(&mut vm, source);
+    assert!(error.is_none());
+    vm.collect_garbage();
+    assert!(vm.gc_stats().live_objects["ObjString"] < 5000);
+}
+
+#[test]
+fn chunks_are_freed_with_their_functions() {
+    let mut vm = Vm::with_built_ins();
+    let source = "
+fn double(x) { return x * 2; }
+#[constructor(new)]
+class Foo { fn bar(self) { return double(2); } }
+var result = Foo.new().bar();";
+    let interpret = |vm: &mut Vm, times: usize| {
+        for _ in 0..times {
+            vm::interpret(vm, source.to_string(), None).unwrap();
+        }
+        vm.collect_garbage();
+        vm.gc_stats().heap_bytes
+    };
+    let heap_bytes = interpret(&mut vm, 10);
+    assert_eq!(interpret(&mut vm, 200), heap_bytes);
+}
+
+#[test]
+fn incremental_collector_frees_garbage() {
+    let mut vm = Vm::with_thread_collector(Collector::Incremental);
+    let source = "
+for i in 0..10000 {
+    var garbage = [i, i, i, i, i, i, i, i];
+}";
+    let (_, error) = run_with_vm(&mut vm, source);
+    assert!(error.is_none());
+    let stats = vm.gc_stats();
+    assert!(stats.collections > 0);
+    assert!(stats.live_objects.get("ObjVec").is_none_or(|&n| n < 10000));
+}
+
+#[test]
+fn incremental_steps_are_bounded() {
+    let max_step_objects = |size: usize| {
+        let mut vm = Vm::with_thread_collector(Collector::Incremental);
+        let source = format!("var v = []; for i in 0..{} {{ v.push([i]); }}", size);
+        let (_, error) = run_with_vm(&mut vm, &source);
+        assert!(error.is_none());
+        vm.gc_stats().max_step_objects
+    };
+    // The statistics cover the whole thread, so the smaller heap is measured first.
+    let small = max_step_objects(500);
+    let large = max_step_objects(10_000);
+    assert!(small > 0);
+    assert!(large < 2 * small, "{} objects visited in a step", large);
+}
+
+#[test]
+fn heap_growth_factor_must_be_at_least_one() {
+    let mut vm = Vm::with_built_ins();
+    let error = vm.set_heap_growth_factor(0.5).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::ValueError);
+    assert!(vm.set_heap_growth_factor(1.5).is_ok());
+}
+
+#[test]
+fn memory_limit_allows_garbage() {
+    let mut vm = Vm::with_built_ins();
+    vm.set_memory_limit(Some(1 << 20));
+    let source = "
+for i in 0..2000 {
+    var v = [i, i, i, i, i, i, i, i];
+    var s = \"${i}\" + \"${v}\";
+}
+print(\"done\");";
+    let (output, error) = run_with_vm(&mut vm, source);
+    vm.set_memory_limit(None);
+    assert!(error.is_none());
+    assert_eq!(output, vec!["done"]);
+}
+
+fn compile_to_bytecode(vm: &mut Vm, source: &str) -> Vec<u8> {
+    let function = compiler::compile(vm, source.to_string(), None).unwrap();
+    serialize::serialize_function(&function).unwrap()
+}
+
+fn run_bytecode_with_vm(vm: &mut Vm, bytecode: &[u8]) -> (Vec<String>, Option<Error>) {
+    vm.set_printer(local_print);
+    let result = vm::interpret_bytecode(vm, bytecode, None);
+    let output = OUTPUT.with(|output| mem::take(&mut *output.borrow_mut()));
+    (output, result.err())
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let mut vm = Vm::with_built_ins();
+    let source = "
+fn counter(step = 2) {
+    var count = 0;
+    fn increment() {
+        count += step;
+        return count;
+    }
+    return increment;
+}
+#[constructor(new)]
+class Point {
+    #[constructor]
+    fn new(self, x, y) {
+        self.x = x;
+        self.y = y;
+    }
+    fn norm2(self) {
+        return self.x * self.x + self.y * self.y;
+    }
+}
+fn numbers(...values) {
+    for v in values {
+        yield v;
+    }
+}
+var c = counter();
+c();
+print(c());
+print(Point.new(3, 4).norm2());
+print(numbers(1.5, true, nil, \"text\").collect());";
+    let bytecode = compile_to_bytecode(&mut vm, source);
+    let (output, error) = run_bytecode_with_vm(&mut vm, &bytecode);
+    assert!(error.is_none(), "{:?}", error.map(|e| e.to_string()));
+    assert_eq!(output, vec!["4", "25", "[1.5, true, nil, text]"]);
+}
+
+#[test]
+fn bytecode_reports_line_numbers() {
+    let mut vm = Vm::with_built_ins();
+    let bytecode = compile_to_bytecode(&mut vm, "var a = 1;\nvar b = a + \"x\";");
+    let (_, error) = run_bytecode_with_vm(&mut vm, &bytecode);
+    let error = error.unwrap();
+    assert_eq!(error.kind(), ErrorKind::TypeError);
+    assert!(error.to_string().contains("line 2"), "{}", error);
+}
+
+#[test]
+fn bytecode_version_mismatch() {
+    let mut vm = Vm::with_built_ins();
+    let mut bytecode = compile_to_bytecode(&mut vm, "print(1);");
+    bytecode[4] = bytecode[4].wrapping_add(1);
+    assert!(!serialize::is_compatible(&bytecode));
+    let (_, error) = run_bytecode_with_vm(&mut vm, &bytecode);
+    let message = format!(
+        "Unsupported bytecode version (expected version {}).",
+        serialize::FORMAT_VERSION
+    );
+    assert_eq!(error.unwrap().messages(), &[message]);
+}
+
+#[test]
+fn bytecode_truncated() {
+    let mut vm = Vm::with_built_ins();
+    let bytecode = compile_to_bytecode(&mut vm, "fn f(a) { return \"a\" + a; } print(f(\"b\"));");
+    for len in 0..bytecode.len() {
+        let (output, error) = run_bytecode_with_vm(&mut vm, &bytecode[..len]);
+        assert!(output.is_empty());
+        assert_eq!(error.unwrap().kind(), ErrorKind::RuntimeError);
+    }
+}
+
+#[test]
+fn bytecode_nested_too_deeply() {
+    let mut vm = Vm::with_built_ins();
+    let depth = 100_000;
+    let mut header = b"YLC\0".to_vec();
+    header.extend_from_slice(&serialize::FORMAT_VERSION.to_le_bytes());
+    // Each function has an empty name, an arity of one, no defaults, upvalues or parameters and a
+    // single instruction. All but the innermost function hold the next one as their only constant,
+    // which is tagged with 5.
+    let mut function_start = Vec::new();
+    for field in [0u32, 1, 0, 0] {
+        function_start.extend_from_slice(&field.to_le_bytes());
+    }
+    function_start.push(0);
+    for field in [0u32, 1] {
+        function_start.extend_from_slice(&field.to_le_bytes());
+    }
+    function_start.push(OpCode::Return as u8);
+    function_start.extend_from_slice(&1i32.to_le_bytes());
+    let mut bytecode = header;
+    for _ in 0..depth {
+        bytecode.extend_from_slice(&function_start);
+        bytecode.extend_from_slice(&1u32.to_le_bytes());
+        bytecode.push(5);
+    }
+    bytecode.extend_from_slice(&function_start);
+    bytecode.extend_from_slice(&0u32.to_le_bytes());
+    for _ in 0..=depth {
+        bytecode.extend_from_slice(&0u32.to_le_bytes());
+    }
+
+    let error = serialize::deserialize_function(&mut vm, &bytecode, None).err();
+    assert_eq!(
+        error.unwrap().messages(),
+        &["Invalid bytecode (functions nested too deeply)."]
+    );
+}
+
+fn script_paths(dir: &Path, paths: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            script_paths(&path, paths);
+        } else if path.extension().is_some_and(|e| e == "yl") {
+            paths.push(path);
+        }
+    }
+}
+
+#[test]
+fn compiled_scripts_pass_verification() {
+    let mut vm = Vm::with_built_ins();
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths = vec![root.join("src/core.yl")];
+    script_paths(&root.join("tests/scripts"), &mut paths);
+    let levels = [
+        OptimizationLevel::None,
+        OptimizationLevel::Basic,
+        OptimizationLevel::Full,
+    ];
+    for level in levels {
+        vm.set_optimization_level(level);
+        for path in &paths {
+            let source = fs::read_to_string(path).unwrap();
+            if let Ok(function) = compiler::compile(&mut vm, source, None) {
+                if let Err(error) = chunk::verify(&function) {
+                    panic!("{} ({:?}): {}", path.display(), level, error);
+                }
+            }
+        }
+    }
+}
+
+fn assemble(vm: &mut Vm, code: &[u8], constants: &[Value]) -> (Root<Chunk>, ObjFunction) {
+    let mut chunk = Chunk::new();
+    for &byte in code {
+        chunk.write(byte, 1);
+    }
+    for &constant in constants {
+        chunk.add_constant(constant);
+    }
+    let function = compiler::compile(vm, String::new(), None).unwrap();
+    let mut function = (*function).clone();
+    let chunk = Root::new(chunk);
+    function.chunk = chunk.as_gc();
+    (chunk, function)
+}
+
+fn verify_code(code: &[u8], constants: &[Value]) -> Result<(), VerifyError> {
+    let mut vm = Vm::with_built_ins();
+    let (_chunk, function) = assemble(&mut vm, code, constants);
+    chunk::verify(&function)
+}
+
+fn run_code(code: &[u8], constants: &[Value]) -> (Vec<String>, Option<Error>) {
+    let mut vm = Vm::with_built_ins();
+    let (_chunk, function) = assemble(&mut vm, code, constants);
+    let bytecode = serialize::serialize_function(&function).unwrap();
+    run_bytecode_with_vm(&mut vm, &bytecode)
+}
+
+fn verify_error(code: &[u8], constants: &[Value]) -> (usize, VerifyErrorKind) {
+    let error = verify_code(code, constants).unwrap_err();
+    (error.offset, error.kind)
+}
+
+#[test]
+fn verify_accepts_balanced_code() {
+    let mut code = vec![
+        OpCode::True as u8,
+        OpCode::JumpIfFalse as u8,
+        0,
+        0,
+        OpCode::Pop as u8,
+        OpCode::Jump as u8,
+        0,
+        0,
+        OpCode::Pop as u8,
+        OpCode::Nil as u8,
+        OpCode::Return as u8,
+    ];
+    code[2..4].copy_from_slice(&4u16.to_le_bytes());
+    code[6..8].copy_from_slice(&1u16.to_le_bytes());
+    assert!(verify_code(&code, &[]).is_ok());
+}
+
+#[test]
+fn verify_rejects_unknown_opcode() {
+    assert_eq!(
+        verify_error(&[0xff, OpCode::Return as u8], &[]),
+        (0, VerifyErrorKind::UnknownOpcode(0xff))
+    );
+}
+
+#[test]
+fn verify_rejects_truncated_instruction() {
+    assert_eq!(
+        verify_error(&[OpCode::Nil as u8, OpCode::Constant as u8, 0], &[]),
+        (1, VerifyErrorKind::TruncatedInstruction)
+    );
+}
+
+#[test]
+fn verify_rejects_invalid_constant() {
+    let code = [OpCode::Constant as u8, 1, 0, OpCode::Return as u8];
+    assert_eq!(
+        verify_error(&code, &[Value::Number(1.0)]),
+        (0, VerifyErrorKind::InvalidConstant(1))
+    );
+    let code = [OpCode::GetGlobal as u8, 0, 0, OpCode::Return as u8];
+    assert_eq!(
+        verify_error(&code, &[Value::Number(1.0)]),
+        (0, VerifyErrorKind::InvalidConstant(0))
+    );
+}
+
+#[test]
+fn verify_rejects_invalid_local() {
+    let code = [OpCode::GetLocal as u8, 1, OpCode::Return as u8];
+    assert_eq!(
+        verify_error(&code, &[]),
+        (0, VerifyErrorKind::InvalidLocal(1))
+    );
+}
+
+#[test]
+fn verify_rejects_invalid_upvalue() {
+    let code = [OpCode::GetUpvalue as u8, 0, OpCode::Return as u8];
+    assert_eq!(
+        verify_error(&code, &[]),
+        (0, VerifyErrorKind::InvalidUpvalue(0))
+    );
+}
+
+#[test]
+fn verify_rejects_invalid_inline_cache() {
+    let mut vm = Vm::with_built_ins();
+    let function = compiler::compile(&mut vm, "\"name\".len();".to_string(), None).unwrap();
+    let name = function.chunk.constants[0].unpack();
+    let code = [
+        OpCode::Nil as u8,
+        OpCode::GetProperty as u8,
+        0,
+        0,
+        0,
+        0,
+        OpCode::Return as u8,
+    ];
+    assert_eq!(
+        verify_error(&code, &[name]),
+        (1, VerifyErrorKind::InvalidInlineCache(0))
+    );
+}
+
+#[test]
+fn verify_rejects_jump_into_instruction() {
+    let mut code = vec![OpCode::Jump as u8, 0, 0, OpCode::Constant as u8, 0, 0];
+    code.push(OpCode::Return as u8);
+    code[1..3].copy_from_slice(&1u16.to_le_bytes());
+    assert_eq!(
+        verify_error(&code, &[Value::None]),
+        (0, VerifyErrorKind::InvalidJump)
+    );
+}
+
+#[test]
+fn verify_rejects_jump_past_end() {
+    let mut code = vec![OpCode::Loop as u8, 0, 0, OpCode::Return as u8];
+    code[1..3].copy_from_slice(&4u16.to_le_bytes());
+    assert_eq!(verify_error(&code, &[]), (0, VerifyErrorKind::InvalidJump));
+}
+
+#[test]
+fn verify_rejects_stack_underflow() {
+    let code = [OpCode::Pop as u8, OpCode::Pop as u8, OpCode::Return as u8];
+    assert_eq!(
+        verify_error(&code, &[]),
+        (1, VerifyErrorKind::StackUnderflow)
+    );
+}
+
+#[test]
+fn verify_rejects_stack_overflow() {
+    let mut code = vec![OpCode::Nil as u8; 300];
+    code.push(OpCode::Return as u8);
+    assert_eq!(
+        verify_error(&code, &[]),
+        (255, VerifyErrorKind::StackOverflow)
+    );
+    let mut code = vec![OpCode::Nil as u8; 200_000];
+    code.push(OpCode::Return as u8);
+    let (_, error) = run_code(&code, &[]);
+    assert_eq!(
+        error.unwrap().messages(),
+        &["Invalid bytecode (stack overflow at offset 255 in script)."]
+    );
+}
+
+#[test]
+fn verify_rejects_unbalanced_branches() {
+    let mut code = vec![
+        OpCode::True as u8,
+        OpCode::JumpIfFalse as u8,
+        0,
+        0,
+        OpCode::Nil as u8,
+        OpCode::Return as u8,
+    ];
+    code[2..4].copy_from_slice(&1u16.to_le_bytes());
+    assert_eq!(
+        verify_error(&code, &[]),
+        (
+            4,
+            VerifyErrorKind::StackMismatch {
+                expected: 2,
+                found: 3
+            }
+        )
+    );
+}
+
+#[test]
+fn verify_rejects_missing_return() {
+    assert_eq!(
+        verify_error(&[OpCode::Nil as u8], &[]),
+        (0, VerifyErrorKind::MissingReturn)
+    );
+}
+
+#[test]
+fn verify_rejects_parameters_not_matching_arity() {
+    let mut vm = Vm::with_built_ins();
+    let script = compiler::compile(&mut vm, "fn f(a = 1, b = 2) {}".to_string(), None).unwrap();
+    let nested = script
+        .chunk
+        .constants
+        .iter()
+        .find_map(|constant| constant.try_as_obj_function())
+        .unwrap();
+    let mut function = (*nested).clone();
+    assert!(chunk::verify(&function).is_ok());
+    // Keyword arguments are bound using the parameter names, so a name is needed for each one.
+    function.parameters.truncate(1);
+    let error = chunk::verify(&function).unwrap_err();
+    assert_eq!(
+        (error.offset, error.kind),
+        (0, VerifyErrorKind::InvalidSignature)
+    );
+}
+
+#[test]
+fn bytecode_is_verified_when_loaded() {
+    let mut vm = Vm::with_built_ins();
+    let mut bytecode = compile_to_bytecode(&mut vm, "");
+    // An empty script compiles to NIL followed by RETURN. The code is followed by its line numbers
+    // and the (zero) counts of constants and inline caches.
+    let code_offset = bytecode.len() - 4 - 4 - 2 * 4 - 2;
+    assert_eq!(bytecode[code_offset + 1], OpCode::Return as u8);
+    bytecode[code_offset + 1] = OpCode::Pop as u8;
+    let (_, error) = run_bytecode_with_vm(&mut vm, &bytecode);
+    assert_eq!(
+        error.unwrap().messages(),
+        &["Invalid bytecode (missing return at offset 1 in script)."]
+    );
+}
+
+#[test]
+fn extend_rejects_non_sequence_target() {
+    let code = [
+        OpCode::Nil as u8,
+        OpCode::BuildVec as u8,
+        0,
+        OpCode::Extend as u8,
+        OpCode::Return as u8,
+    ];
+    let (_, error) = run_code(&code, &[]);
+    assert_eq!(error.unwrap().kind(), ErrorKind::TypeError);
+}
+
+#[test]
+fn get_super_rejects_non_class() {
+    let mut vm = Vm::with_built_ins();
+    let name = vm.new_root_obj_string("name");
+    let code = [
+        OpCode::Nil as u8,
+        OpCode::Nil as u8,
+        OpCode::GetSuper as u8,
+        0,
+        0,
+        OpCode::Return as u8,
+    ];
+    let (_, error) = run_code(&code, &[Value::ObjString(name.as_gc())]);
+    assert_eq!(error.unwrap().kind(), ErrorKind::TypeError);
+}
+
+#[test]
+fn super_invoke_rejects_non_class() {
+    let mut vm = Vm::with_built_ins();
+    let name = vm.new_root_obj_string("name");
+    let code = [
+        OpCode::Nil as u8,
+        OpCode::Nil as u8,
+        OpCode::SuperInvokeKw as u8,
+        0,
+        0,
+        0,
+        0,
+        OpCode::Return as u8,
+    ];
+    let (_, error) = run_code(&code, &[Value::ObjString(name.as_gc())]);
+    assert_eq!(error.unwrap().kind(), ErrorKind::TypeError);
+}
+
+#[test]
+fn call_spread_rejects_non_vec_arguments() {
+    let code = [
+        OpCode::Nil as u8,
+        OpCode::Nil as u8,
+        OpCode::CallSpread as u8,
+        0,
+        OpCode::Return as u8,
+    ];
+    let (_, error) = run_code(&code, &[]);
+    assert_eq!(error.unwrap().kind(), ErrorKind::TypeError);
+}
+
+#[test]
+fn method_rejects_non_function() {
+    let mut vm = Vm::with_built_ins();
+    let name = vm.new_root_obj_string("name");
+    let code = [
+        OpCode::DeclareClass as u8,
+        0,
+        0,
+        OpCode::Nil as u8,
+        OpCode::Method as u8,
+        0,
+        0,
+        OpCode::Return as u8,
+    ];
+    let (_, error) = run_code(&code, &[Value::ObjString(name.as_gc())]);
+    assert_eq!(error.unwrap().kind(), ErrorKind::TypeError);
+}
+
+#[test]
+fn method_rejects_missing_class() {
+    let mut vm = Vm::with_built_ins();
+    let name = vm.new_root_obj_string("name");
+    let code = [
+        OpCode::Closure as u8,
+        1,
+        0,
+        OpCode::Method as u8,
+        0,
+        0,
+        OpCode::Nil as u8,
+        OpCode::Return as u8,
+    ];
+    let function = compiler::compile(&mut vm, String::new(), None).unwrap();
+    let constants = [
+        Value::ObjString(name.as_gc()),
+        Value::ObjFunction(function.as_gc()),
+    ];
+    let (_, error) = run_code(&code, &constants);
+    assert_eq!(
+        error.unwrap().messages()[0],
+        "Unhandled RuntimeError: No class is being defined."
+    );
+}
+
+fn compile_opcodes(vm: &mut Vm, source: &str) -> (Vec<u8>, Vec<(usize, usize)>) {
+    let function = compiler::compile(vm, source.to_string(), None).unwrap();
+    let chunk = &function.chunk;
+    let mut opcodes = Vec::new();
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let opcode = OpCode::from_byte(chunk.code[offset]).unwrap();
+        let next = offset + 1 + opcode.arg_sizes().iter().sum::<usize>();
+        if opcode == OpCode::Jump || opcode == OpCode::JumpIfFalse {
+            let jump = u16::from_le_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+            jumps.push((offset, next + jump as usize));
+        }
+        opcodes.push(chunk.code[offset]);
+        offset = next;
+    }
+    (opcodes, jumps)
+}
+
+#[test]
+fn optimizer_folds_constants() {
+    let mut vm = Vm::with_built_ins();
+    let source = "print(1 + 2 * 3 - -4); print(\"a\" + \"b\"); print(1 < 2);";
+    let (opcodes, _) = compile_opcodes(&mut vm, source);
+    let print = |argument| [OpCode::GetGlobal, argument, OpCode::Call, OpCode::Pop];
+    let mut expected = Vec::new();
+    expected.extend(print(OpCode::Constant));
+    expected.extend(print(OpCode::Constant));
+    expected.extend(print(OpCode::True));
+    expected.extend([OpCode::Nil, OpCode::Return]);
+    let expected: Vec<_> = expected.into_iter().map(|op| op as u8).collect();
+    assert_eq!(opcodes, expected);
+    let (output, _) = run_with_vm(&mut vm, source);
+    assert_eq!(output, vec!["11", "ab", "true"]);
+}
+
+#[test]
+fn optimizer_removes_dead_code() {
+    let mut vm = Vm::with_built_ins();
+    let (opcodes, _) = compile_opcodes(&mut vm, "throw \"error\"; print(1); 2;");
+    let expected = [OpCode::Constant, OpCode::Throw];
+    assert_eq!(opcodes, expected.map(|op| op as u8));
+}
+
+#[test]
+fn optimizer_threads_jumps() {
+    let mut vm = Vm::with_built_ins();
+    let source = "
+var a = true;
+var b = false;
+if a {
+    if b {
+        print(1);
+    } else {
+        print(2);
+    }
+} else {
+    print(3);
+}";
+    vm.set_optimization_level(OptimizationLevel::None);
+    let (_, jumps) = compile_opcodes(&mut vm, source);
+    let offsets: Vec<_> = jumps.iter().map(|&(offset, _)| offset).collect();
+    assert!(jumps.iter().any(|(_, target)| offsets.contains(target)));
+
+    vm.set_optimization_level(OptimizationLevel::Basic);
+    let (_, jumps) = compile_opcodes(&mut vm, source);
+    let offsets: Vec<_> = jumps.iter().map(|&(offset, _)| offset).collect();
+    assert!(jumps.iter().all(|(_, target)| !offsets.contains(target)));
+    let (output, _) = run_with_vm(&mut vm, source);
+    assert_eq!(output, vec!["2"]);
+}
+
+#[test]
+fn optimizer_fuses_superinstructions() {
+    let mut vm = Vm::with_built_ins();
+    let source = "{ var a = 1; var b = 2; print(a + b); print(a * b); print(a - 1); }";
+    let (opcodes, _) = compile_opcodes(&mut vm, source);
+    let count = |opcode: OpCode| opcodes.iter().filter(|&&op| op == opcode as u8).count();
+    assert_eq!(count(OpCode::AddLocals), 1);
+    assert_eq!(count(OpCode::GetLocals), 1);
+    assert_eq!(count(OpCode::GetLocalConstant), 1);
+    assert_eq!(count(OpCode::GetLocal), 0);
+    assert_eq!(count(OpCode::Add), 0);
+    let (output, _) = run_with_vm(&mut vm, source);
+    assert_eq!(output, vec!["3", "2", "0"]);
+}
+
+#[test]
+fn opcodes_round_trip_through_bytes() {
+    let mut byte = 0;
+    while let Some(opcode) = OpCode::from_byte(byte) {
+        assert_eq!(opcode as u8, byte);
+        byte += 1;
+    }
+    assert!((byte..=u8::MAX).all(|byte| OpCode::from_byte(byte).is_none()));
+    assert!(byte > OpCode::AddLocals as u8);
+}
+
+#[test]
+fn packed_values_round_trip() {
+    let mut vm = Vm::with_built_ins();
+    let string = vm.new_gc_obj_string("packed");
+    let vec = vm.new_root_obj_vec();
+    let values = [
+        Value::None,
+        Value::Boolean(false),
+        Value::Boolean(true),
+        Value::Number(1.5),
+        Value::Number(-0.0),
+        Value::Number(f64::INFINITY),
+        Value::Number(f64::NEG_INFINITY),
+        Value::Number(f64::MIN_POSITIVE),
+        Value::ObjString(string),
+        Value::ObjVec(vec.as_gc()),
+    ];
+    for &value in &values {
+        let unpacked = PackedValue::new(value).unpack();
+        assert!(unpacked == value, "{} was unpacked as {}", value, unpacked);
+    }
+
+    let zero = PackedValue::new(Value::Number(-0.0)).unpack();
+    assert!(zero.try_as_number().unwrap().is_sign_negative());
+    for &nan in &[f64::NAN, -f64::NAN, f64::from_bits(0xfff8_0000_0000_0001)] {
+        let unpacked = PackedValue::new(Value::Number(nan)).unpack();
+        assert!(unpacked.try_as_number().unwrap().is_nan());
+    }
+
+    if cfg!(feature = "nan_boxing") {
+        assert_eq!(mem::size_of::<PackedValue>(), 8);
+    } else {
+        assert_eq!(mem::size_of::<PackedValue>(), mem::size_of::<Value>());
+    }
+}
+
+#[test]
+fn optimization_levels() {
+    let mut vm = Vm::with_built_ins();
+    let source = "print(1 + 2); throw nil; print(3);";
+    let count =
+        |opcodes: &[u8], opcode: OpCode| opcodes.iter().filter(|&&op| op == opcode as u8).count();
+
+    vm.set_optimization_level(OptimizationLevel::None);
+    let (opcodes, _) = compile_opcodes(&mut vm, source);
+    assert_eq!(
+        (count(&opcodes, OpCode::Add), count(&opcodes, OpCode::Call)),
+        (1, 2)
+    );
+
+    vm.set_optimization_level(OptimizationLevel::Basic);
+    let (opcodes, _) = compile_opcodes(&mut vm, source);
+    assert_eq!(
+        (count(&opcodes, OpCode::Add), count(&opcodes, OpCode::Call)),
+        (1, 1)
+    );
+
+    vm.set_optimization_level(OptimizationLevel::Full);
+    let (opcodes, _) = compile_opcodes(&mut vm, source);
+    assert_eq!(
+        (count(&opcodes, OpCode::Add), count(&opcodes, OpCode::Call)),
+        (0, 1)
+    );
+}
+
+thread_local! {
+    static MODULE_BYTECODE: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+fn bytecode_module_loader(path: &str) -> Result<ModuleSource, Error> {
+    match path {
+        "compiled" => Ok(ModuleSource::Bytecode(
+            MODULE_BYTECODE.with(|bytecode| bytecode.borrow().clone()),
+        )),
+        "corrupt" => Ok(ModuleSource::Bytecode(b"YLC\0".to_vec())),
+        _ => Ok(ModuleSource::Source("var value = \"source\";".to_string())),
+    }
+}
+
+#[test]
+fn import_bytecode_module() {
+    let mut vm = Vm::with_built_ins();
+    let bytecode = compile_to_bytecode(&mut vm, "var value = \"compiled\";");
+    MODULE_BYTECODE.with(|b| *b.borrow_mut() = bytecode);
+    vm.set_module_loader(bytecode_module_loader);
+    let source = "
+import \"compiled\";
+import \"other\";
+print(compiled.value);
+print(other.value);
+try {
+    import \"corrupt\";
+}
+catch err {
+    print(err.context);
+}";
+    let (output, error) = run_with_vm(&mut vm, source);
+    assert!(error.is_none(), "{:?}", error.map(|e| e.to_string()));
+    assert_eq!(
+        output,
+        vec![
+            "compiled",
+            "source",
+            "Error loading module bytecode:",
+            "    Invalid bytecode (missing header)."
+        ]
+    );
+}
+
 #[allow(unused_macros)]
 macro_rules! test_case {
     ($name:ident, $source:expr) => {
@@ -190,9 +1303,9 @@ macro_rules! test_case {
 #[allow(unused_macros)]
 macro_rules! gen_module_loader {
     ($($key:expr => $result:expr),*) => {
-        fn module_loader(path: &str) -> Result<String, Error> {
+        fn module_loader(path: &str) -> Result<ModuleSource, Error> {
             match path {
-                $($key => Ok($result.to_string()),)*
+                $($key => Ok(ModuleSource::Source($result.to_string())),)*
                 _ => Err(Error::with_message(
                     ErrorKind::ImportError,
                     &format!("Unable to read file '{}.yl' (file not found).", path),